@@ -0,0 +1,63 @@
+//! Compares the throughput of splicing with copying through the proxy, with
+//! `cargo bench`.
+
+use std::time::Instant;
+use tcp_proxy::Proxy;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const SIZE: usize = 1 << 30;
+
+/// Sends SIZE bytes through a proxy with `extra` options to an upstream that
+/// discards them, returning how many MiB per second got through.
+async fn throughput(extra: &[&str]) -> f64 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let upstream = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; 1 << 16];
+        let mut total = 0;
+        loop {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => return total,
+                n => total += n,
+            }
+        }
+    });
+    let mut args = vec![
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--summary-only",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    args.extend(extra);
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+
+    let started = Instant::now();
+    let mut client = TcpStream::connect(proxy.local_addr().unwrap())
+        .await
+        .unwrap();
+    let chunk = vec![0; 1 << 16];
+    for _ in 0..SIZE / chunk.len() {
+        client.write_all(&chunk).await.unwrap();
+    }
+    client.shutdown().await.unwrap();
+    assert_eq!(upstream.await.unwrap(), SIZE);
+    let elapsed = started.elapsed().as_secs_f64();
+
+    drop(client);
+    proxy.shutdown().await.unwrap();
+    (SIZE >> 20) as f64 / elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    for (name, extra) in [("splice", &[][..]), ("copy", &["--no-splice"][..])] {
+        println!("{name}: {:.0} MiB/s", throughput(extra).await);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::line_writer::LineWriter;
+use crate::Opt;
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use time::OffsetDateTime;
+
+/// Writes --access-log, which all connections share.
+static WRITER: OnceLock<LineWriter> = OnceLock::new();
+
+pub fn open(opt: &Opt) -> Result<()> {
+    let Some(path) = &opt.access_log else {
+        return Ok(());
+    };
+    if WRITER.get().is_some() {
+        return Ok(());
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open access log {}", path.display()))?;
+    let _ = WRITER.set(LineWriter::spawn(path, file));
+    Ok(())
+}
+
+/// A request and its response, as a line in Combined Log Format.
+pub struct Entry<'a> {
+    pub client_ip: Option<IpAddr>,
+    /// When the request was read.
+    pub time: OffsetDateTime,
+    pub request_line: &'a str,
+    pub status: u16,
+    /// The size of the response body, if it ended before the connection.
+    pub bytes: Option<u64>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+impl fmt::Display for Entry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = self.time;
+        match self.client_ip {
+            Some(ip) => write!(f, "{ip} - - ")?,
+            None => write!(f, "- - - ")?,
+        }
+        write!(
+            f,
+            "[{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] ",
+            time.day(),
+            &time.month().to_string()[..3],
+            time.year(),
+            time.hour(),
+            time.minute(),
+            time.second()
+        )?;
+        write!(
+            f,
+            "\"{}\" {} ",
+            quoted(Some(self.request_line)),
+            self.status
+        )?;
+        match self.bytes {
+            Some(bytes) if bytes > 0 => write!(f, "{bytes} ")?,
+            _ => write!(f, "- ")?,
+        }
+        write!(
+            f,
+            "\"{}\" \"{}\"",
+            quoted(self.referer),
+            quoted(self.user_agent)
+        )
+    }
+}
+
+/// `value` escaped for a quoted field, or - if it is missing.
+fn quoted(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "-".to_string();
+    };
+    let mut quoted = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.extend(c.escape_default()),
+            c => quoted.push(c),
+        }
+    }
+    quoted
+}
+
+/// Queues a line for the --access-log, if there is one.
+pub fn write(entry: &Entry) {
+    if let Some(writer) = WRITER.get() {
+        writer.write(format!("{entry}\n"));
+    }
+}
+
+pub fn flush() {
+    if let Some(writer) = WRITER.get() {
+        writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_in_combined_log_format() {
+        let entry = Entry {
+            client_ip: Some([192, 0, 2, 1].into()),
+            time: OffsetDateTime::from_unix_timestamp(971186136).unwrap(),
+            request_line: "GET /apache_pb.gif HTTP/1.0",
+            status: 200,
+            bytes: Some(2326),
+            referer: Some("http://www.example.com/start.html"),
+            user_agent: None,
+        };
+        assert_eq!(
+            entry.to_string(),
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 \
+             \"http://www.example.com/start.html\" \"-\""
+        );
+
+        let entry = Entry {
+            client_ip: None,
+            bytes: None,
+            user_agent: Some("say \"hi\""),
+            ..entry
+        };
+        assert_eq!(
+            entry.to_string(),
+            "- - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 - \
+             \"http://www.example.com/start.html\" \"say \\\"hi\\\"\""
+        );
+    }
+}
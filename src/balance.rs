@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How connections are spread over the hostname and each --upstream.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    /// Each connection goes to the next target.
+    RoundRobin,
+    /// Connections go to the first target that can be connected to.
+    Failover,
+}
+
+impl FromStr for Balance {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "failover" => Ok(Balance::Failover),
+            _ => Err("expected round-robin or failover"),
+        }
+    }
+}
+
+/// What all connections share to pick their target: when targets last failed to
+/// connect, so --balance failover skips them for --failover-cooldown, and which
+/// ones pass --health-check.
+pub struct Balancer {
+    failures: Mutex<HashMap<usize, Instant>>,
+    healthy: Vec<AtomicBool>,
+}
+
+impl Balancer {
+    /// A balancer for `count` targets, which are healthy until checked.
+    pub fn new(count: usize) -> Self {
+        Balancer {
+            failures: Mutex::default(),
+            healthy: (0..count).map(|_| AtomicBool::new(true)).collect(),
+        }
+    }
+
+    /// The indexes of the targets to try for connection `i` in order: the next
+    /// healthy one with round-robin, or else all healthy ones, leaving out those that
+    /// failed within `cooldown` unless that leaves none. Empty if none are healthy.
+    pub fn candidates(&self, balance: Balance, i: usize, cooldown: Duration) -> Vec<usize> {
+        let healthy: Vec<_> = (0..self.healthy.len())
+            .filter(|&target| self.healthy[target].load(Relaxed))
+            .collect();
+        if balance == Balance::RoundRobin || healthy.is_empty() {
+            return healthy
+                .get(i % healthy.len().max(1))
+                .copied()
+                .into_iter()
+                .collect();
+        }
+        let failures = self.failures.lock().unwrap();
+        let up: Vec<_> = healthy
+            .iter()
+            .copied()
+            .filter(|target| {
+                failures
+                    .get(target)
+                    .is_none_or(|at| at.elapsed() >= cooldown)
+            })
+            .collect();
+        if up.is_empty() {
+            healthy
+        } else {
+            up
+        }
+    }
+
+    pub fn failed(&self, target: usize) {
+        self.failures.lock().unwrap().insert(target, Instant::now());
+    }
+
+    /// Forgets that `target` failed, returning whether it had.
+    pub fn succeeded(&self, target: usize) -> bool {
+        self.failures.lock().unwrap().remove(&target).is_some()
+    }
+
+    pub fn set_healthy(&self, target: usize, healthy: bool) {
+        self.healthy[target].store(healthy, Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_targets_are_skipped_until_the_cooldown_is_over() {
+        let balancer = Balancer::new(3);
+        let cooldown = Duration::from_secs(60);
+        balancer.failed(0);
+        balancer.failed(2);
+        assert_eq!(balancer.candidates(Balance::Failover, 0, cooldown), [1]);
+        let no_cooldown = balancer.candidates(Balance::Failover, 0, Duration::ZERO);
+        assert_eq!(no_cooldown, [0, 1, 2]);
+
+        balancer.failed(1);
+        assert_eq!(
+            balancer.candidates(Balance::Failover, 0, cooldown),
+            [0, 1, 2]
+        );
+        assert!(balancer.succeeded(0) && !balancer.succeeded(0));
+        assert_eq!(balancer.candidates(Balance::Failover, 0, cooldown), [0]);
+    }
+
+    #[test]
+    fn unhealthy_targets_are_left_out() {
+        let balancer = Balancer::new(3);
+        balancer.set_healthy(1, false);
+        let round_robin: Vec<_> = (0..3)
+            .flat_map(|i| balancer.candidates(Balance::RoundRobin, i, Duration::ZERO))
+            .collect();
+        assert_eq!(round_robin, [0, 2, 0]);
+        let failover = balancer.candidates(Balance::Failover, 0, Duration::ZERO);
+        assert_eq!(failover, [0, 2]);
+
+        balancer.set_healthy(0, false);
+        balancer.set_healthy(2, false);
+        assert!(balancer
+            .candidates(Balance::RoundRobin, 0, Duration::ZERO)
+            .is_empty());
+        assert!(balancer
+            .candidates(Balance::Failover, 0, Duration::ZERO)
+            .is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use crate::Opt;
+use anyhow::{Context, Result};
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, SanType};
+use std::net::IpAddr;
+use time::{Duration, OffsetDateTime};
+
+pub fn self_signed_params(opt: &Opt) -> CertificateParams {
+    certificate_params(&opt.hostname, &self_signed_names(opt))
+}
+
+/// The subject alternative names of the self-signed certificate, in order.
+pub fn self_signed_names(opt: &Opt) -> Vec<&str> {
+    let mut names: Vec<&str> = vec![];
+    if opt.unix_target().is_none() {
+        names.push(&opt.hostname);
+    }
+    for name in ["localhost", "127.0.0.1"]
+        .into_iter()
+        .chain(opt.san.iter().map(String::as_str))
+    {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+pub fn certificate_params(common_name: &str, names: &[&str]) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.to_string()),
+        })
+        .collect();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params
+}
+
+/// Returns the PKCS#8 private key and the certificate, both DER encoded.
+pub fn generate_der(
+    mut params: CertificateParams,
+    ca: Option<&Certificate>,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    if ca.is_some() {
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(365);
+    }
+    let cert = Certificate::from_params(params).context("Failed to generate certificate")?;
+
+    let der = match ca {
+        Some(ca) => cert
+            .serialize_der_with_signer(ca)
+            .context("Failed to sign certificate with the CA")?,
+        None => cert
+            .serialize_der()
+            .context("Failed to self-sign certificate")?,
+    };
+
+    Ok((cert.serialize_private_key_der(), der))
+}
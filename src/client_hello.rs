@@ -0,0 +1,319 @@
+use crate::{AsyncStream, Opt};
+use anyhow::Result;
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+const HANDSHAKE_RECORD: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const SERVER_NAME_EXTENSION: u16 = 0;
+const SUPPORTED_GROUPS_EXTENSION: u16 = 10;
+const EC_POINT_FORMATS_EXTENSION: u16 = 11;
+const ALPN_EXTENSION: u16 = 16;
+
+/// Stop buffering if a ClientHello hasn't completed after this many bytes.
+const MAX_SNIFF_BYTES: usize = 1 << 16;
+
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub alpn: Vec<String>,
+    /// The JA3 fingerprint string: version, ciphers, extensions, curves and point formats.
+    pub ja3: String,
+}
+
+pub enum Sniffed {
+    Incomplete,
+    NotTls,
+    Malformed,
+    ClientHello(ClientHello),
+}
+
+/// A cursor over a byte slice that fails on truncated input.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.take(3)?;
+        Some(usize::from_be_bytes([
+            0, 0, 0, 0, 0, bytes[0], bytes[1], bytes[2],
+        ]))
+    }
+
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()?;
+        Some(Reader(self.take(len.into())?))
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()?;
+        Some(Reader(self.take(len.into())?))
+    }
+}
+
+/// Reassembles the handshake messages carried by the TLS records in `data`,
+/// so a ClientHello fragmented across several records can be parsed.
+/// Returns `None` if `data` doesn't look like TLS records.
+fn handshake_bytes(data: &[u8]) -> Option<Vec<u8>> {
+    let mut records = Reader(data);
+    let mut handshake = vec![];
+    while !records.0.is_empty() {
+        if records.0[0] != HANDSHAKE_RECORD {
+            return None;
+        }
+        if records.0.len() < 5 {
+            break;
+        }
+        let header = records.take(5).unwrap();
+        if header[1] != 3 {
+            return None;
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]).into();
+        match records.take(len) {
+            Some(fragment) => handshake.extend_from_slice(fragment),
+            None => {
+                handshake.extend_from_slice(records.0);
+                break;
+            }
+        }
+    }
+    Some(handshake)
+}
+
+/// GREASE values (RFC 8701) are random per client and left out of JA3.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn ja3_list(values: impl IntoIterator<Item = u16>) -> String {
+    values
+        .into_iter()
+        .filter(|&value| !is_grease(value))
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn u16_list(mut reader: Reader) -> Option<Vec<u16>> {
+    let mut values = vec![];
+    while !reader.0.is_empty() {
+        values.push(reader.u16()?);
+    }
+    Some(values)
+}
+
+#[derive(Default)]
+struct Extensions {
+    server_name: Option<String>,
+    alpn: Vec<String>,
+    types: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u16>,
+}
+
+fn parse_extensions(mut extensions: Reader) -> Option<Extensions> {
+    let mut parsed = Extensions::default();
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        parsed.types.push(extension_type);
+        match extension_type {
+            SERVER_NAME_EXTENSION => {
+                let mut names = data.vec16()?;
+                while !names.0.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vec16()?;
+                    if name_type == 0 {
+                        parsed.server_name = Some(String::from_utf8_lossy(name.0).into_owned());
+                    }
+                }
+            }
+            SUPPORTED_GROUPS_EXTENSION => parsed.groups = u16_list(data.vec16()?)?,
+            EC_POINT_FORMATS_EXTENSION => {
+                parsed.point_formats = data.vec8()?.0.iter().map(|&f| f.into()).collect();
+            }
+            ALPN_EXTENSION => {
+                let mut protocols = data.vec16()?;
+                while !protocols.0.is_empty() {
+                    let protocol = protocols.vec8()?;
+                    parsed
+                        .alpn
+                        .push(String::from_utf8_lossy(protocol.0).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(parsed)
+}
+
+fn parse_body(mut body: Reader) -> Option<ClientHello> {
+    let version = body.u16()?; // legacy_version
+    body.take(32)?; // random
+    body.vec8()?; // legacy_session_id
+    let ciphers = u16_list(body.vec16()?)?;
+    body.vec8()?; // legacy_compression_methods
+    let extensions = if body.0.is_empty() {
+        Extensions::default()
+    } else {
+        parse_extensions(body.vec16()?)?
+    };
+
+    let ja3 = [
+        version.to_string(),
+        ja3_list(ciphers),
+        ja3_list(extensions.types),
+        ja3_list(extensions.groups),
+        ja3_list(extensions.point_formats),
+    ]
+    .join(",");
+    Some(ClientHello {
+        server_name: extensions.server_name,
+        alpn: extensions.alpn,
+        ja3,
+    })
+}
+
+/// Parses the start of a client stream as a TLS ClientHello.
+pub fn parse(data: &[u8]) -> Sniffed {
+    let Some(handshake) = handshake_bytes(data) else {
+        return Sniffed::NotTls;
+    };
+    let mut message = Reader(&handshake);
+    match message.u8() {
+        Some(CLIENT_HELLO) => {}
+        Some(_) => return Sniffed::NotTls,
+        None => return Sniffed::Incomplete,
+    }
+    let Some(len) = message.u24() else {
+        return Sniffed::Incomplete;
+    };
+    let Some(body) = message.take(len) else {
+        return Sniffed::Incomplete;
+    };
+
+    match parse_body(Reader(body)) {
+        Some(hello) => Sniffed::ClientHello(hello),
+        None => Sniffed::Malformed,
+    }
+}
+
+/// Reads from the client until it is clear whether it starts with a TLS ClientHello,
+/// logs its SNI and ALPN or JA3 fingerprint, and returns everything read so it can
+/// be forwarded.
+pub async fn sniff(opt: &Opt, stream: &mut AsyncStream) -> Result<(Vec<u8>, Option<ClientHello>)> {
+    let mut data = vec![];
+    let mut buf = vec![0; 1 << 14];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        data.extend_from_slice(&buf[..n]);
+        let hello = match parse(&data) {
+            Sniffed::Incomplete if n > 0 && data.len() < MAX_SNIFF_BYTES => continue,
+            Sniffed::Incomplete | Sniffed::NotTls => {
+                if !data.is_empty() {
+                    info!("Not a TLS ClientHello, forwarding unchanged");
+                }
+                return Ok((data, None));
+            }
+            Sniffed::Malformed => {
+                warn!("Malformed TLS ClientHello, forwarding unchanged");
+                return Ok((data, None));
+            }
+            Sniffed::ClientHello(hello) => hello,
+        };
+        if opt.sniff_sni || !opt.route.is_empty() {
+            let alpn = if hello.alpn.is_empty() {
+                "none".to_string()
+            } else {
+                hello.alpn.join(", ")
+            };
+            info!(
+                "ClientHello SNI: {}, ALPN: {alpn}",
+                hello.server_name.as_deref().unwrap_or("none")
+            );
+        }
+        if opt.ja3 {
+            info!("JA3: {:x} {}", md5::compute(&hello.ja3), hello.ja3);
+        }
+        return Ok((data, Some(hello)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn extension(extension_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut out = extension_type.to_be_bytes().to_vec();
+        out.extend(vec16(data));
+        out
+    }
+
+    fn client_hello_record(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend([0; 32]);
+        body.push(0);
+        body.extend(vec16(&[0x1a, 0x1a, 0x13, 0x01, 0xc0, 0x2f]));
+        body.extend([1, 0]);
+        body.extend(vec16(extensions));
+
+        let mut handshake = vec![CLIENT_HELLO];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+        let mut record = vec![HANDSHAKE_RECORD, 3, 1];
+        record.extend(vec16(&handshake));
+        record
+    }
+
+    #[test]
+    fn ja3_skips_grease() {
+        let mut extensions = extension(0x2a2a, &[]);
+        extensions.extend(extension(
+            SERVER_NAME_EXTENSION,
+            &vec16(&[&[0][..], &vec16(b"example.com")].concat()),
+        ));
+        extensions.extend(extension(
+            SUPPORTED_GROUPS_EXTENSION,
+            &vec16(&[0x4a, 0x4a, 0x00, 0x1d, 0x00, 0x17]),
+        ));
+        extensions.extend(extension(EC_POINT_FORMATS_EXTENSION, &[1, 0]));
+        extensions.extend(extension(0xfafa, &[0]));
+
+        let Sniffed::ClientHello(hello) = parse(&client_hello_record(&extensions)) else {
+            panic!("expected a ClientHello");
+        };
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.ja3, "771,4865-49199,0-10-11,29-23,0");
+    }
+
+    #[test]
+    fn truncated_extension_is_malformed() {
+        let extensions = extension(SUPPORTED_GROUPS_EXTENSION, &[0, 4, 0, 0x1d]);
+        assert!(matches!(
+            parse(&client_hello_record(&extensions)),
+            Sniffed::Malformed
+        ));
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err("expected one of auto, always or never"),
+        }
+    }
+}
+
+static ENABLED: OnceLock<bool> = OnceLock::new();
+
+/// Decides once whether log lines are colored.
+pub fn init(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::io::stdout().is_terminal()
+        }
+    };
+    let _ = ENABLED.set(enabled);
+}
+
+/// Connection colors, avoiding the green and blue of the arrows.
+const CONNECTION_COLORS: [&str; 8] = ["36", "33", "35", "31", "96", "93", "95", "91"];
+
+fn paint(color: &str, text: impl Display) -> String {
+    if ENABLED.get().copied().unwrap_or(false) {
+        format!("\x1b[{color}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// The `[i]` that starts each line about a connection, in a color stable for `i`.
+pub fn tag(i: usize) -> String {
+    paint(
+        CONNECTION_COLORS[i % CONNECTION_COLORS.len()],
+        format_args!("[{i}]"),
+    )
+}
+
+/// Marks data read from the client.
+pub fn incoming() -> String {
+    paint("1;32", "==>")
+}
+
+/// Marks data read from the upstream.
+pub fn outgoing() -> String {
+    paint("1;34", "<==")
+}
@@ -0,0 +1,308 @@
+use crate::dump::Dump;
+use crate::har::Har;
+use crate::logging::{self, Direction, LogFormat};
+use crate::{pcap, Opt};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fmt::Write;
+use std::str::FromStr;
+use tracing::{debug, info, trace, Level};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    Text,
+    Hex,
+}
+
+impl FromStr for DataFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(DataFormat::Text),
+            "hex" => Ok(DataFormat::Hex),
+            _ => Err("expected text or hex"),
+        }
+    }
+}
+
+/// Logs the data forwarded in both directions of one connection.
+pub struct DataLog {
+    i: usize,
+    /// Bytes read so far in each direction, also used to continue hex dump offsets
+    /// across chunks.
+    incoming_bytes: usize,
+    outgoing_bytes: usize,
+    /// Bytes printed so far in both directions, counted against --max-show-total.
+    shown: usize,
+    dump: Dump,
+    har: Option<Har>,
+}
+
+impl DataLog {
+    pub fn new(i: usize) -> Self {
+        Self {
+            i,
+            incoming_bytes: 0,
+            outgoing_bytes: 0,
+            shown: 0,
+            dump: Dump::default(),
+            har: None,
+        }
+    }
+
+    /// Bytes read from the client and from the upstream so far.
+    pub fn totals(&self) -> (usize, usize) {
+        (self.incoming_bytes, self.outgoing_bytes)
+    }
+
+    pub fn incoming(&mut self, opt: &Opt, data_read: &[u8]) {
+        self.read(opt, Direction::Incoming, data_read, true)
+    }
+
+    pub fn outgoing(&mut self, opt: &Opt, data_read: &[u8]) {
+        self.read(opt, Direction::Outgoing, data_read, true)
+    }
+
+    /// Logs `bytes` forwarded in `direction` that the proxy never saw, as they were
+    /// spliced.
+    pub fn spliced(&mut self, opt: &Opt, direction: Direction, bytes: usize) {
+        match direction {
+            Direction::Incoming => self.incoming_bytes += bytes,
+            Direction::Outgoing => self.outgoing_bytes += bytes,
+        }
+        if bytes > 0 && !opt.summary_only {
+            debug!(event = "data", direction = direction.name(), bytes);
+        }
+    }
+
+    /// Logs data read in `direction`, without its payload unless `show`, like when
+    /// it is shown decoded instead.
+    pub fn read(&mut self, opt: &Opt, direction: Direction, data_read: &[u8], show: bool) {
+        let total = match direction {
+            Direction::Incoming => &mut self.incoming_bytes,
+            Direction::Outgoing => &mut self.outgoing_bytes,
+        };
+        let offset = *total;
+        *total += data_read.len();
+        self.log_data_read(opt, direction, offset, data_read, show)
+    }
+
+    fn log_data_read(
+        &mut self,
+        opt: &Opt,
+        direction: Direction,
+        offset: usize,
+        data_read: &[u8],
+        show: bool,
+    ) {
+        if data_read.is_empty() {
+            return;
+        }
+        self.dump.write(opt, self.i, direction, data_read);
+        pcap::data(self.i, direction, data_read);
+        if opt.har.is_some() {
+            self.har
+                .get_or_insert_with(|| Har::new(opt, self.i))
+                .read(direction, data_read);
+        }
+        if opt.summary_only {
+            return;
+        }
+        // Payloads are TRACE events, so -q hides them even with --show-data.
+        let shown = if show && tracing::enabled!(Level::TRACE) {
+            self.take_shown(opt, data_read)
+        } else {
+            None
+        };
+        let direction = direction.name();
+        let bytes = data_read.len();
+        let Some(shown) = shown else {
+            debug!(event = "data", direction, bytes);
+            return;
+        };
+        let (text, base64) = payload(opt, offset, shown);
+        let omitted = (shown.len() < bytes).then(|| bytes - shown.len());
+        trace!(
+            event = "data",
+            direction,
+            bytes,
+            text = text.as_deref(),
+            base64 = base64.as_deref(),
+            omitted
+        );
+        if opt.max_show_total == Some(self.shown) {
+            info!("Shown {} bytes, not showing more data", self.shown);
+        }
+    }
+
+    /// Logs part of a body forwarded before, as it decoded from `encoding`, with
+    /// `offset` bytes of it decoded before.
+    pub fn decoded(
+        &mut self,
+        opt: &Opt,
+        direction: Direction,
+        encoding: &str,
+        offset: usize,
+        decoded: &[u8],
+    ) {
+        if decoded.is_empty() || opt.summary_only || !tracing::enabled!(Level::TRACE) {
+            return;
+        }
+        let Some(shown) = self.take_shown(opt, decoded) else {
+            return;
+        };
+        let (text, base64) = payload(opt, offset, shown);
+        let bytes = decoded.len();
+        let omitted = (shown.len() < bytes).then(|| bytes - shown.len());
+        trace!(
+            event = "data",
+            direction = direction.name(),
+            bytes,
+            decoded = encoding,
+            text = text.as_deref(),
+            base64 = base64.as_deref(),
+            omitted
+        );
+    }
+
+    /// Returns the part of `data_read` that --show-data and its limits allow printing.
+    fn take_shown<'a>(&mut self, opt: &Opt, data_read: &'a [u8]) -> Option<&'a [u8]> {
+        if !logging::show_data() {
+            return None;
+        }
+        let mut limit = opt.max_show_bytes.unwrap_or(usize::MAX);
+        if let Some(max_total) = opt.max_show_total {
+            if self.shown >= max_total {
+                return None;
+            }
+            limit = limit.min(max_total - self.shown);
+        }
+        let shown = &data_read[..data_read.len().min(limit)];
+        self.shown += shown.len();
+        Some(shown)
+    }
+}
+
+/// The `text` and `base64` fields of a chunk shown in the --log-format: rendered for
+/// the terminal in text, or exactly in JSON with base64 for binary data.
+fn payload(opt: &Opt, offset: usize, shown: &[u8]) -> (Option<String>, Option<String>) {
+    if opt.log_format == LogFormat::Text {
+        return (Some(render_payload(opt, offset, shown)), None);
+    }
+    let text = String::from_utf8_lossy(shown);
+    if opt.force_text || !looks_binary(&text) {
+        (Some(text.into_owned()), None)
+    } else {
+        (None, Some(STANDARD.encode(shown)))
+    }
+}
+
+/// Formats a chunk of data in the --data-format, `offset` bytes into its stream.
+fn render_payload(opt: &Opt, offset: usize, shown: &[u8]) -> String {
+    match opt.data_format {
+        DataFormat::Text => {
+            let text = String::from_utf8_lossy(shown);
+            if !opt.force_text && looks_binary(&text) {
+                format!("<binary data, {} bytes>", shown.len())
+            } else {
+                escape_controls(&text)
+            }
+        }
+        DataFormat::Hex => {
+            let mut dump = hex_dump(offset, shown);
+            dump.pop();
+            dump
+        }
+    }
+}
+
+/// Line breaks and tabs are printed as they are, other control characters are escaped.
+fn is_escaped(c: char) -> bool {
+    c.is_control() && !matches!(c, '\t' | '\n' | '\r')
+}
+
+/// Whether more than a tenth of `text` is control characters or invalid UTF-8.
+pub fn looks_binary(text: &str) -> bool {
+    let suspicious = text
+        .chars()
+        .filter(|&c| is_escaped(c) || c == char::REPLACEMENT_CHARACTER)
+        .count();
+    suspicious * 10 > text.chars().count()
+}
+
+/// Escapes control characters like `cat -v`, so data can't drive the terminal.
+fn escape_controls(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if !is_escaped(c) {
+            escaped.push(c);
+            continue;
+        }
+        let mut code = c as u32;
+        if code >= 0x80 {
+            escaped.push_str("M-");
+            code -= 0x80;
+        }
+        escaped.push('^');
+        escaped.push(char::from_u32(code ^ 0x40).unwrap());
+    }
+    escaped
+}
+
+/// Formats `data` like `xxd`, numbering lines from `offset`.
+fn hex_dump(offset: usize, data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (n, line) in data.chunks(16).enumerate() {
+        write!(dump, "{:08x}:", offset + n * 16).unwrap();
+        for column in 0..16 {
+            if column % 2 == 0 {
+                dump.push(' ');
+            }
+            match line.get(column) {
+                Some(byte) => write!(dump, "{byte:02x}").unwrap(),
+                None => dump.push_str("  "),
+            }
+        }
+        dump.push_str("  ");
+        dump.extend(line.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push('\n');
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(
+            escape_controls("a\x1b[2Jb\r\n\t\x7f\u{9b}"),
+            "a^[[2Jb\r\n\t^?M-^["
+        );
+    }
+
+    #[test]
+    fn binary_detection() {
+        assert!(!looks_binary("GET / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert!(looks_binary(&String::from_utf8_lossy(&[
+            0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03
+        ])));
+    }
+
+    #[test]
+    fn hex_dump_matches_xxd() {
+        assert_eq!(
+            hex_dump(0x10, b"GET / HTTP/1.1\r\nHost: x\r\n"),
+            "00000010: 4745 5420 2f20 4854 5450 2f31 2e31 0d0a  GET / HTTP/1.1..\n\
+             00000020: 486f 7374 3a20 780d 0a                   Host: x..\n"
+        );
+    }
+}
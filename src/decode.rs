@@ -0,0 +1,109 @@
+use flate2::write::{GzDecoder, ZlibDecoder};
+use std::io::Write;
+
+/// Decompresses a body with --decode-bodies, only to show it; the body is forwarded
+/// as it was sent.
+pub struct Decoder {
+    pub encoding: &'static str,
+    /// Bytes decoded so far, to continue hex dump offsets across chunks.
+    pub offset: usize,
+    inner: Inner,
+}
+
+enum Inner {
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(ZlibDecoder<Vec<u8>>),
+    // Boxed, as its state is much larger than the others'.
+    Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+}
+
+impl Decoder {
+    /// A decoder for the Content-Encoding `encoding`, if it is one that can be
+    /// decoded.
+    pub fn new(encoding: &str) -> Option<Self> {
+        let (encoding, inner) = match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => ("gzip", Inner::Gzip(GzDecoder::new(vec![]))),
+            "deflate" => ("deflate", Inner::Deflate(ZlibDecoder::new(vec![]))),
+            "br" => (
+                "br",
+                Inner::Brotli(Box::new(brotli::DecompressorWriter::new(vec![], 4096))),
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            encoding,
+            offset: 0,
+            inner,
+        })
+    }
+
+    /// Decodes more of the body, returning what that decoded to.
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let decoded = match &mut self.inner {
+            Inner::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                std::mem::take(decoder.get_mut())
+            }
+            Inner::Deflate(decoder) => {
+                decoder.write_all(data)?;
+                std::mem::take(decoder.get_mut())
+            }
+            Inner::Brotli(decoder) => {
+                decoder.write_all(data)?;
+                std::mem::take(decoder.get_mut())
+            }
+        };
+        self.offset += decoded.len();
+        Ok(decoded)
+    }
+
+    /// Decodes the rest once the body ended.
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self.inner {
+            Inner::Gzip(decoder) => decoder.finish(),
+            Inner::Deflate(decoder) => decoder.finish(),
+            Inner::Brotli(mut decoder) => {
+                decoder.close()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    #[test]
+    fn bodies_decode_across_chunks() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"hello, hello, hello").unwrap();
+        let body = encoder.finish().unwrap();
+
+        let mut decoder = Decoder::new("GZIP").unwrap();
+        let mut decoded = vec![];
+        for chunk in body.chunks(3) {
+            decoded.extend(decoder.write(chunk).unwrap());
+        }
+        decoded.extend(decoder.finish().unwrap());
+        assert_eq!(decoded, b"hello, hello, hello");
+
+        let mut encoder = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+        encoder.write_all(b"hello, hello, hello").unwrap();
+        let body = encoder.into_inner();
+
+        let mut decoder = Decoder::new("br").unwrap();
+        let mut decoded = vec![];
+        for chunk in body.chunks(3) {
+            decoded.extend(decoder.write(chunk).unwrap());
+        }
+        decoded.extend(decoder.finish().unwrap());
+        assert_eq!(decoded, b"hello, hello, hello");
+
+        assert!(Decoder::new("zstd").is_none());
+        let mut decoder = Decoder::new("deflate").unwrap();
+        assert!(decoder.write(b"not zlib").is_err());
+    }
+}
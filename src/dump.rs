@@ -0,0 +1,61 @@
+use crate::logging::Direction;
+use crate::Opt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::error;
+
+/// One direction of a connection's --dump-dir capture.
+#[derive(Default)]
+enum DumpFile {
+    /// Nothing has been read in this direction yet.
+    #[default]
+    Unopened,
+    Open(BufWriter<File>),
+    /// Opening or writing failed, which has been reported once.
+    Failed,
+}
+
+/// Raw copies of the bytes read in both directions of connection `i`, written to
+/// `conn-<i>-in.bin` and `conn-<i>-out.bin`. The files are flushed when dropped.
+#[derive(Default)]
+pub struct Dump {
+    incoming: DumpFile,
+    outgoing: DumpFile,
+}
+
+impl Dump {
+    pub fn write(&mut self, opt: &Opt, i: usize, direction: Direction, data: &[u8]) {
+        let Some(dir) = &opt.dump_dir else {
+            return;
+        };
+        let (file, suffix) = match direction {
+            Direction::Incoming => (&mut self.incoming, "in"),
+            Direction::Outgoing => (&mut self.outgoing, "out"),
+        };
+        let path = dir.join(format!("conn-{i}-{suffix}.bin"));
+        if let DumpFile::Unopened = file {
+            *file = match open(&path) {
+                Ok(opened) => DumpFile::Open(BufWriter::new(opened)),
+                Err(e) => {
+                    error!("Failed to create {}: {e}", path.display());
+                    DumpFile::Failed
+                }
+            };
+        }
+        if let DumpFile::Open(writer) = file {
+            if let Err(e) = writer.write_all(data) {
+                error!("Failed to write to {}: {e}", path.display());
+                *file = DumpFile::Failed;
+            }
+        }
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+}
@@ -0,0 +1,104 @@
+use crate::stats::Connection;
+use crate::Opt;
+use socket2::Socket;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Duration;
+use tracing::warn;
+
+/// The SplitMix64 increment.
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Picks the connections --fault-drop-rate drops, from --fault-seed so a run can be
+/// repeated.
+pub struct Faults {
+    pub seed: u64,
+    state: AtomicU64,
+}
+
+impl Faults {
+    pub fn new(opt: &Opt) -> Self {
+        let seed = opt
+            .fault_seed
+            .unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        Faults {
+            seed,
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    /// Whether the next connection should be dropped. Connections are asked about
+    /// in the order they are accepted, so the same seed drops the same ones.
+    pub fn drop_next(&self, opt: &Opt) -> bool {
+        opt.fault_drop_rate > 0.0 && self.next_f64() < opt.fault_drop_rate
+    }
+
+    /// A number in [0, 1) from SplitMix64.
+    fn next_f64(&self) -> f64 {
+        let mut z = self.state.fetch_add(GAMMA, Relaxed).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A duplicate of the client's socket with --fault-reset, so a fault can make
+/// closing it send an RST.
+pub fn resettable(opt: &Opt, stream: &tokio::net::TcpStream) -> Option<Socket> {
+    opt.fault_reset
+        .then(|| socket2::SockRef::from(stream).try_clone().ok())
+        .flatten()
+}
+
+/// Makes closing `socket` send an RST rather than a FIN.
+pub fn reset(socket: Option<&Socket>) {
+    if let Some(socket) = socket {
+        if let Err(e) = socket.set_linger(Some(Duration::ZERO)) {
+            warn!("Failed to set SO_LINGER for --fault-reset: {e}");
+        }
+    }
+}
+
+/// Closes the connection right away for --fault-drop-rate.
+pub fn drop_connection<T>(stream: T, socket: Option<&Socket>) {
+    warn!("Fault: dropping the connection (--fault-drop-rate)");
+    reset(socket);
+    drop(stream);
+}
+
+/// Resolves once about `bytes` were forwarded for --fault-abort-after, so the
+/// connection can be killed.
+pub async fn abort_after(bytes: u64, connection: &Connection, socket: Option<&Socket>) {
+    connection.forwarded_total(bytes).await;
+    warn!("Fault: aborting the connection after {bytes} forwarded bytes (--fault-abort-after)");
+    reset(socket);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn dropped(args: &[&str]) -> Vec<bool> {
+        let opt = Opt::from_iter(["tcp-proxy", "localhost"].iter().chain(args));
+        let faults = Faults::new(&opt);
+        (0..1000).map(|_| faults.drop_next(&opt)).collect()
+    }
+
+    #[test]
+    fn the_same_seed_drops_the_same_connections() {
+        let args = ["--fault-drop-rate", "0.25", "--fault-seed", "42"];
+        let first = dropped(&args);
+        assert_eq!(first, dropped(&args));
+        let count = first.iter().filter(|&&dropped| dropped).count();
+        assert!((200..300).contains(&count), "dropped {count}");
+
+        let other = dropped(&["--fault-drop-rate", "0.25", "--fault-seed", "43"]);
+        assert_ne!(first, other);
+
+        assert!(!dropped(&[]).contains(&true));
+        assert!(!dropped(&["--fault-drop-rate", "1"]).contains(&false));
+    }
+}
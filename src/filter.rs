@@ -0,0 +1,225 @@
+use crate::logging::Direction;
+use crate::{AsyncStream, Opt};
+use anyhow::{Context as _, Result};
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream,
+    ReadBuf, ReadHalf, WriteHalf,
+};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+use tracing::{info, warn, Instrument, Span};
+
+/// The data read from a stream as a --filter-cmd outputs it. What is written
+/// goes to the stream unchanged.
+pub struct Filtered {
+    output: DuplexStream,
+    write: WriteHalf<AsyncStream>,
+    /// Why the filter failed, once it did.
+    error: Arc<Mutex<Option<io::Error>>>,
+    /// Pipes the data through the filter, stopped when the stream is dropped.
+    task: JoinHandle<()>,
+}
+
+impl Filtered {
+    /// `inner` with what is read from it piped through --filter-cmd if it applies
+    /// to `direction`.
+    pub fn wrap(opt: &Opt, direction: Direction, inner: AsyncStream) -> Result<AsyncStream> {
+        let Some(command) = &opt.filter_cmd else {
+            return Ok(inner);
+        };
+        if !opt.filter_direction.includes(direction) {
+            return Ok(inner);
+        }
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .env("TCP_PROXY_DIRECTION", direction.name())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run --filter-cmd {command}"))?;
+        let stderr = BufReader::new(child.stderr.take().unwrap());
+        tokio::spawn(
+            async move {
+                let mut lines = stderr.lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    warn!("Filter: {line}");
+                }
+            }
+            .instrument(Span::current()),
+        );
+
+        let (read, write) = tokio::io::split(inner);
+        let (output, mut filtered) = tokio::io::duplex(1 << 16);
+        let error = Arc::new(Mutex::new(None));
+        let task = tokio::spawn({
+            let error = error.clone();
+            let fallback = opt.filter_fallback;
+            async move {
+                // Stored before the output ends, so reading it sees the error.
+                if let Err(e) = pipe(child, direction, read, &mut filtered, fallback).await {
+                    *error.lock().unwrap() = Some(e);
+                }
+            }
+            .instrument(Span::current())
+        });
+        Ok(Box::pin(Filtered {
+            output,
+            write,
+            error,
+            task,
+        }))
+    }
+}
+
+/// Feeds what is read from `input` to the filter and writes what it outputs to
+/// `output`. If the filter exits before `input` ended the rest is forwarded as it
+/// is with `fallback`.
+async fn pipe(
+    mut child: Child,
+    direction: Direction,
+    mut input: ReadHalf<AsyncStream>,
+    output: &mut DuplexStream,
+    fallback: bool,
+) -> io::Result<()> {
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let mut buf = vec![0; 1 << 16];
+    // The input still to be forwarded once the filter is gone, if it didn't end.
+    let unfiltered = {
+        let to_filter = async {
+            loop {
+                let n = input.read(&mut buf).await?;
+                if n == 0 {
+                    drop(stdin);
+                    return Ok(None);
+                }
+                if stdin.write_all(&buf[..n]).await.is_err() {
+                    return Ok::<_, io::Error>(Some(buf[..n].to_vec()));
+                }
+            }
+        };
+        let from_filter = tokio::io::copy(&mut stdout, output);
+        tokio::pin!(to_filter, from_filter);
+        tokio::select! {
+            // The input ending first isn't the filter exiting early.
+            biased;
+            sent = &mut to_filter => {
+                let unfiltered = sent?;
+                from_filter.await?;
+                unfiltered
+            }
+            copied = &mut from_filter => {
+                copied?;
+                // Stopping the input to the filter loses what it was writing there.
+                Some(vec![])
+            }
+        }
+    };
+    match child.wait().await {
+        Ok(status) if !status.success() => {
+            warn!(
+                "--filter-cmd for {} data exited with {status}",
+                direction.name()
+            )
+        }
+        _ => {}
+    }
+
+    let Some(data) = unfiltered else {
+        output.shutdown().await?;
+        return Ok(());
+    };
+    if !fallback {
+        return Err(io::Error::other(
+            "--filter-cmd exited before the data ended",
+        ));
+    }
+    info!(
+        "--filter-cmd for {} data exited before it ended, forwarding the rest unfiltered",
+        direction.name()
+    );
+    output.write_all(&data).await?;
+    tokio::io::copy(&mut input, output).await?;
+    output.shutdown().await
+}
+
+impl AsyncRead for Filtered {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.output).poll_read(cx, buf) {
+            // Why the filter failed is stored before its output ends.
+            Poll::Ready(Ok(())) if buf.filled().len() == filled => {
+                match self.error.lock().unwrap().take() {
+                    Some(e) => Poll::Ready(Err(e)),
+                    None => Poll::Ready(Ok(())),
+                }
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl AsyncWrite for Filtered {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_shutdown(cx)
+    }
+}
+
+impl Drop for Filtered {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    /// What reading through `command` gives after "hello" was sent, and the peer
+    /// closed if `close`.
+    async fn filtered(command: &str, close: bool) -> io::Result<Vec<u8>> {
+        let opt = Opt::from_iter(["tcp-proxy", "localhost", "--filter-cmd", command]);
+        let (mut peer, inner) = tokio::io::duplex(1 << 16);
+        let mut stream = Filtered::wrap(&opt, Direction::Incoming, Box::pin(inner)).unwrap();
+        peer.write_all(b"hello").await.unwrap();
+        if close {
+            peer.shutdown().await.unwrap();
+        }
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.map(|_| received)
+    }
+
+    #[tokio::test]
+    async fn data_is_piped_through_the_filter() {
+        assert_eq!(filtered("tr a-z A-Z", true).await.unwrap(), b"HELLO");
+        let error = filtered("exit 3", false).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--filter-cmd exited before the data ended"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{AsyncStream, Opt};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// A stream that what is written to goes out in separate writes of at most `size`
+/// bytes, with `delay` between the slices of one write. What is read from it is
+/// unchanged.
+pub struct Fragmented {
+    inner: AsyncStream,
+    size: usize,
+    delay: Duration,
+    /// Before the next slice, once a write was cut short.
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Fragmented {
+    /// `inner` with writes cut into slices by --fragment if it was given.
+    pub fn wrap(opt: &Opt, inner: AsyncStream) -> AsyncStream {
+        match opt.fragment {
+            Some(size) => Box::pin(Fragmented::new(
+                size,
+                Duration::from_millis(opt.fragment_delay),
+                inner,
+            )),
+            None => inner,
+        }
+    }
+
+    fn new(size: usize, delay: Duration, inner: AsyncStream) -> Self {
+        Fragmented {
+            inner,
+            size,
+            delay,
+            sleep: None,
+        }
+    }
+}
+
+impl AsyncRead for Fragmented {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Fragmented {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(sleep) = &mut self.sleep {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+        }
+        let len = buf.len().min(self.size);
+        let poll = self.inner.as_mut().poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(written)) = poll {
+            // The rest of `buf` is the next slice, which waits for --fragment-delay.
+            if written < buf.len() && !self.delay.is_zero() {
+                self.sleep = Some(Box::pin(tokio::time::sleep(self.delay)));
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::Instant;
+
+    /// Records the size of each write it gets.
+    struct Writes(Arc<Mutex<Vec<usize>>>);
+
+    impl AsyncRead for Writes {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Writes {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.lock().unwrap().push(buf.len());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_are_cut_into_delayed_slices() {
+        let writes = Arc::default();
+        let inner = Box::pin(Writes(Arc::clone(&writes)));
+        let mut fragmented = Fragmented::new(3, Duration::from_millis(100), inner);
+        let started = Instant::now();
+        fragmented.write_all(b"0123456789").await.unwrap();
+        assert_eq!(*writes.lock().unwrap(), [3, 3, 3, 1]);
+        assert_eq!(started.elapsed(), Duration::from_millis(300));
+
+        // Only the slices of one write are apart.
+        fragmented.write_all(b"ab").await.unwrap();
+        assert_eq!(*writes.lock().unwrap(), [3, 3, 3, 1, 2]);
+        assert_eq!(started.elapsed(), Duration::from_millis(300));
+    }
+}
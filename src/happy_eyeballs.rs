@@ -0,0 +1,175 @@
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long an attempt gets before the next address is tried alongside it, as RFC
+/// 8305 recommends.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// `addrs` alternating between IPv6 and IPv4, starting with IPv6 if `prefer_ipv6`
+/// or otherwise the family of the first one. Each family keeps its order.
+pub fn interleave(addrs: Vec<SocketAddr>, prefer_ipv6: Option<bool>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let ipv6_first = prefer_ipv6.unwrap_or(first.is_ipv6());
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == ipv6_first);
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connects to `addrs` with `connect`, starting an attempt for each in turn every
+/// `delay` or as soon as the one before fails, and returns the connection that is
+/// made first and where to. The other attempts are dropped.
+pub async fn race<T, F>(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    connect: impl Fn(SocketAddr) -> F,
+) -> io::Result<(T, SocketAddr)>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    let mut next = addrs.iter().copied().peekable();
+    let mut attempts: Vec<(SocketAddr, Pin<Box<F>>)> = vec![];
+    let mut last_error = None;
+    let mut next_attempt = Box::pin(tokio::time::sleep(Duration::ZERO));
+    poll_fn(|cx| loop {
+        if next.peek().is_some() && next_attempt.as_mut().poll(cx).is_ready() {
+            let addr = next.next().unwrap();
+            attempts.push((addr, Box::pin(connect(addr))));
+            next_attempt.as_mut().reset(Instant::now() + delay);
+            continue;
+        }
+        let mut failed = false;
+        let mut i = 0;
+        while i < attempts.len() {
+            match attempts[i].1.as_mut().poll(cx) {
+                Poll::Ready(Ok(connection)) => return Poll::Ready(Ok((connection, attempts[i].0))),
+                Poll::Ready(Err(e)) => {
+                    attempts.remove(i);
+                    last_error = Some(e);
+                    failed = true;
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        if failed && next.peek().is_some() {
+            next_attempt.as_mut().reset(Instant::now());
+            continue;
+        }
+        if attempts.is_empty() && next.peek().is_none() {
+            let error = last_error
+                .take()
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses"));
+            return Poll::Ready(Err(error));
+        }
+        return Poll::Pending;
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn families_take_turns() {
+        let resolved = addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]);
+        assert_eq!(
+            interleave(resolved.clone(), None),
+            addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"])
+        );
+        assert_eq!(
+            interleave(resolved, Some(false)),
+            addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "[::2]:1", "[::3]:1"])
+        );
+        let resolved = addrs(&["10.0.0.1:1", "[::1]:1"]);
+        assert_eq!(interleave(resolved.clone(), None), resolved);
+        assert_eq!(
+            interleave(resolved, Some(true)),
+            addrs(&["[::1]:1", "10.0.0.1:1"])
+        );
+    }
+
+    /// Races a stub where each address connects or fails after how long it says,
+    /// returning what won, when, and which attempts were started when.
+    async fn run(
+        stub: &[(&str, u64, bool)],
+    ) -> (
+        io::Result<SocketAddr>,
+        Duration,
+        Vec<(SocketAddr, Duration)>,
+    ) {
+        let started = Instant::now();
+        let attempts = Mutex::new(vec![]);
+        let addrs: Vec<SocketAddr> = stub
+            .iter()
+            .map(|(addr, ..)| addr.parse().unwrap())
+            .collect();
+        let result = race(&addrs, ATTEMPT_DELAY, |addr| {
+            attempts.lock().unwrap().push((addr, started.elapsed()));
+            let (_, ms, works) = stub[addrs.iter().position(|&a| a == addr).unwrap()];
+            async move {
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                match works {
+                    true => Ok(()),
+                    false => Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+                }
+            }
+        })
+        .await;
+        let result = result.map(|((), addr)| addr);
+        (result, started.elapsed(), attempts.into_inner().unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_hanging_address_doesnt_hold_up_the_next() {
+        let (result, elapsed, attempts) =
+            run(&[("[::1]:1", 10_000, true), ("10.0.0.1:1", 50, true)]).await;
+        assert_eq!(result.unwrap(), "10.0.0.1:1".parse().unwrap());
+        assert_eq!(elapsed, Duration::from_millis(300));
+        assert_eq!(attempts[1].1, ATTEMPT_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_fast_first_address_wins_alone() {
+        let (result, elapsed, attempts) =
+            run(&[("[::1]:1", 100, true), ("10.0.0.1:1", 10, true)]).await;
+        assert_eq!(result.unwrap(), "[::1]:1".parse().unwrap());
+        assert_eq!(elapsed, Duration::from_millis(100));
+        assert_eq!(attempts.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_start_the_next_attempt_right_away() {
+        let (result, elapsed, attempts) = run(&[
+            ("[::1]:1", 10, false),
+            ("10.0.0.1:1", 10, false),
+            ("[::2]:1", 10, true),
+        ])
+        .await;
+        assert_eq!(result.unwrap(), "[::2]:1".parse().unwrap());
+        assert_eq!(elapsed, Duration::from_millis(30));
+        assert_eq!(attempts.len(), 3);
+
+        let (result, _, _) = run(&[("[::1]:1", 10, false), ("10.0.0.1:1", 20, false)]).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
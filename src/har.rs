@@ -0,0 +1,631 @@
+use crate::data_log::looks_binary;
+use crate::logging::{rfc3339, Direction};
+use crate::Opt;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use httparse::Status::{Complete, Partial};
+use serde_json::{json, Map, Value};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::OnceLock;
+use std::time::Instant;
+use time::OffsetDateTime;
+use tracing::{error, info};
+
+/// Closes the entries array, rewritten after each entry so the file is always valid JSON.
+const TRAILER: &str = "\n]}}\n";
+
+/// Heads that haven't completed after this many bytes aren't HTTP.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+enum Message {
+    Entry(Value),
+    Flush(Sender<()>),
+}
+
+/// Feeds the thread writing --har, which all connections share.
+static SENDER: OnceLock<Sender<Message>> = OnceLock::new();
+
+pub fn open(opt: &Opt) -> Result<()> {
+    let Some(path) = &opt.har else {
+        return Ok(());
+    };
+    if SENDER.get().is_some() {
+        return Ok(());
+    }
+    let mut file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let creator = json!({"name": "tcp-proxy", "version": env!("CARGO_PKG_VERSION")});
+    write!(
+        file,
+        "{{\"log\":{{\"version\":\"1.2\",\"creator\":{creator},\"entries\":[{TRAILER}"
+    )
+    .with_context(|| format!("Failed to write to {}", path.display()))?;
+    let path = path.clone();
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        if let Err(e) = write_entries(&mut file, receiver) {
+            error!("Failed to write to {}: {e}", path.display());
+        }
+    });
+    let _ = SENDER.set(sender);
+    Ok(())
+}
+
+fn write_entries(file: &mut File, receiver: Receiver<Message>) -> std::io::Result<()> {
+    let mut first = true;
+    for message in receiver {
+        match message {
+            Message::Entry(entry) => {
+                file.seek(SeekFrom::End(-(TRAILER.len() as i64)))?;
+                let separator = if first { "\n" } else { ",\n" };
+                write!(file, "{separator}{entry}{TRAILER}")?;
+                first = false;
+            }
+            Message::Flush(ack) => {
+                let _ = ack.send(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits until every finished entry has been written.
+pub fn flush() {
+    if let Some(sender) = SENDER.get() {
+        let (ack, done) = mpsc::channel();
+        if sender.send(Message::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Stamp {
+    instant: Instant,
+    time: OffsetDateTime,
+}
+
+impl Stamp {
+    fn now() -> Self {
+        Self {
+            instant: Instant::now(),
+            time: OffsetDateTime::now_utc(),
+        }
+    }
+
+    fn millis_until(self, later: Stamp) -> f64 {
+        later
+            .instant
+            .saturating_duration_since(self.instant)
+            .as_secs_f64()
+            * 1000.0
+    }
+}
+
+/// Where a parser is in its stream of messages.
+enum State {
+    Head,
+    Length(u64),
+    ChunkSize,
+    ChunkData(u64),
+    /// The line break after a chunk.
+    ChunkEnd,
+    Trailers,
+    UntilClose,
+    /// Not HTTP, or no longer HTTP after an upgrade.
+    Stopped,
+}
+
+/// A request or response, as far as it has been read.
+struct HttpMessage {
+    /// The HAR fields known once the head is parsed.
+    fields: Map<String, Value>,
+    mime_type: String,
+    /// Bytes of the body on the wire, including chunk framing.
+    body_size: usize,
+    /// Bytes of the body once decoded.
+    content_size: usize,
+    /// The first --har-max-body bytes of the decoded body.
+    content: Vec<u8>,
+}
+
+impl HttpMessage {
+    fn new(fields: Value, headers: &[httparse::Header], headers_size: usize) -> Self {
+        let Value::Object(mut fields) = fields else {
+            unreachable!()
+        };
+        fields.insert("cookies".into(), json!([]));
+        fields.insert(
+            "headers".into(),
+            headers
+                .iter()
+                .map(|header| json!({"name": header.name, "value": lossy(header.value)}))
+                .collect(),
+        );
+        fields.insert("headersSize".into(), headers_size.into());
+        Self {
+            fields,
+            mime_type: header(headers, "content-type").unwrap_or_default(),
+            body_size: 0,
+            content_size: 0,
+            content: vec![],
+        }
+    }
+
+    fn add_content(&mut self, data: &[u8], max_body: usize) {
+        self.content_size += data.len();
+        let room = max_body.saturating_sub(self.content.len());
+        self.content.extend(&data[..data.len().min(room)]);
+    }
+
+    /// The body as HAR `text`, with the fields saying how it is encoded or truncated.
+    fn content_fields(&self) -> Map<String, Value> {
+        let mut fields = Map::new();
+        fields.insert("mimeType".into(), self.mime_type.clone().into());
+        if self.content.is_empty() {
+            return fields;
+        }
+        let text = String::from_utf8_lossy(&self.content);
+        if looks_binary(&text) {
+            fields.insert("text".into(), STANDARD.encode(&self.content).into());
+            fields.insert("encoding".into(), "base64".into());
+        } else {
+            fields.insert("text".into(), text.into());
+        }
+        if self.content.len() < self.content_size {
+            fields.insert(
+                "comment".into(),
+                format!("truncated to {} bytes", self.content.len()).into(),
+            );
+        }
+        fields
+    }
+
+    fn into_request(mut self) -> Value {
+        self.fields.insert("bodySize".into(), self.body_size.into());
+        if self.body_size > 0 {
+            self.fields
+                .insert("postData".into(), Value::Object(self.content_fields()));
+        }
+        Value::Object(self.fields)
+    }
+
+    fn into_response(mut self) -> Value {
+        let mut content = self.content_fields();
+        content.insert("size".into(), self.content_size.into());
+        self.fields.insert("content".into(), Value::Object(content));
+        self.fields.insert("bodySize".into(), self.body_size.into());
+        Value::Object(self.fields)
+    }
+}
+
+fn lossy(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).into_owned()
+}
+
+fn header(headers: &[httparse::Header], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| lossy(header.value))
+}
+
+/// How the body after `headers` is delimited, or `otherwise` without a length.
+fn body_state(headers: &[httparse::Header], otherwise: State) -> State {
+    let chunked = header(headers, "transfer-encoding")
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        State::ChunkSize
+    } else if let Some(length) =
+        header(headers, "content-length").and_then(|value| value.trim().parse().ok())
+    {
+        State::Length(length)
+    } else {
+        otherwise
+    }
+}
+
+/// One direction of a connection.
+struct Parser {
+    buffer: Vec<u8>,
+    state: State,
+    /// When the first byte of the current head was read.
+    started: Option<Stamp>,
+}
+
+impl Parser {
+    fn new() -> Self {
+        Self {
+            buffer: vec![],
+            state: State::Head,
+            started: None,
+        }
+    }
+
+    /// Reads as much of the body of `message` as is buffered, returning whether it
+    /// is complete.
+    fn read_body(&mut self, message: &mut HttpMessage, max_body: usize) -> bool {
+        loop {
+            match self.state {
+                State::Head | State::Stopped => return true,
+                State::Length(0) => {
+                    self.state = State::Head;
+                    return true;
+                }
+                State::Length(remaining) | State::ChunkData(remaining) => {
+                    let n = self.buffer.len().min(remaining as usize);
+                    if n == 0 {
+                        return false;
+                    }
+                    message.add_content(&self.buffer[..n], max_body);
+                    message.body_size += n;
+                    self.buffer.drain(..n);
+                    let remaining = remaining - n as u64;
+                    self.state = match self.state {
+                        State::Length(_) => State::Length(remaining),
+                        _ if remaining == 0 => State::ChunkEnd,
+                        _ => State::ChunkData(remaining),
+                    };
+                }
+                State::ChunkSize => match httparse::parse_chunk_size(&self.buffer) {
+                    Ok(Complete((n, size))) => {
+                        message.body_size += n;
+                        self.buffer.drain(..n);
+                        self.state = if size == 0 {
+                            State::Trailers
+                        } else {
+                            State::ChunkData(size)
+                        };
+                    }
+                    Ok(Partial) => return false,
+                    Err(_) => {
+                        self.state = State::Stopped;
+                        return false;
+                    }
+                },
+                State::ChunkEnd => {
+                    if self.buffer.len() < 2 {
+                        return false;
+                    }
+                    message.body_size += 2;
+                    self.buffer.drain(..2);
+                    self.state = State::ChunkSize;
+                }
+                State::Trailers => {
+                    let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") else {
+                        return false;
+                    };
+                    message.body_size += end + 2;
+                    self.buffer.drain(..end + 2);
+                    if end == 0 {
+                        self.state = State::Head;
+                        return true;
+                    }
+                }
+                State::UntilClose => {
+                    let n = self.buffer.len();
+                    message.add_content(&self.buffer, max_body);
+                    message.body_size += n;
+                    self.buffer.clear();
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+struct Exchange {
+    started: Stamp,
+    request: Option<HttpMessage>,
+    /// The finished request.
+    request_entry: Value,
+    request_done: Stamp,
+    head_request: bool,
+}
+
+/// Records the HTTP exchanges of connection `i` for --har, pairing responses with
+/// requests in order so keep-alive and pipelining work.
+pub struct Har {
+    i: usize,
+    scheme: &'static str,
+    default_host: String,
+    max_body: usize,
+    requests: Parser,
+    responses: Parser,
+    /// The request whose body is being read.
+    request: Option<Exchange>,
+    /// Requests that have been sent, waiting for their responses.
+    pending: VecDeque<Exchange>,
+    /// The response being read, with when it started.
+    response: Option<(Stamp, HttpMessage)>,
+}
+
+impl Har {
+    pub fn new(opt: &Opt, i: usize) -> Self {
+        Self {
+            i,
+            scheme: if opt.ssl_server { "https" } else { "http" },
+            default_host: opt.host_header_value(),
+            max_body: opt.har_max_body,
+            requests: Parser::new(),
+            responses: Parser::new(),
+            request: None,
+            pending: VecDeque::new(),
+            response: None,
+        }
+    }
+
+    pub fn read(&mut self, direction: Direction, data: &[u8]) {
+        let stopped = |parser: &Parser| matches!(parser.state, State::Stopped);
+        if stopped(&self.requests) || stopped(&self.responses) {
+            return;
+        }
+        let result = match direction {
+            Direction::Incoming => self.read_requests(data),
+            Direction::Outgoing => self.read_responses(data),
+        };
+        if let Err(reason) = result {
+            info!("Not recording to --har any more: {reason}");
+            self.requests.state = State::Stopped;
+            self.responses.state = State::Stopped;
+        }
+    }
+
+    fn read_requests(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        self.requests.buffer.extend(data);
+        loop {
+            if let State::Head = self.requests.state {
+                if self.requests.buffer.is_empty() {
+                    return Ok(());
+                }
+                let started = *self.requests.started.get_or_insert_with(Stamp::now);
+                let mut headers = vec![httparse::EMPTY_HEADER; 128];
+                let mut request = httparse::Request::new(&mut headers);
+                let n = match request.parse(&self.requests.buffer) {
+                    Ok(Complete(n)) => n,
+                    Ok(Partial) if self.requests.buffer.len() < MAX_HEAD_SIZE => return Ok(()),
+                    _ => return Err("failed to parse HTTP request"),
+                };
+                let method = request.method.unwrap_or_default();
+                let path = request.path.unwrap_or_default();
+                let host = header(request.headers, "host").unwrap_or(self.default_host.clone());
+                let url = if path.starts_with('/') {
+                    format!("{}://{host}{path}", self.scheme)
+                } else {
+                    path.to_string()
+                };
+                let query: Vec<Value> = path
+                    .split_once('?')
+                    .map(|(_, query)| query)
+                    .into_iter()
+                    .flat_map(|query| query.split('&'))
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                        json!({"name": name, "value": value})
+                    })
+                    .collect();
+                let fields = json!({
+                    "method": method,
+                    "url": url,
+                    "httpVersion": format!("HTTP/1.{}", request.version.unwrap_or(1)),
+                    "queryString": query,
+                });
+                let message = HttpMessage::new(fields, request.headers, n);
+                self.requests.state = body_state(request.headers, State::Length(0));
+                self.request = Some(Exchange {
+                    started,
+                    request: Some(message),
+                    request_entry: Value::Null,
+                    request_done: started,
+                    head_request: method.eq_ignore_ascii_case("HEAD"),
+                });
+                self.requests.buffer.drain(..n);
+                self.requests.started = None;
+            }
+
+            let Some(exchange) = &mut self.request else {
+                return Ok(());
+            };
+            let message = exchange.request.as_mut().unwrap();
+            if !self.requests.read_body(message, self.max_body) {
+                return Ok(());
+            }
+            let mut exchange = self.request.take().unwrap();
+            exchange.request_entry = exchange.request.take().unwrap().into_request();
+            exchange.request_done = Stamp::now();
+            self.pending.push_back(exchange);
+        }
+    }
+
+    fn read_responses(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        self.responses.buffer.extend(data);
+        loop {
+            if let State::Head = self.responses.state {
+                if self.responses.buffer.is_empty() {
+                    return Ok(());
+                }
+                let started = *self.responses.started.get_or_insert_with(Stamp::now);
+                let mut headers = vec![httparse::EMPTY_HEADER; 128];
+                let mut response = httparse::Response::new(&mut headers);
+                let n = match response.parse(&self.responses.buffer) {
+                    Ok(Complete(n)) => n,
+                    Ok(Partial) if self.responses.buffer.len() < MAX_HEAD_SIZE => return Ok(()),
+                    _ => return Err("failed to parse HTTP response"),
+                };
+                let status = response.code.unwrap_or_default();
+                if (100..200).contains(&status) && status != 101 {
+                    self.responses.buffer.drain(..n);
+                    self.responses.started = None;
+                    continue;
+                }
+                let head_request = match self.pending.front().or(self.request.as_ref()) {
+                    Some(exchange) => exchange.head_request,
+                    None => return Err("HTTP response without a request"),
+                };
+                let fields = json!({
+                    "status": status,
+                    "statusText": response.reason.unwrap_or_default(),
+                    "httpVersion": format!("HTTP/1.{}", response.version.unwrap_or(1)),
+                    "redirectURL": header(response.headers, "location").unwrap_or_default(),
+                });
+                let message = HttpMessage::new(fields, response.headers, n);
+                self.responses.state = if head_request || matches!(status, 101 | 204 | 304) {
+                    State::Length(0)
+                } else {
+                    body_state(response.headers, State::UntilClose)
+                };
+                self.response = Some((started, message));
+                self.responses.buffer.drain(..n);
+                self.responses.started = None;
+                if status == 101 {
+                    self.finish_response(Stamp::now());
+                    return Err("protocol switched");
+                }
+            }
+
+            let Some((_, message)) = &mut self.response else {
+                return Ok(());
+            };
+            if !self.responses.read_body(message, self.max_body) {
+                return Ok(());
+            }
+            self.finish_response(Stamp::now());
+        }
+    }
+
+    /// Pairs the response that was just read with the oldest request and sends the entry.
+    fn finish_response(&mut self, done: Stamp) {
+        let Some((started, message)) = self.response.take() else {
+            return;
+        };
+        let exchange = match self.pending.pop_front() {
+            Some(exchange) => exchange,
+            // Responding before the request body was read, like a 413.
+            None => match self.request.take() {
+                Some(mut exchange) => {
+                    exchange.request_entry = exchange.request.take().unwrap().into_request();
+                    exchange.request_done = started;
+                    exchange
+                }
+                None => return,
+            },
+        };
+        self.send_entry(exchange, message.into_response(), started, done);
+    }
+
+    fn send_entry(
+        &self,
+        exchange: Exchange,
+        response: Value,
+        response_started: Stamp,
+        done: Stamp,
+    ) {
+        let send = exchange.started.millis_until(exchange.request_done);
+        let wait = exchange.request_done.millis_until(response_started);
+        let receive = response_started.millis_until(done);
+        let entry = json!({
+            "startedDateTime": rfc3339(exchange.started.time),
+            "time": send + wait + receive,
+            "request": exchange.request_entry,
+            "response": response,
+            "cache": {},
+            "timings": {"send": send, "wait": wait, "receive": receive},
+            "connection": self.i.to_string(),
+        });
+        if let Some(sender) = SENDER.get() {
+            let _ = sender.send(Message::Entry(entry));
+        }
+    }
+}
+
+impl Drop for Har {
+    /// Finishes a response delimited by the connection closing, and records requests
+    /// that never got a response with status 0.
+    fn drop(&mut self) {
+        let done = Stamp::now();
+        if let State::UntilClose = self.responses.state {
+            self.finish_response(done);
+        }
+        for exchange in std::mem::take(&mut self.pending) {
+            let response = json!({
+                "status": 0,
+                "statusText": "",
+                "httpVersion": "",
+                "cookies": [],
+                "headers": [],
+                "content": {"size": 0, "mimeType": ""},
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+            });
+            let request_done = exchange.request_done;
+            self.send_entry(exchange, response, request_done, request_done);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn har() -> Har {
+        Har {
+            i: 0,
+            scheme: "http",
+            default_host: "example.com".into(),
+            max_body: 4,
+            requests: Parser::new(),
+            responses: Parser::new(),
+            request: None,
+            pending: VecDeque::new(),
+            response: None,
+        }
+    }
+
+    #[test]
+    fn keep_alive_requests_are_paired_in_order() {
+        let mut har = har();
+        har.read_requests(b"GET /a?x=1 HTTP/1.1\r\nHost: h\r\n\r\nPOST /b HTTP/1.1\r\n")
+            .unwrap();
+        har.read_requests(b"Content-Length: 5\r\n\r\nhello")
+            .unwrap();
+        assert_eq!(har.pending.len(), 2);
+        assert_eq!(har.pending[0].request_entry["url"], "http://h/a?x=1");
+        assert_eq!(har.pending[0].request_entry["queryString"][0]["name"], "x");
+        assert_eq!(har.pending[1].request_entry["url"], "http://example.com/b");
+        assert_eq!(har.pending[1].request_entry["postData"]["text"], "hell");
+
+        har.read_responses(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .unwrap();
+        assert_eq!(har.pending.len(), 1);
+        har.read_responses(b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc")
+            .unwrap();
+        assert!(har.response.is_some());
+        har.read_responses(b"\r\n0\r\n\r\n").unwrap();
+        assert!(har.pending.is_empty() && har.response.is_none());
+        assert!(matches!(har.responses.state, State::Head));
+    }
+
+    #[test]
+    fn chunked_body_is_decoded() {
+        let mut har = har();
+        har.read_requests(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        har.read_responses(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n")
+            .unwrap();
+        let (_, message) = har.response.as_ref().unwrap();
+        assert_eq!(message.content, b"ab");
+        assert_eq!(message.content_size, 2);
+        assert_eq!(message.body_size, b"2\r\nab\r\n".len());
+    }
+
+    #[test]
+    fn non_http_is_rejected() {
+        let mut har = har();
+        assert!(har.read_requests(b"\x16\x03\x01\x02\x00").is_err());
+    }
+}
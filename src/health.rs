@@ -0,0 +1,121 @@
+use crate::balance::Balancer;
+use crate::save_certs::SavedCerts;
+use crate::ssl::{self, wrap_ssl_client};
+use crate::{connect_with_header, proxy_protocol, Opt, Target};
+use anyhow::{anyhow, bail, Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+/// Starts checking each target every --health-check-interval with --health-check,
+/// in tasks that stop when `tasks` is dropped.
+pub fn spawn(
+    opt: &Arc<Opt>,
+    balancer: &Arc<Balancer>,
+    ssl_connector: Option<Arc<ssl::Connector>>,
+    saved_certs: &SavedCerts,
+    tasks: &mut JoinSet<()>,
+) {
+    if !opt.health_check {
+        return;
+    }
+    for i in 0..opt.targets().len() {
+        tasks.spawn(watch(
+            opt.clone(),
+            i,
+            balancer.clone(),
+            ssl_connector.clone(),
+            saved_certs.clone(),
+        ));
+    }
+}
+
+/// Marks target `i` down after --health-check-fall failed checks in a row, and up
+/// again after --health-check-rise passed ones.
+async fn watch(
+    opt: Arc<Opt>,
+    i: usize,
+    balancer: Arc<Balancer>,
+    ssl_connector: Option<Arc<ssl::Connector>>,
+    saved_certs: SavedCerts,
+) {
+    let target = opt.targets()[i];
+    let timeout = Duration::from_secs(opt.health_check_timeout);
+    let mut interval = tokio::time::interval(Duration::from_secs(opt.health_check_interval));
+    let (mut up, mut passed, mut failed) = (true, 0, 0);
+    loop {
+        interval.tick().await;
+        let checked = check(&opt, target, ssl_connector.as_deref(), &saved_certs);
+        let result = match tokio::time::timeout(timeout, checked).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("timed out after {}s", opt.health_check_timeout)),
+        };
+        match result {
+            Ok(()) => {
+                (passed, failed) = (passed + 1, 0);
+                if !up && passed >= opt.health_check_rise {
+                    up = true;
+                    balancer.set_healthy(i, true);
+                    info!(parent: None, "Upstream {target} is up, it passed {passed} health checks");
+                }
+            }
+            Err(e) => {
+                (passed, failed) = (0, failed + 1);
+                if up && failed >= opt.health_check_fall {
+                    up = false;
+                    balancer.set_healthy(i, false);
+                    warn!(
+                        parent: None,
+                        "Upstream {target} is down, it failed {failed} health checks: {e:#}"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Connects to `target`, sending a PROXY protocol header for a connection of the
+/// proxy's own with --send-proxy, does the TLS handshake with --ssl, and with
+/// --health-check-path requests that path expecting a 2xx status.
+async fn check(
+    opt: &Opt,
+    target: Target<'_>,
+    ssl_connector: Option<&ssl::Connector>,
+    saved_certs: &SavedCerts,
+) -> Result<()> {
+    let header = |_| proxy_protocol::local_header(opt);
+    let (mut stream, _) = connect_with_header(target, Some(opt), header).await?;
+    if let Some(ssl_connector) = ssl_connector.filter(|_| opt.starttls.is_none()) {
+        (stream, _) = wrap_ssl_client(opt, stream, ssl_connector, saved_certs).await?;
+    }
+    let Some(path) = &opt.health_check_path else {
+        return Ok(());
+    };
+
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        opt.host_header_value()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = vec![];
+    let mut buf = [0; 1024];
+    while !response.contains(&b'\n') && response.len() < 4096 {
+        match stream.read(&mut buf).await? {
+            0 => break,
+            n => response.extend(&buf[..n]),
+        }
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    let status: u16 = status_line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.get(2..5))
+        .and_then(|status| status.parse().ok())
+        .with_context(|| format!("Invalid response to {path}: {status_line:?}"))?;
+    if !(200..300).contains(&status) {
+        bail!("{path} answered {status_line}");
+    }
+    Ok(())
+}
@@ -0,0 +1,1808 @@
+use crate::access_log;
+use crate::data_log::DataLog;
+use crate::decode::Decoder;
+use crate::logging::{show_data, Direction};
+use crate::stats::Connection;
+use crate::{color, forward, latency, websocket, AsyncStream, Opt, Stub, Target};
+use anyhow::Result;
+use httparse::Error::TooManyHeaders;
+use httparse::Status::{Complete, Partial};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+struct RequestLine<'a> {
+    method: &'a str,
+    path: &'a str,
+    version: u8,
+}
+
+impl<'a> RequestLine<'a> {
+    fn new<'b>(request: httparse::Request<'b, 'a>) -> Self {
+        Self {
+            method: request.method.unwrap(),
+            path: request.path.unwrap(),
+            version: request.version.unwrap(),
+        }
+    }
+}
+
+struct RequestHeaders<'a> {
+    request_line: RequestLine<'a>,
+    headers: Vec<httparse::Header<'a>>,
+}
+
+impl<'a> RequestHeaders<'a> {
+    fn new(request_line: RequestLine<'a>, mut headers: Vec<httparse::Header<'a>>) -> Self {
+        while headers.last().map(|h| h.name.is_empty()).unwrap_or(false) {
+            headers.pop();
+        }
+
+        Self {
+            request_line,
+            headers,
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&'a [u8]> {
+        last_header(&self.headers, name)
+    }
+
+    /// How the body following the header is delimited.
+    fn body(&self) -> Body {
+        if self.request_line.method.eq_ignore_ascii_case("CONNECT")
+            || self.header("upgrade").is_some()
+        {
+            return Body::Opaque;
+        }
+        match declared_body(&self.headers) {
+            // A request can't end when the client stops sending, as the client still
+            // wants the response.
+            Some(Body::UntilClose) => Body::Opaque,
+            Some(body) => body,
+            None => Body::Length(0),
+        }
+    }
+}
+
+struct StatusLine<'a> {
+    version: u8,
+    code: u16,
+    reason: &'a str,
+}
+
+struct ResponseHeaders<'a> {
+    status_line: StatusLine<'a>,
+    headers: Vec<httparse::Header<'a>>,
+}
+
+impl<'a> ResponseHeaders<'a> {
+    /// Whether more responses to the same request follow this one.
+    fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status_line.code) && self.status_line.code != 101
+    }
+
+    /// How the body following the header of a response to `request` is
+    /// delimited.
+    fn body(&self, request: &Exchange) -> Body {
+        let code = self.status_line.code;
+        if code == 101 || request.method.eq_ignore_ascii_case("CONNECT") && code / 100 == 2 {
+            return Body::Opaque;
+        }
+        if self.is_interim()
+            || code == 204
+            || code == 304
+            || request.method.eq_ignore_ascii_case("HEAD")
+        {
+            return Body::Length(0);
+        }
+        declared_body(&self.headers).unwrap_or(Body::UntilClose)
+    }
+}
+
+/// The value of the last `name` header.
+fn last_header<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .rfind(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value)
+}
+
+/// The body delimited by Transfer-Encoding or Content-Length, if either is sent.
+/// A body in another transfer coding lasts until the sender closes.
+fn declared_body(headers: &[httparse::Header]) -> Option<Body> {
+    if let Some(encoding) = last_header(headers, "transfer-encoding") {
+        let chunked = String::from_utf8_lossy(encoding)
+            .rsplit(',')
+            .next()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        return Some(if chunked {
+            Body::Chunked
+        } else {
+            Body::UntilClose
+        });
+    }
+    let length = last_header(headers, "content-length")?;
+    Some(
+        match std::str::from_utf8(length).map(|l| l.trim().parse()) {
+            Ok(Ok(length)) => Body::Length(length),
+            _ => Body::Opaque,
+        },
+    )
+}
+
+enum Body {
+    Length(usize),
+    Chunked,
+    /// Lasts until the sender stops sending, like a response without a length.
+    UntilClose,
+    /// Can't be told apart from what follows it, like after an upgrade.
+    Opaque,
+}
+
+/// More headers than this make a request be forwarded without rewriting it.
+const MAX_HEADERS: usize = 1024;
+
+fn parse_http_request_headers(
+    buffer: &[u8],
+) -> Result<Option<(usize, RequestHeaders<'_>)>, httparse::Error> {
+    let mut max_headers = 16;
+    loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(buffer) {
+            Ok(Complete(n)) => {
+                let request_line = RequestLine::new(request);
+                let request_headers = RequestHeaders::new(request_line, headers);
+                return Ok(Some((n, request_headers)));
+            }
+            Ok(Partial) => return Ok(None),
+            Err(TooManyHeaders) if max_headers < MAX_HEADERS => {
+                max_headers = (max_headers * 2).min(MAX_HEADERS);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn parse_http_response_headers(
+    buffer: &[u8],
+) -> Result<Option<(usize, ResponseHeaders<'_>)>, httparse::Error> {
+    let mut max_headers = 16;
+    loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(buffer) {
+            Ok(Complete(n)) => {
+                let status_line = StatusLine {
+                    version: response.version.unwrap(),
+                    code: response.code.unwrap(),
+                    reason: response.reason.unwrap(),
+                };
+                let headers = headers
+                    .into_iter()
+                    .filter(|header| !header.name.is_empty())
+                    .collect();
+                return Ok(Some((
+                    n,
+                    ResponseHeaders {
+                        status_line,
+                        headers,
+                    },
+                )));
+            }
+            Ok(Partial) => return Ok(None),
+            Err(TooManyHeaders) if max_headers < MAX_HEADERS => {
+                max_headers = (max_headers * 2).min(MAX_HEADERS);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Replaces the authority of `url` with `to` if it is `from`, keeping the scheme,
+/// any user info and everything after the authority.
+fn replace_authority(url: &str, from: &str, to: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    let (user_info, host) = match authority.rsplit_once('@') {
+        Some((user_info, host)) => (&authority[..=user_info.len()], host),
+        None => ("", authority),
+    };
+    host.eq_ignore_ascii_case(from)
+        .then(|| format!("{scheme}://{user_info}{to}{path}"))
+}
+
+/// `path` with the longest matching --rewrite-path prefix replaced, keeping the
+/// query.
+fn rewrite_path(opt: &Opt, path: &str) -> Option<String> {
+    let end = path.find('?').unwrap_or(path.len());
+    let (from, to) = opt
+        .rewrite_path
+        .iter()
+        .filter(|(from, _)| path[..end].starts_with(from.as_str()))
+        .max_by_key(|(from, _)| from.len())?;
+    Some(format!("{to}{}", &path[from.len()..]))
+}
+
+/// The headers --add-forwarded sets, appending to those the client sent.
+fn forwarded_headers(
+    opt: &Opt,
+    client_ip: Option<IpAddr>,
+    headers: &RequestHeaders,
+) -> Vec<(&'static str, String)> {
+    let append = |name, value: String| match headers.header(name) {
+        Some(sent) => format!("{}, {value}", String::from_utf8_lossy(sent)),
+        None => value,
+    };
+    let proto = if opt.ssl_server { "https" } else { "http" };
+    let node = match client_ip {
+        Some(IpAddr::V6(ip)) => format!("\"[{ip}]\""),
+        Some(ip) => ip.to_string(),
+        None => "unknown".to_string(),
+    };
+
+    let mut forwarded = vec![];
+    if let Some(ip) = client_ip {
+        forwarded.push(("X-Forwarded-For", append("x-forwarded-for", ip.to_string())));
+    }
+    forwarded.push(("X-Forwarded-Proto", proto.to_string()));
+    forwarded.push((
+        "Forwarded",
+        append("forwarded", format!("for={node};proto={proto}")),
+    ));
+    forwarded
+}
+
+/// Rewrites the Host header of a request, with --rewrite-origin the Origin and
+/// Referer headers naming the same host, adds the --add-forwarded and
+/// --set-header headers, drops the --remove-header ones and rewrites the path
+/// with --rewrite-path.
+/// Returns the header to send instead if anything changed.
+fn rewrite_headers(
+    opt: &Opt,
+    connection: &Connection,
+    headers: RequestHeaders,
+) -> Result<Option<Vec<u8>>> {
+    let host = opt.host_header_value();
+    let urls: Vec<_> = match headers.header("host") {
+        Some(original) if opt.rewrite_origin && opt.rewrites_host() && !host.is_empty() => {
+            let original = String::from_utf8_lossy(original);
+            headers
+                .headers
+                .iter()
+                .map(|header| {
+                    let name = header.name.to_ascii_lowercase();
+                    if name != "origin" && name != "referer" {
+                        return None;
+                    }
+                    let url = std::str::from_utf8(header.value).ok()?;
+                    replace_authority(url, &original, &host)
+                })
+                .collect()
+        }
+        _ => vec![],
+    };
+    let forwarded = if opt.add_forwarded {
+        forwarded_headers(opt, connection.client_ip(), &headers)
+    } else {
+        vec![]
+    };
+    // Borrows `host`, `urls` and `forwarded` from here on.
+    let mut headers = headers;
+    let mut headers_changed = false;
+    let path = rewrite_path(opt, headers.request_line.path);
+    if let Some(path) = &path {
+        info!(
+            event = "http_rewrite",
+            from = headers.request_line.path,
+            to = path.as_str()
+        );
+        headers_changed = true;
+        connection.http_rewrite();
+    }
+    let removed = String::new();
+    let remove = |name: &str| {
+        opt.remove_header
+            .iter()
+            .any(|removed| removed.eq_ignore_ascii_case(name))
+    };
+    for (i, header) in headers.headers.iter_mut().enumerate() {
+        let to = if remove(header.name) {
+            &removed
+        } else if header.name.eq_ignore_ascii_case("host") && opt.rewrites_host() {
+            &host
+        } else if let Some(Some(url)) = urls.get(i) {
+            url
+        } else {
+            continue;
+        };
+        info!(
+            event = "http_rewrite",
+            header = header.name.to_ascii_lowercase(),
+            from = %String::from_utf8_lossy(header.value),
+            to = (!to.is_empty()).then_some(to.as_str())
+        );
+        header.value = to.as_bytes();
+        headers_changed = true;
+        connection.http_rewrite();
+    }
+    headers.headers.retain(|header| !remove(header.name));
+    let authorized = headers
+        .headers
+        .iter()
+        .any(|header| header.name.eq_ignore_ascii_case("authorization"));
+    let basic_auth = opt
+        .basic_auth
+        .as_ref()
+        .filter(|_| !(authorized && opt.basic_auth_keep_existing));
+    let set = forwarded
+        .iter()
+        .map(|(name, value)| (*name, value))
+        .chain(basic_auth.map(|value| ("Authorization", value)))
+        .chain(
+            opt.set_header
+                .iter()
+                .map(|(name, value)| (name.as_str(), value)),
+        );
+    for (name, value) in set {
+        if basic_auth.is_some_and(|basic_auth| std::ptr::eq(value, basic_auth)) {
+            info!("Setting Authorization header for --basic-auth");
+        } else {
+            info!("Setting {name} header to {value}");
+        }
+        // The first copy the client sent takes the value, and any others are dropped.
+        let mut replaced = false;
+        headers.headers.retain_mut(|header| {
+            if !header.name.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if replaced {
+                return false;
+            }
+            header.value = value.as_bytes();
+            replaced = true;
+            true
+        });
+        if !replaced {
+            headers.headers.push(httparse::Header {
+                name,
+                value: value.as_bytes(),
+            });
+        }
+        headers_changed = true;
+        connection.http_rewrite();
+    }
+    headers
+        .headers
+        .retain(|header| !(host.is_empty() && header.name.eq_ignore_ascii_case("host")));
+    if !headers_changed {
+        return Ok(None);
+    }
+
+    let RequestLine {
+        method,
+        path: sent_path,
+        version,
+    } = headers.request_line;
+    let path = path.as_deref().unwrap_or(sent_path);
+    let request_line = format!("{method} {path} HTTP/1.{version}");
+    Ok(Some(serialize(&request_line, &headers.headers)?))
+}
+
+/// The host and port `authority` names, with `default_port` if it has none.
+fn host_and_port(authority: &str, default_port: u16) -> Option<(&str, u16)> {
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => Some((host, port.parse().ok()?)),
+        _ => Some((authority, default_port)),
+    }
+}
+
+/// The URL in a Location header pointing at the proxy instead if it points at
+/// the upstream.
+fn rewrite_location(opt: &Opt, location: &str, proxy: &str) -> Option<String> {
+    let (scheme, rest) = location.split_once("://")?;
+    // A Location without a port means the default one of its own scheme, which
+    // need not be the one the upstream speaks.
+    let location_port = match scheme.to_ascii_lowercase().as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let (location_host, location_port) = host_and_port(host, location_port)?;
+    let upstream_port = if opt.ssl { 443 } else { 80 };
+    let mut upstream = vec![opt.host_header_value()];
+    for target in opt.targets() {
+        if let Target::Tcp(..) = target {
+            upstream.push(target.to_string());
+        }
+    }
+    let points_at_upstream = upstream
+        .iter()
+        .filter(|authority| !authority.is_empty())
+        .filter_map(|authority| host_and_port(authority, upstream_port))
+        .any(|(host, port)| host.eq_ignore_ascii_case(location_host) && port == location_port);
+    if !points_at_upstream {
+        return None;
+    }
+    let rewritten = replace_authority(location, host, proxy)?;
+    let proto = if opt.ssl_server { "https" } else { "http" };
+    Some(format!("{proto}{}", &rewritten[scheme.len()..]))
+}
+
+/// The Set-Cookie value with its Domain attribute set to `domain`, or removed if
+/// that is empty, keeping the other attributes as they are.
+fn rewrite_cookie_domain(cookie: &str, domain: &str) -> Option<String> {
+    let mut changed = false;
+    let parts: Vec<_> = cookie
+        .split(';')
+        .enumerate()
+        .filter_map(|(i, part)| {
+            let (name, _) = part.split_once('=').unwrap_or((part, ""));
+            if i == 0 || !name.trim().eq_ignore_ascii_case("domain") {
+                return Some(part.to_string());
+            }
+            changed = true;
+            (!domain.is_empty()).then(|| format!("{name}={domain}"))
+        })
+        .collect();
+    changed.then(|| parts.join(";"))
+}
+
+/// The Content-Security-Policy without its upgrade-insecure-requests directives,
+/// if it has any.
+fn strip_upgrade_insecure_requests(policy: &str) -> Option<String> {
+    let is_upgrade = |directive: &&str| {
+        directive
+            .split_whitespace()
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case("upgrade-insecure-requests"))
+    };
+    if !policy
+        .split([',', ';'])
+        .any(|directive| is_upgrade(&directive))
+    {
+        return None;
+    }
+    let policies: Vec<_> = policy
+        .split(',')
+        .map(|policy| {
+            let directives: Vec<_> = policy.split(';').filter(|d| !is_upgrade(d)).collect();
+            directives.join(";")
+        })
+        .filter(|policy| !policy.trim().is_empty())
+        .collect();
+    Some(policies.join(",").trim().to_string())
+}
+
+/// Rewrites the Location header of a response to `request` with
+/// --rewrite-location, the Domain of its cookies and with --strip-hsts what
+/// makes browsers switch to HTTPS. Returns the header to send instead if
+/// anything changed.
+fn rewrite_response_headers(
+    opt: &Opt,
+    connection: &Connection,
+    request: &Exchange,
+    headers: ResponseHeaders,
+) -> Result<Option<Vec<u8>>> {
+    let proxy = match &request.host {
+        _ if !opt.rewrite_location => None,
+        Some(host) => Some(host.clone()),
+        None => opt.listen_authority(),
+    };
+    let location = headers
+        .headers
+        .iter()
+        .rposition(|header| header.name.eq_ignore_ascii_case("location"));
+    let rewritten: Vec<_> = headers
+        .headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| {
+            let value = std::str::from_utf8(header.value).ok()?;
+            if Some(i) == location {
+                rewrite_location(opt, value, proxy.as_deref()?)
+            } else if header.name.eq_ignore_ascii_case("set-cookie") {
+                rewrite_cookie_domain(value, opt.cookie_domain()?)
+            } else if !opt.strip_hsts {
+                None
+            } else if header
+                .name
+                .eq_ignore_ascii_case("strict-transport-security")
+            {
+                Some(String::new())
+            } else if header.name.eq_ignore_ascii_case("content-security-policy") {
+                strip_upgrade_insecure_requests(value)
+            } else {
+                None
+            }
+        })
+        .collect();
+    // Borrows `rewritten` from here on.
+    let mut headers = headers;
+    let mut headers_changed = false;
+    for (header, to) in headers.headers.iter_mut().zip(&rewritten) {
+        let Some(to) = to else {
+            continue;
+        };
+        info!(
+            event = "http_rewrite",
+            header = header.name.to_ascii_lowercase(),
+            from = %String::from_utf8_lossy(header.value),
+            to = (!to.is_empty()).then_some(to.as_str())
+        );
+        header.value = to.as_bytes();
+        headers_changed = true;
+        connection.http_rewrite();
+    }
+    if !headers_changed {
+        return Ok(None);
+    }
+    let removed = rewritten.iter().map(|to| to.as_deref() == Some(""));
+    let kept = headers.headers.iter().zip(removed);
+    headers.headers = kept
+        .filter(|(_, removed)| !removed)
+        .map(|(h, _)| *h)
+        .collect();
+
+    let StatusLine {
+        version,
+        code,
+        reason,
+    } = headers.status_line;
+    let status_line = format!("HTTP/1.{version} {code} {reason}");
+    Ok(Some(serialize(&status_line, &headers.headers)?))
+}
+
+fn serialize(start_line: &str, headers: &[httparse::Header]) -> Result<Vec<u8>> {
+    let mut headers_buf = vec![];
+    writeln!(&mut headers_buf, "{start_line}\r")?;
+    for header in headers {
+        write!(&mut headers_buf, "{}: ", header.name)?;
+        headers_buf.extend(header.value);
+        writeln!(&mut headers_buf, "\r")?;
+    }
+    writeln!(&mut headers_buf, "\r")?;
+    Ok(headers_buf)
+}
+
+/// What to do after forwarding a message.
+enum Next {
+    Message,
+    /// Forward the rest of the connection unchanged.
+    Raw,
+    /// Forward the rest of the connection unchanged, logging its WebSocket frames.
+    WebSocket,
+    /// The sender stopped sending.
+    Closed,
+}
+
+/// What the responses to a request depend on.
+#[derive(Clone)]
+struct Exchange {
+    method: String,
+    /// The request line and headers as the client sent them.
+    request_line: String,
+    host: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /// Whether the client asked to upgrade to WebSocket.
+    websocket: bool,
+    /// The response to send instead of the upstream's, with --block and --stub.
+    answer: Option<Answer>,
+    /// When the request's header was forwarded.
+    sent: Instant,
+    time: OffsetDateTime,
+}
+
+/// What both directions of a connection share.
+#[derive(Default)]
+struct Shared {
+    /// Requests forwarded to the upstream whose final response wasn't read yet.
+    exchanges: Mutex<VecDeque<Exchange>>,
+    /// Set once the upstream accepted an upgrade to WebSocket.
+    websocket: AtomicBool,
+    /// Notified when a request the proxy answers was queued.
+    answered: Notify,
+}
+
+/// A response the proxy sends itself instead of forwarding the request.
+#[derive(Clone)]
+struct Answer {
+    status: u16,
+    response: Vec<u8>,
+    body_size: usize,
+    /// Whether the connection is closed after it, as the client asked.
+    close: bool,
+}
+
+impl Answer {
+    fn new(status: u16, headers: &[(&str, &str)], body: &[u8], close: bool) -> Self {
+        let mut response = format!("HTTP/1.1 {status} {}\r\n", reason_phrase(status));
+        for (name, value) in headers {
+            if !name.eq_ignore_ascii_case("content-length") {
+                response += &format!("{name}: {value}\r\n");
+            }
+        }
+        if status != 204 && status != 304 {
+            response += &format!("Content-Length: {}\r\n", body.len());
+        }
+        if close {
+            response += "Connection: close\r\n";
+        }
+        response += "\r\n";
+        let mut response = response.into_bytes();
+        response.extend(body);
+        Self {
+            status,
+            response,
+            body_size: body.len(),
+            close,
+        }
+    }
+
+    /// An error the proxy answers with, explaining it with `message`.
+    fn error(status: u16, message: &str, close: bool) -> Self {
+        let body = format!("{status} {}\n\n{message}\n", reason_phrase(status));
+        let headers = [("Content-Type", "text/plain; charset=utf-8")];
+        Self::new(status, &headers, body.as_bytes(), close)
+    }
+
+    /// The answer to --stub `stub`.
+    fn stub(stub: &Stub, close: bool) -> Self {
+        let json = serde_json::from_str::<serde_json::Value>(&stub.body)
+            .is_ok_and(|body| body.is_object() || body.is_array());
+        let content_type = if json {
+            "application/json"
+        } else {
+            "text/plain; charset=utf-8"
+        };
+        let mut headers = vec![];
+        if !stub
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        {
+            headers.push(("Content-Type", content_type));
+        }
+        headers.extend(
+            stub.headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        Self::new(stub.status, &headers, stub.body.as_bytes(), close)
+    }
+}
+
+/// Whether the client asked to close the connection after the response to a
+/// request with `headers`.
+fn closes(headers: &RequestHeaders) -> bool {
+    let connection = |option: &str| {
+        headers
+            .headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("connection"))
+            .flat_map(|header| header.value.split(|&b| b == b','))
+            .any(|value| {
+                String::from_utf8_lossy(value)
+                    .trim()
+                    .eq_ignore_ascii_case(option)
+            })
+    };
+    match headers.request_line.version {
+        0 => !connection("keep-alive"),
+        _ => connection("close"),
+    }
+}
+
+/// Whether an Upgrade header in `headers` names WebSocket.
+fn upgrades_to_websocket(headers: &[httparse::Header]) -> bool {
+    last_header(headers, "upgrade").is_some_and(|upgrade| {
+        String::from_utf8_lossy(upgrade)
+            .split(',')
+            .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+    })
+}
+
+/// One direction of a connection whose messages are parsed.
+struct Messages<'a, 'b> {
+    opt: &'a Opt,
+    connection: &'a Connection,
+    data_log: &'a Mutex<&'b mut DataLog>,
+    shared: &'a Shared,
+    direction: Direction,
+    from: ReadHalf<AsyncStream>,
+    to: WriteHalf<AsyncStream>,
+    /// Read but not forwarded yet.
+    buf: Vec<u8>,
+    /// How many bytes of the current message's body were forwarded, not counting
+    /// the framing of chunks.
+    body_bytes: u64,
+    /// Shows the body being forwarded decompressed.
+    decoder: Option<Decoder>,
+    /// Set while reading a request the proxy answers, which isn't forwarded.
+    discard: bool,
+}
+
+impl Messages<'_, '_> {
+    /// Reads more, returning how many bytes were read.
+    async fn read_more(&mut self) -> Result<usize> {
+        self.buf.reserve(self.opt.buffer_size);
+        let n = self.from.read_buf(&mut self.buf).await?;
+        let data = &self.buf[self.buf.len() - n..];
+        match self.direction {
+            Direction::Incoming => self.data_log.lock().unwrap().incoming(self.opt, data),
+            Direction::Outgoing => self.data_log.lock().unwrap().outgoing(self.opt, data),
+        }
+        self.connection.forwarded(self.direction, n);
+        if n > 0 {
+            latency::chunk(self.opt, self.connection).await;
+        }
+        Ok(n)
+    }
+
+    /// Forwards the first `n` buffered bytes.
+    async fn send(&mut self, n: usize) -> Result<()> {
+        if !self.discard {
+            self.to.write_all(&self.buf[..n]).await?;
+        }
+        self.buf.drain(..n);
+        Ok(())
+    }
+
+    /// Reads until at least `n` bytes are buffered. Returns false if the sender
+    /// stopped sending before that.
+    async fn fill(&mut self, n: usize) -> Result<bool> {
+        while self.buf.len() < n {
+            if self.read_more().await? == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Forwards `n` bytes of a body, reading them first where needed. Returns false
+    /// if the sender stopped sending before that.
+    async fn pass(&mut self, mut n: usize) -> Result<bool> {
+        while n > 0 {
+            if self.buf.is_empty() && self.read_more().await? == 0 {
+                return Ok(false);
+            }
+            let part = n.min(self.buf.len());
+            self.decode(part);
+            self.send(part).await?;
+            self.body_bytes += part as u64;
+            n -= part;
+        }
+        Ok(true)
+    }
+
+    /// Shows the first `n` buffered bytes of a body decompressed, with a decoder.
+    fn decode(&mut self, n: usize) {
+        let Some(decoder) = &mut self.decoder else {
+            return;
+        };
+        let offset = decoder.offset;
+        match decoder.write(&self.buf[..n]) {
+            Ok(decoded) => self.data_log.lock().unwrap().decoded(
+                self.opt,
+                self.direction,
+                decoder.encoding,
+                offset,
+                &decoded,
+            ),
+            Err(e) => {
+                warn!(
+                    "Failed to decode {} body ({e}), not decoding it",
+                    decoder.encoding
+                );
+                self.decoder = None;
+            }
+        }
+    }
+
+    /// Shows the rest of a decompressed body once it was forwarded.
+    fn finish_decoding(&mut self) {
+        let Some(decoder) = self.decoder.take() else {
+            return;
+        };
+        let (encoding, offset) = (decoder.encoding, decoder.offset);
+        match decoder.finish() {
+            Ok(decoded) => self.data_log.lock().unwrap().decoded(
+                self.opt,
+                self.direction,
+                encoding,
+                offset,
+                &decoded,
+            ),
+            Err(e) => warn!("Failed to decode {encoding} body ({e})"),
+        }
+    }
+
+    async fn chunked_body(&mut self) -> Result<Next> {
+        loop {
+            let (line_size, chunk_size) = match httparse::parse_chunk_size(&self.buf) {
+                Ok(Complete((line_size, chunk_size))) => (line_size, chunk_size),
+                Ok(Partial) => {
+                    if self.read_more().await? == 0 {
+                        return Ok(Next::Closed);
+                    }
+                    continue;
+                }
+                Err(_) => {
+                    warn!("Invalid HTTP chunk size, not modifying data any more");
+                    return Ok(Next::Raw);
+                }
+            };
+            self.send(line_size).await?;
+            if chunk_size == 0 {
+                break;
+            }
+            let Ok(chunk_size) = usize::try_from(chunk_size) else {
+                return Ok(Next::Raw);
+            };
+            if !self.pass(chunk_size).await? || !self.fill(2).await? {
+                return Ok(Next::Closed);
+            }
+            self.send(2).await?;
+        }
+
+        // Trailer fields, up to an empty line.
+        loop {
+            match self.buf.windows(2).position(|end| end == b"\r\n") {
+                Some(end) => {
+                    self.send(end + 2).await?;
+                    if end == 0 {
+                        return Ok(Next::Message);
+                    }
+                }
+                None if self.buf.len() >= self.opt.max_header_size => return Ok(Next::Raw),
+                None => {
+                    if self.read_more().await? == 0 {
+                        return Ok(Next::Closed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads until `parse` finds a complete header, returning its size, or what to
+    /// do if there is none.
+    async fn read_header(
+        &mut self,
+        parse: impl Fn(&[u8]) -> Result<Option<usize>, httparse::Error>,
+    ) -> Result<Result<usize, Next>> {
+        loop {
+            match parse(&self.buf) {
+                Ok(Some(header_size)) => {
+                    let arrow = match self.direction {
+                        Direction::Incoming => color::incoming(),
+                        Direction::Outgoing => color::outgoing(),
+                    };
+                    info!("{arrow} HTTP header read");
+                    return Ok(Ok(header_size));
+                }
+                Ok(None) if self.buf.len() >= self.opt.max_header_size => {
+                    info!(
+                        "HTTP header is longer than --max-header-size {}, not modifying data",
+                        self.opt.max_header_size
+                    );
+                    return Ok(Err(Next::Raw));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    info!("Error reading HTTP header ({e}), not modifying data");
+                    return Ok(Err(Next::Raw));
+                }
+            }
+            if self.read_more().await? == 0 {
+                if !self.buf.is_empty() {
+                    let sender = match self.direction {
+                        Direction::Incoming => "Client",
+                        Direction::Outgoing => "Upstream",
+                    };
+                    info!(
+                        "{sender} closed before sending complete HTTP headers, not modifying data"
+                    );
+                    self.send(self.buf.len()).await?;
+                }
+                return Ok(Err(Next::Closed));
+            }
+        }
+    }
+
+    /// Forwards a header of `header_size` bytes, or `rewritten` instead, and the
+    /// body following it.
+    async fn message(
+        &mut self,
+        header_size: usize,
+        rewritten: Option<Vec<u8>>,
+        body: Body,
+    ) -> Result<Next> {
+        self.body_bytes = 0;
+        match rewritten {
+            Some(headers) => {
+                self.to.write_all(&headers).await?;
+                self.buf.drain(..header_size);
+            }
+            None => self.send(header_size).await?,
+        }
+
+        match body {
+            Body::Length(length) => Ok(if self.pass(length).await? {
+                Next::Message
+            } else {
+                Next::Closed
+            }),
+            Body::Chunked => self.chunked_body().await,
+            Body::UntilClose => {
+                self.pass(usize::MAX).await?;
+                Ok(Next::Closed)
+            }
+            Body::Opaque => Ok(Next::Raw),
+        }
+    }
+
+    /// Forwards the next request, with its header rewritten.
+    async fn request(&mut self) -> Result<Next> {
+        let parse = |buf: &[u8]| parse_http_request_headers(buf).map(|h| h.map(|(n, _)| n));
+        let header_size = match self.read_header(parse).await? {
+            Ok(header_size) => header_size,
+            Err(next) => return Ok(next),
+        };
+        let Ok(Some((_, headers))) = parse_http_request_headers(&self.buf) else {
+            unreachable!("the header was parsed before");
+        };
+        let RequestLine {
+            method,
+            path,
+            version,
+        } = headers.request_line;
+        let header = |name| {
+            let value = headers.header(name)?;
+            Some(String::from_utf8_lossy(value).into_owned())
+        };
+        let websocket = upgrades_to_websocket(&headers.headers);
+        let target = format!("{method} {path}");
+        let close = closes(&headers);
+        let stubbed = |stub: &&Stub| stub.matches(path);
+        let answer = if let Some(block) = self.opt.block.iter().find(|b| b.is_match(&target)) {
+            info!("Blocking {target}, which matches --block {block}");
+            Some(Answer::error(
+                self.opt.block_status,
+                "Blocked by the proxy",
+                close,
+            ))
+        } else if let Some(stub) = self.opt.stub.iter().find(stubbed) {
+            info!("Answering {target} with the --stub for {}", stub.path);
+            Some(Answer::stub(stub, close))
+        } else {
+            None
+        };
+        let answered = answer.is_some();
+        self.shared.exchanges.lock().unwrap().push_back(Exchange {
+            method: method.to_string(),
+            request_line: format!("{method} {path} HTTP/1.{version}"),
+            host: header("host"),
+            referer: header("referer"),
+            user_agent: header("user-agent"),
+            websocket,
+            answer,
+            sent: Instant::now(),
+            time: OffsetDateTime::now_utc(),
+        });
+        let body = headers.body();
+        if answered {
+            self.shared.answered.notify_one();
+            self.discard = true;
+            let next = self.message(header_size, None, body).await?;
+            self.discard = false;
+            return Ok(match next {
+                Next::Message | Next::Closed => next,
+                _ => {
+                    info!("Can't tell where the answered request ends, closing the connection");
+                    Next::Closed
+                }
+            });
+        }
+        let rewritten = if self.opt.rewrite_http() {
+            rewrite_headers(self.opt, self.connection, headers)?
+        } else {
+            None
+        };
+        let next = self.message(header_size, rewritten, body).await?;
+        Ok(match next {
+            Next::Raw if websocket => Next::WebSocket,
+            next => next,
+        })
+    }
+
+    /// Forwards the next response, with its Location and Set-Cookie headers
+    /// rewritten, and logs its status once it was forwarded.
+    async fn response(&mut self) -> Result<Next> {
+        if !self.answer_requests().await? {
+            return Ok(Next::Closed);
+        }
+        let parse = |buf: &[u8]| parse_http_response_headers(buf).map(|h| h.map(|(n, _)| n));
+        let header_size = match self.read_header(parse).await? {
+            Ok(header_size) => header_size,
+            Err(next) => return Ok(next),
+        };
+        let Ok(Some((_, headers))) = parse_http_response_headers(&self.buf) else {
+            unreachable!("the header was parsed before");
+        };
+        let request = if headers.is_interim() {
+            self.shared.exchanges.lock().unwrap().front().cloned()
+        } else {
+            self.shared.exchanges.lock().unwrap().pop_front()
+        };
+        let Some(request) = request else {
+            info!("HTTP response without a request, not modifying data");
+            return Ok(Next::Raw);
+        };
+        let latency = request.sent.elapsed();
+        let final_response = !headers.is_interim();
+        let status_line = &headers.status_line;
+        let version = format!("HTTP/1.{}", status_line.version);
+        let (status, reason) = (status_line.code, status_line.reason.to_string());
+        let body = headers.body(&request);
+        let websocket =
+            status == 101 && request.websocket && upgrades_to_websocket(&headers.headers);
+        if websocket {
+            info!("Upgraded to WebSocket");
+            self.shared.websocket.store(true, Relaxed);
+        }
+        let encoding = last_header(&headers.headers, "content-encoding")
+            .filter(|_| matches!(body, Body::Length(1..) | Body::Chunked | Body::UntilClose));
+        if let Some(encoding) = encoding.filter(|_| self.opt.decode_bodies && show_data()) {
+            let encoding = String::from_utf8_lossy(encoding);
+            self.decoder = Decoder::new(&encoding);
+            if self.decoder.is_none() {
+                info!("Can't decode {encoding} bodies, showing the body as it is");
+            }
+        }
+        let rewritten = rewrite_response_headers(self.opt, self.connection, &request, headers)?;
+        let next = self.message(header_size, rewritten, body).await?;
+        match next {
+            Next::Message | Next::Closed => self.finish_decoding(),
+            _ => self.decoder = None,
+        }
+        if final_response {
+            // A body forwarded raw lasts until the upstream closes.
+            let bytes = (!matches!(next, Next::Raw)).then_some(self.body_bytes);
+            self.log_response(&request, &version, status, &reason, bytes, latency);
+        }
+        Ok(match next {
+            Next::Raw if websocket => Next::WebSocket,
+            next => next,
+        })
+    }
+
+    fn log_response(
+        &self,
+        request: &Exchange,
+        version: &str,
+        status: u16,
+        reason: &str,
+        bytes: Option<u64>,
+        latency: Duration,
+    ) {
+        info!(
+            event = "response",
+            version,
+            status,
+            reason,
+            bytes,
+            latency = latency.as_secs_f64()
+        );
+        access_log::write(&access_log::Entry {
+            client_ip: self.connection.client_ip(),
+            time: request.time,
+            request_line: &request.request_line,
+            status,
+            bytes,
+            referer: request.referer.as_deref(),
+            user_agent: request.user_agent.as_deref(),
+        });
+    }
+
+    /// Answers the requests the proxy answers itself whose turn it is, until the
+    /// upstream starts sending the next response. Returns false if it stopped
+    /// sending instead, or an answer closed the connection.
+    async fn answer_requests(&mut self) -> Result<bool> {
+        loop {
+            let answered = {
+                let mut exchanges = self.shared.exchanges.lock().unwrap();
+                match exchanges.front() {
+                    Some(request) if request.answer.is_some() => exchanges.pop_front(),
+                    _ => None,
+                }
+            };
+            if let Some(request) = answered {
+                let answer = request
+                    .answer
+                    .as_ref()
+                    .expect("only answered requests are taken");
+                let mut response = &answer.response[..];
+                if request.method == "HEAD" {
+                    response = &response[..response.len() - answer.body_size];
+                }
+                self.data_log.lock().unwrap().outgoing(self.opt, response);
+                self.to.write_all(response).await?;
+                let status = answer.status;
+                let bytes = Some(answer.body_size as u64);
+                let latency = request.sent.elapsed();
+                let reason = reason_phrase(status);
+                self.log_response(&request, "HTTP/1.1", status, reason, bytes, latency);
+                if answer.close {
+                    return Ok(false);
+                }
+                continue;
+            }
+            if !self.buf.is_empty() {
+                return Ok(true);
+            }
+            let shared = self.shared;
+            tokio::select! {
+                n = self.read_more() => if n? == 0 {
+                    return Ok(false);
+                },
+                _ = shared.answered.notified() => {}
+            }
+        }
+    }
+
+    /// Forwards messages until they can't be parsed any more, then the rest of
+    /// the stream as it is.
+    async fn forward(mut self) -> Result<()> {
+        let next = loop {
+            let next = match self.direction {
+                Direction::Incoming => self.request().await?,
+                Direction::Outgoing => self.response().await?,
+            };
+            match next {
+                Next::Message => {}
+                Next::Raw | Next::WebSocket => break next,
+                Next::Closed => {
+                    let _ = self.to.shutdown().await;
+                    return Ok(());
+                }
+            }
+        };
+
+        let Messages {
+            opt,
+            connection,
+            data_log,
+            shared,
+            direction,
+            from,
+            mut to,
+            buf,
+            ..
+        } = self;
+        if let Next::WebSocket = next {
+            let accepted = &shared.websocket;
+            return websocket::forward(
+                opt, connection, data_log, direction, accepted, buf, from, to,
+            )
+            .await;
+        }
+        to.write_all(&buf).await?;
+        forward(opt, connection, data_log, direction, from, to).await
+    }
+}
+
+/// Like `forward` in both directions, but rewrites the header of each request and
+/// response on the connection.
+pub async fn forward_http(
+    opt: &Opt,
+    connection: &Connection,
+    data_log: &Mutex<&mut DataLog>,
+    client: AsyncStream,
+    upstream: AsyncStream,
+) -> Result<()> {
+    let (client_read, client_write) = tokio::io::split(client);
+    let (upstream_read, upstream_write) = tokio::io::split(upstream);
+    let shared = Shared::default();
+    let requests = Messages {
+        opt,
+        connection,
+        data_log,
+        shared: &shared,
+        direction: Direction::Incoming,
+        from: client_read,
+        to: upstream_write,
+        buf: vec![],
+        body_bytes: 0,
+        decoder: None,
+        discard: false,
+    };
+    let responses = Messages {
+        opt,
+        connection,
+        data_log,
+        shared: &shared,
+        direction: Direction::Outgoing,
+        from: upstream_read,
+        to: client_write,
+        buf: vec![],
+        body_bytes: 0,
+        decoder: None,
+        discard: false,
+    };
+    tokio::try_join!(requests.forward(), responses.forward())?;
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ if status < 300 => "Success",
+        _ if status < 400 => "Redirection",
+        _ if status < 500 => "Client Error",
+        _ => "Server Error",
+    }
+}
+
+/// Answers the client's request with a 502 response naming why the upstream
+/// couldn't be reached, once the request's header was read.
+pub async fn bad_gateway(
+    opt: &Opt,
+    data_log: &mut DataLog,
+    mut client: AsyncStream,
+    error: &anyhow::Error,
+) -> Result<()> {
+    let mut buf = vec![];
+    loop {
+        match parse_http_request_headers(&buf) {
+            Ok(None) if buf.len() < opt.max_header_size => {}
+            _ => break,
+        }
+        buf.reserve(1 << 14);
+        let n = client.read_buf(&mut buf).await?;
+        data_log.incoming(opt, &buf[buf.len() - n..]);
+        if n == 0 {
+            break;
+        }
+    }
+
+    let answer = Answer::error(502, &format!("{error:#}"), true);
+    info!("Answering with 502 Bad Gateway");
+    data_log.outgoing(opt, &answer.response);
+    client.write_all(&answer.response).await?;
+    let _ = client.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Peer;
+    use crate::stats::Stats;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use structopt::StructOpt;
+
+    /// Runs `forward_http` between a client that sends `request` and an upstream
+    /// that answers with `response` once the client stopped sending, returning
+    /// what the upstream and the client received.
+    async fn proxied(args: &[&str], request: Vec<u8>, response: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+        let args = ["tcp-proxy", "localhost", "--rewrite-host-header"]
+            .iter()
+            .chain(args);
+        let opt = Opt::from_iter(args);
+        let peer = Peer::Tcp(([192, 0, 2, 1], 1234).into());
+        let connection = Arc::new(Stats::default()).open(0, &peer);
+        let (mut client, incoming) = tokio::io::duplex(1 << 16);
+        let (outgoing, mut upstream) = tokio::io::duplex(1 << 16);
+        let client = tokio::spawn(async move {
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let upstream = tokio::spawn(async move {
+            let mut forwarded = vec![];
+            upstream.read_to_end(&mut forwarded).await.unwrap();
+            upstream.write_all(&response).await.unwrap();
+            forwarded
+        });
+
+        let mut data_log = DataLog::new(0);
+        let data_log = Mutex::new(&mut data_log);
+        let forwarding = forward_http(
+            &opt,
+            &connection,
+            &data_log,
+            Box::pin(incoming),
+            Box::pin(outgoing),
+        );
+        tokio::time::timeout(Duration::from_secs(5), forwarding)
+            .await
+            .expect("forwarding didn't finish")
+            .unwrap();
+        (upstream.await.unwrap(), client.await.unwrap())
+    }
+
+    /// What the upstream receives from a client that sends `request`.
+    async fn forwarded_requests(args: &[&str], request: Vec<u8>) -> Vec<u8> {
+        proxied(args, request, vec![]).await.0
+    }
+
+    #[tokio::test]
+    async fn truncated_http_headers_are_forwarded() {
+        let forwarded = forwarded_requests(&[], b"GET / HT".to_vec()).await;
+        assert_eq!(forwarded, b"GET / HT");
+    }
+
+    async fn rewritten_host(args: &[&str], host: &str) -> String {
+        let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\nAccept: */*\r\n\r\n");
+        let forwarded = forwarded_requests(args, request.into_bytes()).await;
+        let forwarded = String::from_utf8(forwarded).unwrap();
+        let rest = forwarded.strip_prefix("GET / HTTP/1.1\r\nHost: ").unwrap();
+        let (host, rest) = rest.split_once("\r\n").unwrap();
+        assert_eq!(rest, "Accept: */*\r\n\r\n");
+        host.to_string()
+    }
+
+    #[tokio::test]
+    async fn host_header_has_port_unless_default() {
+        assert_eq!(rewritten_host(&[], "proxy").await, "localhost");
+        assert_eq!(rewritten_host(&[], "proxy:8080").await, "localhost");
+        let port = ["--host-port", "8080"];
+        assert_eq!(rewritten_host(&port, "proxy").await, "localhost:8080");
+        let ssl = ["--ssl", "--host-port", "443"];
+        assert_eq!(rewritten_host(&ssl, "proxy:8443").await, "localhost");
+        let ssl_on_80 = ["--ssl", "--host-port", "80"];
+        assert_eq!(rewritten_host(&ssl_on_80, "proxy").await, "localhost:80");
+    }
+
+    #[tokio::test]
+    async fn host_header_option_replaces_or_removes_it() {
+        let host_header = ["--host-header", "staging.example.com"];
+        assert_eq!(
+            rewritten_host(&host_header, "proxy").await,
+            "staging.example.com"
+        );
+
+        let request = b"GET / HTTP/1.1\r\nHost: proxy\r\nAccept: */*\r\n\r\n".to_vec();
+        let forwarded = forwarded_requests(&["--host-header", ""], request).await;
+        assert_eq!(forwarded, b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn origin_and_referer_to_the_proxy_are_rewritten() {
+        let request = "GET / HTTP/1.1\r\nHost: proxy:8080\r\nOrigin: https://proxy:8080\r\n\
+                       Referer: http://user@Proxy:8080/a/b?c#d\r\n\
+                       Referer: http://proxy/\r\n\r\n";
+        let forwarded = forwarded_requests(&["--rewrite-origin"], request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nOrigin: https://localhost\r\n\
+             Referer: http://user@localhost/a/b?c#d\r\nReferer: http://proxy/\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwarded_headers_are_added_or_appended_to() {
+        let request = "GET / HTTP/1.1\r\nHost: proxy\r\n\r\n";
+        let forwarded = forwarded_requests(&["--add-forwarded"], request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 192.0.2.1\r\n\
+             X-Forwarded-Proto: http\r\nForwarded: for=192.0.2.1;proto=http\r\n\r\n"
+        );
+
+        let request = "GET / HTTP/1.1\r\nX-Forwarded-For: 198.51.100.7\r\n\
+                       X-Forwarded-Proto: https\r\nForwarded: for=198.51.100.7\r\n\r\n";
+        let forwarded = forwarded_requests(&["--add-forwarded"], request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 198.51.100.7, 192.0.2.1\r\n\
+             X-Forwarded-Proto: http\r\n\
+             Forwarded: for=198.51.100.7, for=192.0.2.1;proto=http\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn removed_headers_are_dropped() {
+        let request = "GET / HTTP/1.1\r\nHost: proxy\r\nAccept-Encoding: gzip\r\n\
+                       accept-encoding: br\r\nAccept: */*\r\n\r\n";
+        let args = [
+            "--remove-header",
+            "Accept-Encoding",
+            "--remove-header",
+            "host",
+        ];
+        let forwarded = forwarded_requests(&args, request.into()).await;
+        assert_eq!(forwarded, b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn set_headers_replace_or_are_appended() {
+        let request = "GET / HTTP/1.1\r\nHost: proxy\r\nAuthorization: Basic dXNlcg==\r\n\r\n";
+        let args = [
+            "--set-header",
+            "authorization: Bearer a:b",
+            "--set-header",
+            "X-Test:1",
+            "--set-header",
+            "X-Test: 2",
+        ];
+        let forwarded = forwarded_requests(&args, request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer a:b\r\n\
+             X-Test: 2\r\n\r\n"
+        );
+
+        let request = "GET / HTTP/1.1\r\nX-Token: a\r\nHost: proxy\r\nX-Token: b\r\n\r\n";
+        let forwarded = forwarded_requests(&["--set-header", "X-Token: c"], request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nX-Token: c\r\nHost: localhost\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn basic_auth_is_added_or_replaces_the_clients() {
+        let request = "GET / HTTP/1.1\r\nHost: proxy\r\n\r\n";
+        let args = ["--basic-auth", "user:pass"];
+        let forwarded = forwarded_requests(&args, request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+
+        let request = "GET / HTTP/1.1\r\nHost: proxy\r\nAuthorization: Bearer x\r\n\r\n";
+        let forwarded = forwarded_requests(&args, request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+
+        let args = ["--basic-auth", "user:pass", "--basic-auth-keep-existing"];
+        let forwarded = forwarded_requests(&args, request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer x\r\n\r\n"
+        );
+
+        // Removing the client's header comes first and --set-header last.
+        let args = [
+            "--basic-auth",
+            "user:pass",
+            "--basic-auth-keep-existing",
+            "--remove-header",
+            "authorization",
+        ];
+        let forwarded = forwarded_requests(&args, request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+        let args = [
+            "--basic-auth",
+            "user:pass",
+            "--set-header",
+            "Authorization: x",
+        ];
+        let forwarded = forwarded_requests(&args, request.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAuthorization: x\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn path_prefixes_are_rewritten() {
+        let args = [
+            "--rewrite-path",
+            "/api=/backend",
+            "--rewrite-path",
+            "/api/v1=/v2",
+            "--rewrite-path",
+            "/q=/query",
+        ];
+        let rewritten = |path: &'static str| async move {
+            let request = format!("GET {path} HTTP/1.1\r\nHost: proxy\r\n\r\n");
+            let forwarded = forwarded_requests(&args, request.into_bytes()).await;
+            let forwarded = String::from_utf8(forwarded).unwrap();
+            let (request_line, _) = forwarded.split_once("\r\n").unwrap();
+            request_line.to_string()
+        };
+        assert_eq!(rewritten("/api/users").await, "GET /backend/users HTTP/1.1");
+        assert_eq!(
+            rewritten("/api/v1/users?id=1").await,
+            "GET /v2/users?id=1 HTTP/1.1"
+        );
+        assert_eq!(rewritten("/other?/api").await, "GET /other?/api HTTP/1.1");
+        assert_eq!(rewritten("/?q=/api").await, "GET /?q=/api HTTP/1.1");
+        assert_eq!(rewritten("/q?x").await, "GET /query?x HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn blocked_requests_are_answered_in_order() {
+        let request = "GET /a HTTP/1.1\r\nHost: proxy\r\n\r\n\
+                       POST /admin/x HTTP/1.1\r\nHost: proxy\r\nContent-Length: 5\r\n\r\nhello\
+                       GET /b HTTP/1.1\r\nHost: proxy\r\n\r\n";
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+                        HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb";
+        let args = [
+            "--block",
+            "^DELETE ",
+            "--block",
+            "^POST /admin",
+            "--block-status",
+            "404",
+        ];
+        let (forwarded, received) = proxied(&args, request.into(), response.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET /a HTTP/1.1\r\nHost: localhost\r\n\r\nGET /b HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        );
+        assert_eq!(
+            String::from_utf8(received).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+             HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 36\r\n\r\n404 Not Found\n\nBlocked by the proxy\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb"
+        );
+    }
+
+    #[tokio::test]
+    async fn stubbed_requests_are_answered_by_the_proxy() {
+        let request = "GET /health?full HTTP/1.1\r\nHost: proxy\r\n\r\n\
+                       GET / HTTP/1.1\r\nHost: proxy\r\n\r\n\
+                       HEAD /health HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n";
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na";
+        let args = [
+            "--stub",
+            "/health=200:{\"ok\":true}",
+            "--stub",
+            "/static/*=404",
+        ];
+        let (forwarded, received) = proxied(&args, request.into(), response.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        );
+        assert_eq!(
+            String::from_utf8(received).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 11\r\n\r\n\
+             {\"ok\":true}\
+             HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na\
+             HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 11\r\n\
+             Connection: close\r\n\r\n"
+        );
+
+        let stub: Stub = "/static/*=404".parse().unwrap();
+        assert!(stub.matches("/static/app.js?v=2") && !stub.matches("/static"));
+        assert!("/x=204:body".parse::<Stub>().is_err());
+    }
+
+    async fn redirected_to(location: &str) -> String {
+        let request = b"GET / HTTP/1.1\r\nHost: proxy:7777\r\n\r\n".to_vec();
+        let response = format!("HTTP/1.1 302 Found\r\nLocation: {location}\r\n\r\n");
+        let args = ["--rewrite-location"];
+        let (_, received) = proxied(&args, request, response.into_bytes()).await;
+        let received = String::from_utf8(received).unwrap();
+        let rest = received.strip_prefix("HTTP/1.1 302 Found\r\n").unwrap();
+        rest.strip_prefix("Location: ")
+            .and_then(|rest| rest.strip_suffix("\r\n\r\n"))
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn locations_of_the_upstream_point_at_the_proxy() {
+        let rewritten = redirected_to("http://localhost/login?next=/").await;
+        assert_eq!(rewritten, "http://proxy:7777/login?next=/");
+        let rewritten = redirected_to("https://user@localhost:80/").await;
+        assert_eq!(rewritten, "http://user@proxy:7777/");
+        for untouched in [
+            "/login",
+            "//localhost/",
+            "http://example.com/",
+            "ftp://localhost/",
+        ] {
+            assert_eq!(redirected_to(untouched).await, untouched);
+        }
+    }
+
+    #[test]
+    fn locations_on_another_scheme_are_left_alone() {
+        let plain = Opt::from_iter(["tcp-proxy", "localhost"]);
+        assert_eq!(
+            rewrite_location(&plain, "https://localhost/login", "proxy"),
+            None
+        );
+        assert_eq!(
+            rewrite_location(&plain, "http://localhost/login", "proxy").as_deref(),
+            Some("http://proxy/login")
+        );
+        let tls = Opt::from_iter(["tcp-proxy", "localhost", "--ssl"]);
+        assert_eq!(rewrite_location(&tls, "http://localhost/", "proxy"), None);
+        assert_eq!(
+            rewrite_location(&tls, "https://localhost/", "proxy").as_deref(),
+            Some("http://proxy/")
+        );
+    }
+
+    #[tokio::test]
+    async fn every_response_on_a_connection_is_rewritten() {
+        let request = b"HEAD / HTTP/1.1\r\nHost: proxy\r\n\r\n\
+                        POST / HTTP/1.1\r\nHost: proxy\r\nContent-Length: 0\r\n\r\n";
+        let response = b"HTTP/1.1 301 Moved Permanently\r\nLocation: http://localhost/a\r\n\
+                         Content-Length: 5\r\n\r\n\
+                         HTTP/1.1 100 Continue\r\n\r\n\
+                         HTTP/1.1 303 See Other\r\nLocation: http://localhost/b\r\n\
+                         Transfer-Encoding: chunked\r\n\r\n\
+                         2\r\nhi\r\n0\r\n\r\n";
+        let args = ["--rewrite-location"];
+        let (_, received) = proxied(&args, request.to_vec(), response.to_vec()).await;
+        assert_eq!(
+            String::from_utf8(received).unwrap(),
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: http://proxy/a\r\n\
+             Content-Length: 5\r\n\r\n\
+             HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 303 See Other\r\nLocation: http://proxy/b\r\n\
+             Transfer-Encoding: chunked\r\n\r\n\
+             2\r\nhi\r\n0\r\n\r\n"
+        );
+    }
+
+    async fn set_cookies(args: &[&str]) -> String {
+        let request = b"GET / HTTP/1.1\r\nHost: proxy\r\n\r\n".to_vec();
+        let response = b"HTTP/1.1 200 OK\r\n\
+                         Set-Cookie: id=a3f; Domain=backend.example; Path=/; Secure; HttpOnly\r\n\
+                         Set-Cookie: theme=dark;domain=.backend.example;SameSite=Lax\r\n\
+                         Set-Cookie: lang=en; Expires=Wed, 21 Oct 2026 07:28:00 GMT\r\n\
+                         Content-Length: 0\r\n\r\n";
+        let (_, received) = proxied(args, request, response.to_vec()).await;
+        String::from_utf8(received).unwrap()
+    }
+
+    #[tokio::test]
+    async fn cookie_domains_are_replaced_or_removed() {
+        assert_eq!(
+            set_cookies(&[]).await,
+            "HTTP/1.1 200 OK\r\n\
+             Set-Cookie: id=a3f; Path=/; Secure; HttpOnly\r\n\
+             Set-Cookie: theme=dark;SameSite=Lax\r\n\
+             Set-Cookie: lang=en; Expires=Wed, 21 Oct 2026 07:28:00 GMT\r\n\
+             Content-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            set_cookies(&["--rewrite-cookie-domain", "proxy.test"]).await,
+            "HTTP/1.1 200 OK\r\n\
+             Set-Cookie: id=a3f; Domain=proxy.test; Path=/; Secure; HttpOnly\r\n\
+             Set-Cookie: theme=dark;domain=proxy.test;SameSite=Lax\r\n\
+             Set-Cookie: lang=en; Expires=Wed, 21 Oct 2026 07:28:00 GMT\r\n\
+             Content-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn hsts_is_stripped() {
+        let request = b"GET / HTTP/1.1\r\nHost: proxy\r\n\r\n".to_vec();
+        let response = b"HTTP/1.1 200 OK\r\n\
+                         Strict-Transport-Security: max-age=63072000; includeSubDomains\r\n\
+                         Content-Security-Policy: default-src 'self'; upgrade-insecure-requests; img-src *\r\n\
+                         Content-Security-Policy: Upgrade-Insecure-Requests\r\n\
+                         Content-Length: 0\r\n\r\n";
+        let (_, received) = proxied(&["--strip-hsts"], request, response.to_vec()).await;
+        assert_eq!(
+            String::from_utf8(received).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             Content-Security-Policy: default-src 'self'; img-src *\r\n\
+             Content-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn too_many_headers_are_rejected() {
+        let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..10_000 {
+            request.extend(format!("X-{i}: a\r\n").as_bytes());
+        }
+        request.extend(b"\r\n");
+        assert!(matches!(
+            parse_http_request_headers(&request),
+            Err(TooManyHeaders)
+        ));
+    }
+
+    #[tokio::test]
+    async fn long_header_is_forwarded_unmodified() {
+        let mut request = b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Long: ".to_vec();
+        request.resize(10 << 20, b'a');
+        let forwarded = forwarded_requests(&[], request.clone()).await;
+        assert!(forwarded == request);
+    }
+
+    #[tokio::test]
+    async fn invalid_chunk_sizes_end_rewriting() {
+        let requests = "\
+            POST /a HTTP/1.1\r\nHost: proxy\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;name=value\r\nhello\r\nzz\r\n\
+            GET /b HTTP/1.1\r\nHost: proxy\r\n\r\n";
+        let forwarded = forwarded_requests(&[], requests.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "\
+            POST /a HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;name=value\r\nhello\r\nzz\r\n\
+            GET /b HTTP/1.1\r\nHost: proxy\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_without_a_length_last_until_the_upstream_closes() {
+        let request = b"GET / HTTP/1.0\r\nHost: proxy\r\n\r\n".to_vec();
+        let response = b"HTTP/1.0 302 Found\r\nLocation: http://localhost/\r\n\r\n\
+                         HTTP/1.0 302 Found\r\nLocation: http://localhost/\r\n\r\n";
+        let args = ["--rewrite-location"];
+        let (_, received) = proxied(&args, request, response.to_vec()).await;
+        assert_eq!(
+            String::from_utf8(received).unwrap(),
+            "HTTP/1.0 302 Found\r\nLocation: http://proxy/\r\n\r\n\
+             HTTP/1.0 302 Found\r\nLocation: http://localhost/\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_request_on_a_connection_is_rewritten() {
+        // Sent in one go, with bodies that look like headers but aren't rewritten.
+        let requests = "\
+            POST /a HTTP/1.1\r\nHost: proxy\r\nContent-Length: 13\r\n\r\nHost: proxy\r\n\
+            POST /b HTTP/1.1\r\nHost: proxy\r\nTransfer-Encoding: chunked\r\n\r\n\
+            d\r\nHost: proxy\r\n\r\n0\r\nX-Trailer: 1\r\n\r\n\
+            GET /c HTTP/1.1\r\nHost: proxy\r\n\r\n";
+        let forwarded = forwarded_requests(&[], requests.into()).await;
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "\
+            POST /a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 13\r\n\r\nHost: proxy\r\n\
+            POST /b HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+            d\r\nHost: proxy\r\n\r\n0\r\nX-Trailer: 1\r\n\r\n\
+            GET /c HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        );
+    }
+}
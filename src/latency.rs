@@ -0,0 +1,47 @@
+use crate::stats::Connection;
+use crate::Opt;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Waits out --delay before a chunk that was read is forwarded.
+pub async fn chunk(opt: &Opt, connection: &Connection) {
+    if opt.delay > 0 {
+        connection.busy_for(jittered(opt, opt.delay)).await;
+    }
+}
+
+/// Waits out --delay-connect before connecting to the upstream.
+pub async fn connect(opt: &Opt, connection: &Connection) {
+    if opt.delay_connect > 0 {
+        connection.busy_for(jittered(opt, opt.delay_connect)).await;
+    }
+}
+
+/// `ms` give or take up to --jitter, chosen uniformly.
+fn jittered(opt: &Opt, ms: u64) -> Duration {
+    if opt.jitter == 0 {
+        return Duration::from_millis(ms);
+    }
+    // Each RandomState has new keys, which is random enough for jitter.
+    let random = RandomState::new().build_hasher().finish();
+    let offset = random % (2 * opt.jitter + 1);
+    Duration::from_millis((ms + offset).saturating_sub(opt.jitter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn jitter_varies_the_delay_both_ways() {
+        let opt = Opt::from_iter(["tcp-proxy", "localhost", "--jitter", "5"]);
+        let delays: Vec<_> = (0..1000).map(|_| jittered(&opt, 10).as_millis()).collect();
+        assert!(delays.iter().all(|delay| (5..=15).contains(delay)));
+        assert!(delays.contains(&5) && delays.contains(&15));
+
+        let delays: Vec<_> = (0..1000).map(|_| jittered(&opt, 2).as_millis()).collect();
+        assert!(delays.iter().all(|&delay| delay <= 7));
+    }
+}
@@ -0,0 +1,2408 @@
+#[cfg(all(feature = "ssl", feature = "rustls"))]
+compile_error!("the ssl and rustls features are mutually exclusive");
+#[cfg(not(any(feature = "ssl", feature = "rustls")))]
+compile_error!("either the ssl or the rustls feature must be enabled");
+
+mod access_log;
+mod balance;
+mod certgen;
+mod client_hello;
+mod color;
+mod data_log;
+mod decode;
+mod dump;
+mod fault;
+mod filter;
+mod fragment;
+mod happy_eyeballs;
+mod har;
+mod health;
+mod http;
+mod http_proxy;
+mod latency;
+mod line_writer;
+mod listener;
+mod log_file;
+mod logging;
+mod mirror;
+mod pcap;
+mod proxy_protocol;
+mod replace;
+#[cfg(feature = "rustls")]
+mod rustls;
+mod save_certs;
+mod sockopt;
+mod socks5;
+#[cfg(target_os = "linux")]
+mod splice;
+#[cfg(feature = "ssl")]
+mod ssl;
+mod starttls;
+mod stats;
+mod throttle;
+#[cfg(target_os = "linux")]
+mod transparent;
+mod udp;
+mod websocket;
+
+#[cfg(feature = "rustls")]
+use crate::rustls as ssl;
+use anyhow::{anyhow, bail, Context, Result};
+use balance::{Balance, Balancer};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use color::ColorChoice;
+use data_log::{DataFormat, DataLog};
+use fault::Faults;
+use filter::Filtered;
+use fragment::Fragmented;
+use http_proxy::{EnvProxy, HttpProxy};
+use listener::{accept_any, Listener, UnixSocketGuard};
+use logging::{Direction, Directions, LogFormat, Timestamps};
+use mirror::Mirrored;
+use regex::Regex;
+use replace::{Replaced, Replacement};
+use save_certs::SavedCerts;
+use socks5::Socks5;
+use ssl::{generate_acceptor, generate_connector, wrap_ssl_client, wrap_ssl_server};
+use starttls::StartTls;
+use stats::{Connection, Stats};
+use std::any::Any;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use throttle::Throttled;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::{TcpSocket, TcpStream, UnixStream};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+trait AsyncReadWrite: AsyncRead + AsyncWrite + Any {
+    /// What the stream is, to tell whether it is a bare socket.
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: AsyncRead + AsyncWrite + Any> AsyncReadWrite for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+type AsyncStream = Pin<Box<dyn AsyncReadWrite + Send>>;
+
+/// Replays bytes that were already read from `inner` before reading from it again.
+struct Prepend {
+    prefix: Vec<u8>,
+    inner: AsyncStream,
+}
+
+impl Prepend {
+    fn wrap(prefix: Vec<u8>, inner: AsyncStream) -> AsyncStream {
+        if prefix.is_empty() {
+            return inner;
+        }
+        Box::pin(Prepend { prefix, inner })
+    }
+}
+
+impl AsyncRead for Prepend {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.prefix.is_empty() {
+            return self.inner.as_mut().poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix[..n]);
+        self.prefix.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Prepend {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.inner.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+/// Runs `step` of connecting to the upstream, failing it as `what` taking too long
+/// once --connect-timeout is over.
+async fn within_connect_timeout<T>(
+    opt: &Opt,
+    what: &str,
+    step: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    if opt.connect_timeout == 0 {
+        return step.await;
+    }
+    match tokio::time::timeout(Duration::from_secs(opt.connect_timeout), step).await {
+        Ok(result) => result,
+        Err(_) => bail!("{what} timed out after {}s", opt.connect_timeout),
+    }
+}
+
+/// Connects to the upstream and, unless --starttls waits for the upgrade, does the
+/// TLS handshake with it for --ssl. Failed attempts are retried --connect-retries
+/// times with --retry-backoff, all within --connect-timeout.
+async fn connect_upstream(
+    opt: &Opt,
+    target: Target<'_>,
+    client: Option<SocketAddr>,
+    ssl_connector: Option<&ssl::Connector>,
+    saved_certs: &SavedCerts,
+) -> Result<(AsyncStream, Option<String>)> {
+    let attempts = async {
+        let mut backoff = Duration::from_millis(opt.retry_backoff);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let result = async {
+                let header =
+                    |local: Option<SocketAddr>| proxy_protocol::header(opt, client.zip(local));
+                let (stream, addr) = connect_with_header(target, Some(opt), header).await?;
+                let (stream, alpn) = match ssl_connector.filter(|_| opt.starttls.is_none()) {
+                    Some(ssl_connector) => {
+                        wrap_ssl_client(opt, stream, ssl_connector, saved_certs).await?
+                    }
+                    None => (stream, None),
+                };
+                Ok((stream, alpn, addr))
+            }
+            .await;
+            match result {
+                Err(e) if attempt <= opt.connect_retries => {
+                    warn!("Attempt {attempt} failed: {e:#}, retrying in {backoff:?}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Ok((stream, alpn, addr)) => {
+                    if let Some(addr) = addr.filter(|_| !opt.summary_only) {
+                        info!("Connected to {addr}");
+                    }
+                    return Ok((stream, alpn));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    let what = format!("Connecting to {target}");
+    within_connect_timeout(opt, &what, attempts).await
+}
+
+/// Connects to the first of `candidates` that works, as the index of each target
+/// and the target. With --balance failover this records which targets fail and
+/// logs when one that isn't the first of all is used.
+async fn connect_first<'a>(
+    opt: &Opt,
+    candidates: impl ExactSizeIterator<Item = (usize, Target<'a>)>,
+    client: Option<SocketAddr>,
+    balancer: &Balancer,
+    ssl_connector: Option<&ssl::Connector>,
+    saved_certs: &SavedCerts,
+) -> Result<(AsyncStream, Option<String>, Target<'a>)> {
+    let failover = opt.balance == Balance::Failover;
+    let mut left = candidates.len();
+    for (i, target) in candidates {
+        left -= 1;
+        match connect_upstream(opt, target, client, ssl_connector, saved_certs).await {
+            Ok((stream, alpn)) => {
+                if failover && balancer.succeeded(i) {
+                    info!("Upstream {target} is back");
+                }
+                if failover && i > 0 {
+                    warn!("Serving from backup upstream {target}");
+                }
+                return Ok((stream, alpn, target));
+            }
+            Err(e) if failover => {
+                balancer.failed(i);
+                if left == 0 {
+                    return Err(e);
+                }
+                warn!(
+                    "Upstream {target} failed, skipping it for {}s: {e:#}",
+                    opt.failover_cooldown
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    bail!("No upstream to connect to")
+}
+
+async fn connect(target: Target<'_>) -> Result<AsyncStream> {
+    Ok(connect_with_header(target, None, |_| None).await?.0)
+}
+
+/// Connects to `target` as `opt` says if given: through its tunnel for a TCP one,
+/// or otherwise to the --resolve addresses from --bind-source, with its socket
+/// options. Then writes the header made from the local address of the connection
+/// first, for --send-proxy. Also returns the address connected to, unless that was
+/// a tunnel or a Unix domain socket.
+async fn connect_with_header(
+    target: Target<'_>,
+    opt: Option<&Opt>,
+    header: impl FnOnce(Option<SocketAddr>) -> Option<Vec<u8>>,
+) -> Result<(AsyncStream, Option<SocketAddr>)> {
+    let tcp = |stream: TcpStream| {
+        if let Some(opt) = opt {
+            sockopt::tune(opt, &stream).context("Failed to set socket options")?;
+        }
+        let local = stream.local_addr().ok();
+        Ok::<_, anyhow::Error>((Box::pin(stream) as AsyncStream, local))
+    };
+    let mut addr = None;
+    let (mut stream, local) = match (target, opt.and_then(Opt::tunnel)) {
+        (Target::Tcp(_, _), Some(Tunnel::Socks5(socks5))) => tcp(socks5.connect(target).await?)?,
+        (Target::Tcp(_, _), Some(Tunnel::Http(proxy))) => tcp(proxy.connect(target).await?)?,
+        (Target::Tcp(host, port), None) => {
+            let stream = connect_tcp(opt, host, port)
+                .await
+                .with_context(|| format!("Failed to connect to {target}"))?;
+            addr = stream.peer_addr().ok();
+            tcp(stream)?
+        }
+        (Target::Unix(path), _) => UnixStream::connect(path)
+            .await
+            .map(|stream| (Box::pin(stream) as AsyncStream, None))
+            .with_context(|| format!("Failed to connect to {target}"))?,
+    };
+    if let Some(header) = header(local) {
+        stream
+            .write_all(&header)
+            .await
+            .context("Failed to send the PROXY protocol header")?;
+    }
+    Ok((stream, addr))
+}
+
+/// Connects to the addresses of `host`, racing them with Happy Eyeballs. They are
+/// the ones --resolve or --resolve-once gave if any, and with --bind-source only
+/// those of its family can be connected to.
+async fn connect_tcp(opt: Option<&Opt>, host: &str, port: u16) -> Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = match opt.and_then(|opt| opt.resolved(host)) {
+        Some(ips) => ips.iter().map(|&ip| SocketAddr::new(ip, port)).collect(),
+        None => tokio::net::lookup_host((host, port)).await?.collect(),
+    };
+    let bind = opt.and_then(|opt| opt.bind_source);
+    let addrs: Vec<_> = addrs
+        .into_iter()
+        .filter(|addr| bind.is_none_or(|bind| bind.is_ipv4() == addr.is_ipv4()))
+        .collect();
+    if addrs.is_empty() {
+        match bind {
+            Some(bind) => {
+                let family = if bind.is_ipv4() { 4 } else { 6 };
+                bail!("{host} has no IPv{family} address to connect to from --bind-source {bind}")
+            }
+            None => bail!("{host} has no address to connect to"),
+        }
+    }
+    let addrs = happy_eyeballs::interleave(addrs, opt.and_then(Opt::prefer_ipv6));
+    let connect = |addr: SocketAddr| async move {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(bind) = bind {
+            // A fixed port is shared by the attempts Happy Eyeballs races, and is
+            // bound again while the last connection from it is in TIME_WAIT.
+            if bind.port() != 0 {
+                socket.set_reuseaddr(true)?;
+            }
+            socket.bind(bind).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to bind to --bind-source {bind}: {e}"),
+                )
+            })?;
+        }
+        socket.connect(addr).await
+    };
+    let (stream, addr) =
+        happy_eyeballs::race(&addrs, happy_eyeballs::ATTEMPT_DELAY, connect).await?;
+    match stream.local_addr() {
+        Ok(local) => debug!("Connected to {addr} from {local}"),
+        Err(_) => debug!("Connected to {addr}"),
+    }
+    Ok(stream)
+}
+
+/// Looks up each TCP target that isn't an address already, for --resolve-once.
+fn resolve_targets(opt: &Opt) -> Result<Vec<(String, Vec<IpAddr>)>> {
+    let mut resolved: Vec<(String, Vec<IpAddr>)> = vec![];
+    for target in opt.targets() {
+        let Target::Tcp(host, port) = target else {
+            continue;
+        };
+        if host.parse::<IpAddr>().is_ok() || resolved.iter().any(|(name, _)| name == host) {
+            continue;
+        }
+        let mut ips = vec![];
+        let addrs = std::net::ToSocketAddrs::to_socket_addrs(&(host, port))
+            .with_context(|| format!("Failed to look up {host} for --resolve-once"))?;
+        for addr in addrs {
+            if !ips.contains(&addr.ip()) {
+                ips.push(addr.ip());
+            }
+        }
+        resolved.push((host.to_string(), ips));
+    }
+    Ok(resolved)
+}
+
+/// Picks the first --route matching the client's SNI, if any were given, instead
+/// of `target`.
+fn select_route<'a>(
+    opt: &'a Opt,
+    server_name: Option<&str>,
+    target: Target<'a>,
+) -> Option<&'a Route> {
+    if opt.route.is_empty() {
+        return None;
+    }
+    let route = server_name.and_then(|name| opt.route.iter().find(|route| route.matches(name)));
+    match (route, server_name) {
+        (Some(route), Some(name)) => {
+            info!("Routing SNI {name} to {}", route.target())
+        }
+        (_, Some(name)) => info!("No route for SNI {name}, forwarding to {target}"),
+        (_, None) => info!("No SNI to route on, forwarding to {target}"),
+    }
+    route
+}
+
+/// Applies --ssl to the upstream and --ssl-server to the client side.
+async fn wrap_tls(
+    opt: &Opt,
+    incoming_stream: AsyncStream,
+    outgoing_stream: AsyncStream,
+    ssl_acceptor: Option<&ssl::Acceptor>,
+    ssl_connector: Option<&ssl::Connector>,
+    saved_certs: &SavedCerts,
+) -> Result<(AsyncStream, AsyncStream)> {
+    let mut upstream_alpn = None;
+    let outgoing_stream = match ssl_connector {
+        Some(ssl_connector) => {
+            let handshake = wrap_ssl_client(opt, outgoing_stream, ssl_connector, saved_certs);
+            let (stream, alpn) =
+                within_connect_timeout(opt, "TLS handshake with upstream", handshake).await?;
+            upstream_alpn = alpn;
+            stream
+        }
+        None => outgoing_stream,
+    };
+    let incoming_stream =
+        wrap_tls_server(opt, incoming_stream, ssl_acceptor, upstream_alpn).await?;
+    Ok((incoming_stream, outgoing_stream))
+}
+
+/// Applies --ssl-server to the client side, warning if the client negotiates
+/// another ALPN protocol than the upstream did.
+async fn wrap_tls_server(
+    opt: &Opt,
+    mut incoming_stream: AsyncStream,
+    ssl_acceptor: Option<&ssl::Acceptor>,
+    upstream_alpn: Option<String>,
+) -> Result<AsyncStream> {
+    let mut client_alpn = None;
+    let incoming_stream = match ssl_acceptor {
+        Some(ssl_acceptor) => {
+            if opt.ja3 {
+                let (hello, _) = client_hello::sniff(opt, &mut incoming_stream).await?;
+                incoming_stream = Prepend::wrap(hello, incoming_stream);
+            }
+            let (stream, alpn) = wrap_ssl_server(opt, incoming_stream, ssl_acceptor).await?;
+            client_alpn = alpn;
+            stream
+        }
+        None => incoming_stream,
+    };
+
+    if let (Some(upstream_alpn), Some(client_alpn)) = (upstream_alpn, client_alpn) {
+        if upstream_alpn != client_alpn {
+            warn!(
+                "Client negotiated ALPN {client_alpn} but upstream negotiated {upstream_alpn}, \
+                 forwarding will likely break"
+            );
+        }
+    }
+
+    Ok(incoming_stream)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_client(
+    opt: &Opt,
+    connection: &Connection,
+    data_log: &mut DataLog,
+    mut incoming_stream: AsyncStream,
+    ssl_acceptor: Option<Arc<ssl::Acceptor>>,
+    ssl_connector: Option<Arc<ssl::Connector>>,
+    saved_certs: SavedCerts,
+    balancer: &Balancer,
+    original: Option<std::io::Result<SocketAddr>>,
+) -> Result<()> {
+    if opt.accept_proxy {
+        if let Some((client, _)) = proxy_protocol::read_header(&mut incoming_stream).await? {
+            info!("Proxied for {client}");
+            connection.proxied(client);
+        }
+    }
+    let destination_opt;
+    let opt = if opt.socks5_server {
+        let (host, port) = socks5::accept(opt, &mut incoming_stream).await?;
+        info!("SOCKS5 client asked for {}", Target::Tcp(&host, port));
+        destination_opt = opt.with_destination(host, port);
+        &destination_opt
+    } else if let Some(original) = original {
+        let original =
+            original.context("Failed to get the original destination for --transparent")?;
+        info!("Originally for {original}");
+        destination_opt = opt.with_destination(original.ip().to_string(), original.port());
+        &destination_opt
+    } else {
+        opt
+    };
+    let (sniffed, client_hello) =
+        if opt.sniff_sni || !opt.route.is_empty() || (opt.ja3 && !opt.ssl_server) {
+            client_hello::sniff(opt, &mut incoming_stream).await?
+        } else {
+            (vec![], None)
+        };
+    let server_name = client_hello.and_then(|hello| hello.server_name);
+    let targets = opt.targets();
+    let cooldown = Duration::from_secs(opt.failover_cooldown);
+    let candidates = balancer.candidates(opt.balance, connection.index(), cooldown);
+    let first = candidates.first().map_or(targets[0], |&i| targets[i]);
+    let route = select_route(opt, server_name.as_deref(), first);
+
+    latency::connect(opt, connection).await;
+    let ssl_connector_ref = ssl_connector.as_deref();
+    let client = connection.client_addr();
+    let connected = match route {
+        Some(route) => {
+            connect_upstream(opt, route.target(), client, ssl_connector_ref, &saved_certs)
+                .await
+                .map(|(stream, alpn)| (stream, alpn, route.target()))
+        }
+        None if candidates.is_empty() => Err(anyhow!("Every upstream is failing --health-check")),
+        None => {
+            let candidates = candidates.iter().map(|&i| (i, targets[i]));
+            connect_first(
+                opt,
+                candidates,
+                client,
+                balancer,
+                ssl_connector_ref,
+                &saved_certs,
+            )
+            .await
+        }
+    };
+    if opt.socks5_server {
+        socks5::reply(&mut incoming_stream, connected.as_ref().map(|_| ())).await?;
+    }
+    let (outgoing_stream, upstream_alpn, target) = match connected {
+        Ok(connected) => connected,
+        Err(e) if opt.parse_http() && opt.starttls.is_none() && !opt.socks5_server => {
+            let mut incoming_stream = Prepend::wrap(sniffed, incoming_stream);
+            if let Some(ssl_acceptor) = &ssl_acceptor {
+                (incoming_stream, _) = wrap_ssl_server(opt, incoming_stream, ssl_acceptor).await?;
+            }
+            http::bad_gateway(opt, data_log, incoming_stream, &e).await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    connection.connected(target.to_string());
+    let mut outgoing_stream = outgoing_stream;
+    let mut incoming_stream = if opt.starttls.is_some() {
+        incoming_stream
+    } else {
+        wrap_tls_server(opt, incoming_stream, ssl_acceptor.as_deref(), upstream_alpn).await?
+    };
+    if !sniffed.is_empty() {
+        data_log.incoming(opt, &sniffed);
+        connection.forwarded(Direction::Incoming, sniffed.len());
+        outgoing_stream.write_all(&sniffed).await?;
+    }
+
+    if let Some(protocol) = opt.starttls {
+        let upgrade = starttls::negotiate(
+            opt,
+            protocol,
+            data_log,
+            &mut incoming_stream,
+            &mut outgoing_stream,
+        )
+        .await?;
+        if let Some(mut upgrade) = upgrade {
+            info!("Upgrading both sides to TLS");
+            (incoming_stream, outgoing_stream) = wrap_tls(
+                opt,
+                upgrade.client(incoming_stream),
+                upgrade.server(outgoing_stream),
+                ssl_acceptor.as_deref(),
+                ssl_connector.as_deref(),
+                &saved_certs,
+            )
+            .await?;
+        }
+    }
+
+    let incoming_stream = Mirrored::wrap(opt, &sniffed, incoming_stream);
+    let incoming_stream = Filtered::wrap(opt, Direction::Incoming, incoming_stream)?;
+    let outgoing_stream = Filtered::wrap(opt, Direction::Outgoing, outgoing_stream)?;
+    let replacements = opt.replacements();
+    let replace =
+        |direction, stream| Replaced::wrap(&replacements, opt.replace_direction, direction, stream);
+    let incoming_stream = replace(Direction::Incoming, incoming_stream);
+    let outgoing_stream = replace(Direction::Outgoing, outgoing_stream);
+    // What is written to the upstream goes up, and to the client down.
+    let incoming_stream = Throttled::wrap(opt, Direction::Outgoing, incoming_stream);
+    let outgoing_stream = Throttled::wrap(opt, Direction::Incoming, outgoing_stream);
+    let incoming_stream = Fragmented::wrap(opt, incoming_stream);
+    let outgoing_stream = Fragmented::wrap(opt, outgoing_stream);
+
+    let data_log = Mutex::new(data_log);
+    if opt.parse_http() {
+        http::forward_http(opt, connection, &data_log, incoming_stream, outgoing_stream).await?;
+    } else {
+        #[cfg(target_os = "linux")]
+        if let Some((incoming, outgoing)) =
+            splice::tcp_streams(opt, &incoming_stream, &outgoing_stream)
+        {
+            debug!("Splicing the data between the sockets");
+            return splice::forward(opt, connection, &data_log, incoming, outgoing).await;
+        }
+        // The directions are forwarded independently, so one side not reading can't
+        // stop the other from being forwarded.
+        let (incoming_read, incoming_write) = tokio::io::split(incoming_stream);
+        let (outgoing_read, outgoing_write) = tokio::io::split(outgoing_stream);
+        tokio::try_join!(
+            forward(
+                opt,
+                connection,
+                &data_log,
+                Direction::Incoming,
+                incoming_read,
+                outgoing_write,
+            ),
+            forward(
+                opt,
+                connection,
+                &data_log,
+                Direction::Outgoing,
+                outgoing_read,
+                incoming_write
+            ),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Forwards one direction of a connection until `from` stops sending, then passes
+/// that on, so clients that half-close still get their response.
+async fn forward(
+    opt: &Opt,
+    connection: &Connection,
+    data_log: &Mutex<&mut DataLog>,
+    direction: Direction,
+    mut from: ReadHalf<AsyncStream>,
+    mut to: WriteHalf<AsyncStream>,
+) -> Result<()> {
+    let mut buf = vec![0; opt.buffer_size];
+    loop {
+        let n = from.read(&mut buf).await?;
+        let data = &buf[..n];
+        match direction {
+            Direction::Incoming => data_log.lock().unwrap().incoming(opt, data),
+            Direction::Outgoing => data_log.lock().unwrap().outgoing(opt, data),
+        }
+        connection.forwarded(direction, n);
+        if n == 0 {
+            // A TLS close_notify keeps the upstream session resumable. Shutting down
+            // fails if the peer is already gone, which is no error.
+            let _ = to.shutdown().await;
+            return Ok(());
+        }
+        latency::chunk(opt, connection).await;
+        to.write_all(data).await?;
+    }
+}
+
+/// The command line options, which also hold how a [`Proxy`] is configured.
+#[derive(Clone, StructOpt)]
+pub struct Opt {
+    /// Upstream host[:port], with an IPv6 address in brackets to give a port, or
+    /// unix:<path> to forward to a Unix domain socket; not given with
+    /// --socks5-server or --transparent
+    #[structopt(default_value = "", hide_default_value = true)]
+    hostname: String,
+
+    /// Another upstream as host[:port], which connections take turns with the
+    /// hostname in being forwarded to, on --host-port unless a port is given; can be
+    /// repeated. Host headers are still rewritten to name the hostname
+    #[structopt(long, number_of_values = 1)]
+    upstream: Vec<Upstream>,
+
+    /// How connections are spread over the hostname and each --upstream:
+    /// round-robin, or failover to forward to the first one that can be connected to
+    #[structopt(long, default_value = "round-robin")]
+    balance: Balance,
+
+    /// Seconds --balance failover skips an upstream for after connecting to it failed
+    #[structopt(long, default_value = "30")]
+    failover_cooldown: u64,
+
+    /// Also send everything clients send to this host[:port], over plain TCP and
+    /// discarding what it answers; the port defaults to --host-port
+    #[structopt(long)]
+    mirror: Option<Upstream>,
+
+    /// Connect to this address instead of looking up the hostname, which is still
+    /// used for SNI and Host headers; can be repeated to try each in turn
+    #[structopt(long, number_of_values = 1, conflicts_with_all = &["resolve-once", "socks5", "http-proxy", "socks5-server", "transparent"])]
+    resolve: Vec<IpAddr>,
+
+    /// Look up the hostname and each --upstream once at startup and keep connecting
+    /// to those addresses, instead of looking them up for every connection
+    #[structopt(long, conflicts_with_all = &["socks5", "http-proxy", "socks5-server", "transparent"])]
+    resolve_once: bool,
+
+    /// The addresses hosts have from --resolve or --resolve-once, in order.
+    #[structopt(skip)]
+    resolved: Vec<(String, Vec<IpAddr>)>,
+
+    /// Try the IPv4 addresses of upstreams first when racing them with Happy
+    /// Eyeballs, instead of the family the first looked up address has
+    #[structopt(long, conflicts_with = "prefer-ipv6")]
+    prefer_ipv4: bool,
+
+    /// Try the IPv6 addresses of upstreams first when racing them with Happy
+    /// Eyeballs, instead of the family the first looked up address has
+    #[structopt(long)]
+    prefer_ipv6: bool,
+
+    /// Connect to upstreams from this local address as ip[:port], to appear to come
+    /// from it when the machine has several. With a port, a connection to an
+    /// upstream address fails while the last one to it is open or in TIME_WAIT
+    #[structopt(long, parse(try_from_str = parse_bind_source), conflicts_with_all = &["socks5", "http-proxy"])]
+    bind_source: Option<SocketAddr>,
+
+    /// Connect to upstreams through this SOCKS5 proxy at host[:port], which resolves
+    /// their hostnames; the port defaults to 1080
+    #[structopt(long)]
+    socks5: Option<Upstream>,
+
+    /// Authenticate to --socks5 with this user:pass
+    #[structopt(long, requires = "socks5")]
+    socks5_auth: Option<socks5::Credentials>,
+
+    /// Connect to upstreams through this HTTP proxy at host[:port] with CONNECT;
+    /// the port defaults to 8080
+    #[structopt(long, conflicts_with = "socks5")]
+    http_proxy: Option<Upstream>,
+
+    /// Authenticate to --http-proxy with Basic auth as user:pass
+    #[structopt(long, requires = "http-proxy", parse(from_str = basic_authorization))]
+    http_proxy_auth: Option<String>,
+
+    /// Without --http-proxy or --socks5, connect through the proxy in $HTTPS_PROXY
+    /// or $ALL_PROXY, which can be http://[user:pass@]host[:port] or socks5://...
+    #[structopt(long)]
+    use_env_proxy: bool,
+
+    /// Act as a SOCKS5 server, forwarding each client to the destination it asks
+    /// for instead of to a hostname
+    #[structopt(long, conflicts_with_all = &["upstream", "balance", "health-check", "route", "sniff-sni", "ja3"])]
+    socks5_server: bool,
+
+    /// Require --socks5-server clients to authenticate with this user:pass
+    #[structopt(long, requires = "socks5-server")]
+    socks5_server_auth: Option<socks5::Credentials>,
+
+    /// Only let --socks5-server clients connect to destinations matching
+    /// <pattern>[:<port>], exact or *.domain; can be repeated
+    #[structopt(long, number_of_values = 1, requires = "socks5-server")]
+    socks5_allow: Vec<socks5::Allowed>,
+
+    /// Milliseconds to wait before forwarding each chunk of data, either way
+    #[structopt(long, default_value = "0")]
+    delay: u64,
+
+    /// Milliseconds to wait before connecting to the upstream
+    #[structopt(long, default_value = "0")]
+    delay_connect: u64,
+
+    /// Vary --delay and --delay-connect by up to this many milliseconds either way
+    #[structopt(long, default_value = "0")]
+    jitter: u64,
+
+    /// Probability from 0 to 1 that a connection is closed right after it is
+    /// accepted, for fault injection
+    #[structopt(long, default_value = "0")]
+    fault_drop_rate: f64,
+
+    /// Kill each connection after about this many bytes were forwarded, both ways
+    /// together, for fault injection
+    #[structopt(long)]
+    fault_abort_after: Option<u64>,
+
+    /// Close TCP clients with an RST rather than a FIN when a fault fires, using
+    /// SO_LINGER(0)
+    #[structopt(long)]
+    fault_reset: bool,
+
+    /// Seed for which connections --fault-drop-rate picks, to repeat a run; a
+    /// random one is logged otherwise
+    #[structopt(long, requires = "fault-drop-rate")]
+    fault_seed: Option<u64>,
+
+    /// Always copy the data through the proxy, instead of splicing it between the
+    /// sockets on Linux when nothing needs to see it
+    #[structopt(long)]
+    no_splice: bool,
+
+    /// Write forwarded data in slices of at most this many bytes, to test how peers
+    /// cope with messages split across reads
+    #[structopt(long)]
+    fragment: Option<usize>,
+
+    /// Milliseconds to wait between the slices of --fragment
+    #[structopt(long, default_value = "0")]
+    fragment_delay: u64,
+
+    /// Set TCP_NODELAY on client and upstream sockets, so small writes aren't held
+    /// back by Nagle's algorithm
+    #[structopt(long)]
+    nodelay: bool,
+
+    /// Enable TCP keepalives on client and upstream sockets, starting after this
+    /// many idle seconds
+    #[structopt(long, parse(try_from_str = sockopt::parse_positive))]
+    tcp_keepalive: Option<u64>,
+
+    /// Size of the kernel receive buffer of client and upstream sockets, in bytes
+    #[structopt(long, parse(try_from_str = sockopt::parse_positive))]
+    so_rcvbuf: Option<usize>,
+
+    /// Size of the kernel send buffer of client and upstream sockets, in bytes
+    #[structopt(long, parse(try_from_str = sockopt::parse_positive))]
+    so_sndbuf: Option<usize>,
+
+    /// Limit each connection to this many bytes per second in either direction
+    #[structopt(long)]
+    rate_limit: Option<u64>,
+
+    /// Limit each connection to this many bytes per second from the client to the
+    /// upstream, instead of --rate-limit
+    #[structopt(long)]
+    rate_limit_up: Option<u64>,
+
+    /// Limit each connection to this many bytes per second from the upstream to the
+    /// client, instead of --rate-limit
+    #[structopt(long)]
+    rate_limit_down: Option<u64>,
+
+    /// Forward each connection to where it was originally for before an iptables
+    /// REDIRECT sent it to the proxy, instead of to a hostname (Linux only). Host
+    /// headers are left alone unless --host-header is given
+    #[structopt(long, conflicts_with_all = &["socks5-server", "listen-unix", "upstream", "balance", "health-check", "route"])]
+    transparent: bool,
+
+    /// Start each upstream connection with a PROXY protocol v1 header naming the
+    /// client, before TLS with --ssl
+    #[structopt(long, conflicts_with = "send-proxy-v2")]
+    send_proxy: bool,
+
+    /// Start each upstream connection with a binary PROXY protocol v2 header
+    /// naming the client, before TLS with --ssl
+    #[structopt(long)]
+    send_proxy_v2: bool,
+
+    /// Expect every client to start with a PROXY protocol v1 or v2 header, as sent
+    /// by a load balancer in front, and take the client's address from it
+    #[structopt(long)]
+    accept_proxy: bool,
+
+    /// Check each upstream in the background by connecting to it, with the TLS
+    /// handshake for --ssl, and take those failing out of rotation
+    #[structopt(long)]
+    health_check: bool,
+
+    /// Request this path with --health-check and expect a 2xx status
+    #[structopt(long, requires = "health-check")]
+    health_check_path: Option<String>,
+
+    /// Seconds between --health-check checks of an upstream
+    #[structopt(long, default_value = "5")]
+    health_check_interval: u64,
+
+    /// Seconds a --health-check check may take before it fails
+    #[structopt(long, default_value = "2")]
+    health_check_timeout: u64,
+
+    /// Passed --health-check checks in a row for an upstream to be back in rotation
+    #[structopt(long, default_value = "2")]
+    health_check_rise: u32,
+
+    /// Failed --health-check checks in a row for an upstream to leave rotation
+    #[structopt(long, default_value = "3")]
+    health_check_fall: u32,
+
+    #[structopt(long)]
+    ssl: bool,
+
+    /// Server name to send to the upstream instead of the hostname
+    #[structopt(long, requires = "ssl", conflicts_with = "no-sni")]
+    sni: Option<String>,
+
+    /// Don't send a server name to the upstream
+    #[structopt(long, requires = "ssl")]
+    no_sni: bool,
+
+    /// ALPN protocol to offer upstream and accept from clients, in order of preference
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_alpn_protocol))]
+    alpn: Vec<String>,
+
+    /// Lowest TLS version to allow on either side (1.0, 1.1, 1.2 or 1.3)
+    #[structopt(long)]
+    tls_min_version: Option<TlsVersion>,
+
+    /// Highest TLS version to allow on either side (1.0, 1.1, 1.2 or 1.3)
+    #[structopt(long)]
+    tls_max_version: Option<TlsVersion>,
+
+    /// Append NSS key log lines for every TLS handshake to this file
+    #[structopt(long, env = "SSLKEYLOGFILE")]
+    keylog: Option<PathBuf>,
+
+    /// Start in plaintext and apply --ssl and --ssl-server once the protocol
+    /// upgrades: smtp, imap, postgres, or manual to upgrade when the client starts a handshake
+    #[structopt(long, requires_all = &["ssl", "ssl-server"])]
+    starttls: Option<StartTls>,
+
+    /// Log the SNI and ALPN requested by TLS clients without terminating TLS
+    #[structopt(long, conflicts_with = "ssl-server")]
+    sniff_sni: bool,
+
+    /// Log the JA3 fingerprint of TLS ClientHellos, both when passing TLS through
+    /// and when terminating it with --ssl-server
+    #[structopt(long)]
+    ja3: bool,
+
+    /// Forward TLS clients whose SNI matches <pattern> (exact or *.domain) to
+    /// <host>:<port> instead, as sni=<pattern>:<host>:<port>; can be repeated
+    #[structopt(long, number_of_values = 1, conflicts_with = "ssl-server")]
+    route: Vec<Route>,
+
+    /// Log a one-line summary of every TLS handshake (implied by --show-data)
+    #[structopt(long)]
+    tls_info: bool,
+
+    /// OpenSSL cipher list for TLS 1.2 and below on either side
+    #[structopt(long)]
+    ciphers: Option<String>,
+
+    /// OpenSSL TLS 1.3 ciphersuites on either side
+    #[structopt(long)]
+    ciphersuites: Option<String>,
+
+    /// Verify the upstream certificate when using --ssl
+    #[structopt(long, requires = "ssl")]
+    verify_upstream: bool,
+
+    /// PEM CA bundle to verify the upstream against instead of the system store
+    #[structopt(long, requires = "verify-upstream")]
+    upstream_ca: Option<PathBuf>,
+
+    /// Base64 SHA-256 of an upstream public key (SPKI) to accept, can be repeated
+    #[structopt(long, requires = "ssl", number_of_values = 1, parse(try_from_str = parse_pin))]
+    pin_sha256: Vec<[u8; 32]>,
+
+    /// Save each distinct upstream certificate chain as PEM into this directory
+    #[structopt(long, requires = "ssl")]
+    save_certs: Option<PathBuf>,
+
+    /// PEM certificate chain to present to the upstream when using --ssl
+    #[structopt(long, requires_all = &["client-key", "ssl"])]
+    client_cert: Option<PathBuf>,
+
+    /// PEM private key for --client-cert
+    #[structopt(long, requires = "client-cert")]
+    client_key: Option<PathBuf>,
+
+    #[structopt(long)]
+    ssl_server: bool,
+
+    /// PEM certificate chain to serve with --ssl-server instead of a self-signed one
+    #[structopt(long, requires_all = &["key", "ssl-server"])]
+    cert: Option<PathBuf>,
+
+    /// PEM private key for --cert
+    #[structopt(long, requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Directory in which to keep the generated server certificate between runs
+    #[structopt(long, conflicts_with = "cert")]
+    cert_cache_dir: Option<PathBuf>,
+
+    /// Ask TLS clients for a certificate and log it
+    #[structopt(long, requires = "ssl-server")]
+    request_client_cert: bool,
+
+    /// Reject TLS clients that don't present a certificate
+    #[structopt(long, requires = "ssl-server")]
+    require_client_cert: bool,
+
+    /// PEM CA bundle that client certificates must chain to
+    #[structopt(long, requires = "require-client-cert")]
+    client_ca: Option<PathBuf>,
+
+    /// PEM CA certificate used to sign generated server certificates
+    #[structopt(long, requires_all = &["ca-key", "ssl-server"])]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM private key for --ca-cert
+    #[structopt(long, requires = "ca-cert")]
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
+    ca_key: Option<PathBuf>,
+
+    /// Generate a certificate matching the SNI of each incoming TLS connection
+    #[structopt(long, requires = "ssl-server")]
+    dynamic_certs: bool,
+
+    /// Maximum number of generated per-SNI certificates to keep
+    #[structopt(long, default_value = "1000")]
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
+    dynamic_cert_cache_size: usize,
+
+    /// Extra subject alternative name for the generated server certificate
+    #[structopt(long, number_of_values = 1)]
+    san: Vec<String>,
+
+    #[structopt(long, default_value = "0.0.0.0")]
+    listen_addr: IpAddr,
+
+    #[structopt(long, default_value = "7777")]
+    listen_port: u16,
+
+    /// Listen on both 0.0.0.0 and [::] instead of --listen-addr
+    #[structopt(long, conflicts_with = "listen-addr")]
+    dual_stack: bool,
+
+    /// Relay UDP datagrams instead of TCP connections
+    #[structopt(long)]
+    udp: bool,
+
+    /// Seconds without traffic after which a UDP session is forgotten
+    #[structopt(long, default_value = "60")]
+    udp_timeout: u64,
+
+    /// Listen on a Unix domain socket instead of a TCP port
+    #[structopt(long, conflicts_with_all = &["listen-addr", "listen-port", "dual-stack"])]
+    listen_unix: Option<PathBuf>,
+
+    /// Upstream port, unless the hostname has one; defaults to 80, 443 with --ssl or
+    /// that of the --starttls protocol
+    #[structopt(long)]
+    host_port: Option<u16>,
+
+    #[structopt(long)]
+    show_data: bool,
+
+    /// Log a single line when each connection ends instead of one for each chunk read
+    #[structopt(long, conflicts_with = "show-data")]
+    summary_only: bool,
+
+    /// Log more: -v adds a line for each chunk read, -vv everything
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Log less: -q only warnings and errors, -qq only errors
+    #[structopt(short, long, parse(from_occurrences), conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Print log lines as text, or as one JSON object per line
+    #[structopt(long, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Seconds to wait for the upstream to accept a connection, and for the TLS
+    /// handshake with it with --ssl, including all --connect-retries, for each
+    /// upstream tried; 0 waits as long as the OS does
+    #[structopt(long, default_value = "10")]
+    connect_timeout: u64,
+
+    /// Retry connecting to the upstream this many times, for as long as
+    /// --connect-timeout allows
+    #[structopt(long, default_value = "0")]
+    connect_retries: u32,
+
+    /// Milliseconds to wait before the first retry of --connect-retries, doubled
+    /// for each one after it
+    #[structopt(long, default_value = "100")]
+    retry_backoff: u64,
+
+    /// Seconds without data forwarded either way after which a connection is
+    /// closed; 0 keeps idle connections open
+    #[structopt(long, default_value = "0")]
+    idle_timeout: u64,
+
+    /// Seconds after which a connection is closed however active it is, counting
+    /// from when it was accepted; 0 means no limit
+    #[structopt(long, default_value = "0")]
+    max_connection_age: u64,
+
+    /// Handle at most this many connections at once; 0 means no limit
+    #[structopt(long, default_value = "0")]
+    max_connections: usize,
+
+    /// What to do with connections beyond --max-connections: queue leaves them
+    /// waiting to be accepted, reject closes them right away
+    #[structopt(long, default_value = "queue")]
+    overflow: Overflow,
+
+    /// Seconds to wait for open connections to finish when stopping
+    #[structopt(long, default_value = "10")]
+    drain_timeout: u64,
+
+    /// Write the raw bytes read from each side of each connection to
+    /// conn-<i>-in.bin and conn-<i>-out.bin in this directory, decrypted with --ssl-server
+    #[structopt(long)]
+    dump_dir: Option<PathBuf>,
+
+    /// Write the forwarded data to this pcapng file as one fake TCP stream per connection
+    #[structopt(long)]
+    pcap: Option<PathBuf>,
+
+    /// Record the HTTP requests and responses of all connections to this HAR file
+    #[structopt(long)]
+    har: Option<PathBuf>,
+
+    /// Include up to this many bytes of each body in the --har file
+    #[structopt(long, default_value = "0")]
+    har_max_body: usize,
+
+    /// Append a line in Combined Log Format to this file for each HTTP request and
+    /// response, on connections whose HTTP headers are rewritten
+    #[structopt(long)]
+    access_log: Option<PathBuf>,
+
+    /// Also write log lines to this file, or - for only printing them
+    #[structopt(long)]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the --log-file once it would grow beyond this many bytes
+    #[structopt(long, requires = "log-file")]
+    log_max_size: Option<u64>,
+
+    /// How many rotated log files to keep as <log-file>.1, <log-file>.2, ...
+    #[structopt(long, default_value = "5")]
+    log_keep: usize,
+
+    /// Prefix log lines with the time: rfc3339, or relative for seconds since start
+    #[structopt(long)]
+    timestamps: Option<Timestamps>,
+
+    /// Color log lines by direction and connection: auto, always or never
+    #[structopt(long, default_value = "auto")]
+    color: ColorChoice,
+
+    /// How --show-data prints each chunk: text, or hex for an xxd-style dump
+    #[structopt(long, default_value = "text")]
+    data_format: DataFormat,
+
+    /// Print data that looks binary with --show-data instead of a placeholder
+    #[structopt(long, requires = "show-data")]
+    force_text: bool,
+
+    /// Print at most this many bytes of each chunk with --show-data
+    #[structopt(long, requires = "show-data")]
+    max_show_bytes: Option<usize>,
+
+    /// Also print gzip, deflate and br compressed HTTP response bodies
+    /// decompressed with --show-data
+    #[structopt(long, requires = "show-data")]
+    decode_bodies: bool,
+
+    /// Stop printing data for a connection after this many bytes with --show-data
+    #[structopt(long, requires = "show-data")]
+    max_show_total: Option<usize>,
+
+    #[structopt(long)]
+    rewrite_host_header: bool,
+
+    /// Rewrite the Host header to this value instead of the upstream, or remove it
+    /// if empty. Implies --rewrite-host-header
+    #[structopt(long)]
+    host_header: Option<String>,
+
+    /// Add X-Forwarded-For, X-Forwarded-Proto and Forwarded headers naming the
+    /// client. Implies --rewrite-host-header
+    #[structopt(long)]
+    add_forwarded: bool,
+
+    /// Remove this header from requests; can be repeated. Implies
+    /// --rewrite-host-header
+    #[structopt(long, number_of_values = 1)]
+    remove_header: Vec<String>,
+
+    /// Set a request header, given as "Name: value", replacing it if present; can
+    /// be repeated. Implies --rewrite-host-header
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_header))]
+    set_header: Vec<(String, String)>,
+
+    /// Authenticate requests with HTTP Basic auth as "user:password", replacing
+    /// the client's Authorization header. Applied after --remove-header and before
+    /// --set-header. Implies --rewrite-host-header
+    #[structopt(long, parse(from_str = basic_authorization))]
+    basic_auth: Option<String>,
+
+    /// Keep the Authorization header of requests that have one with --basic-auth
+    #[structopt(long, requires = "basic-auth")]
+    basic_auth_keep_existing: bool,
+
+    /// Replace the prefix of request paths, given as "/old-prefix=/new-prefix";
+    /// can be repeated, the longest matching prefix is replaced. Implies
+    /// --rewrite-host-header
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_path_rewrite))]
+    rewrite_path: Vec<(String, String)>,
+
+    /// Also rewrite Origin and Referer headers naming the host the client
+    /// connected to. Implies --rewrite-host-header
+    #[structopt(long)]
+    rewrite_origin: bool,
+
+    /// Rewrite Location headers in responses that point at the upstream to point
+    /// at the proxy instead
+    #[structopt(long)]
+    rewrite_location: bool,
+
+    /// Set the Domain of cookies the upstream sets to this, or remove it if empty.
+    /// With --rewrite-host-header it is removed by default
+    #[structopt(long)]
+    rewrite_cookie_domain: Option<String>,
+
+    /// Remove Strict-Transport-Security headers and upgrade-insecure-requests
+    /// Content-Security-Policy directives from responses
+    #[structopt(long)]
+    strip_hsts: bool,
+
+    /// Answer requests whose "METHOD path" matches this regex with --block-status
+    /// instead of forwarding them; can be repeated
+    #[structopt(long, number_of_values = 1)]
+    block: Vec<Regex>,
+
+    /// Answer requests for this path with a canned response instead of forwarding
+    /// them, given as "/path=STATUS[:BODY]"; a path ending in * matches any path
+    /// starting with the rest. Can be repeated
+    #[structopt(long, number_of_values = 1)]
+    stub: Vec<Stub>,
+
+    /// Add a header to the --stub responses for a path, given as "/path=Name: value";
+    /// can be repeated
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_stub_header))]
+    stub_header: Vec<(String, (String, String))>,
+
+    /// The status of responses to requests matching --block
+    #[structopt(long, default_value = "403", parse(try_from_str = parse_block_status))]
+    block_status: u16,
+
+    /// Replace this byte sequence in the forwarded data, given as "from=to"; can be
+    /// repeated. Replacements that change the length break protocols framing
+    /// messages by length, so they aren't allowed when HTTP messages are parsed
+    #[structopt(long, number_of_values = 1, parse(try_from_str = replace::parse_text))]
+    replace: Vec<Replacement>,
+
+    /// Like --replace with the byte sequences given as hex digits
+    #[structopt(long, number_of_values = 1, parse(try_from_str = replace::parse_hex))]
+    replace_hex: Vec<Replacement>,
+
+    /// Which data --replace applies to: incoming, outgoing or both
+    #[structopt(long, default_value = "both")]
+    replace_direction: Directions,
+
+    /// Pipe the data read from each connection through this shell command before
+    /// forwarding it, run once per connection and direction with
+    /// TCP_PROXY_DIRECTION set to incoming or outgoing
+    #[structopt(long)]
+    filter_cmd: Option<String>,
+
+    /// Which data --filter-cmd applies to: incoming, outgoing or both
+    #[structopt(long, default_value = "both")]
+    filter_direction: Directions,
+
+    /// Forward the rest of the data unfiltered if the --filter-cmd exits before it
+    /// ended, instead of failing the connection
+    #[structopt(long, requires = "filter-cmd")]
+    filter_fallback: bool,
+
+    /// Forward messages whose header is longer than this many bytes without
+    /// rewriting it
+    #[structopt(long, default_value = "65536")]
+    max_header_size: usize,
+
+    /// How many bytes to read at once in each direction, from 1024 to 16777216.
+    /// Every open connection holds two buffers of this size
+    #[structopt(long, default_value = "65536", parse(try_from_str = parse_buffer_size))]
+    buffer_size: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TlsVersion {
+    Tls1_0,
+    Tls1_1,
+    Tls1_2,
+    Tls1_3,
+}
+
+impl FromStr for TlsVersion {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.0" => Ok(TlsVersion::Tls1_0),
+            "1.1" => Ok(TlsVersion::Tls1_1),
+            "1.2" => Ok(TlsVersion::Tls1_2),
+            "1.3" => Ok(TlsVersion::Tls1_3),
+            _ => Err("expected one of 1.0, 1.1, 1.2 or 1.3"),
+        }
+    }
+}
+
+/// What happens to connections beyond --max-connections.
+#[derive(Clone, Copy)]
+enum Overflow {
+    /// Close them right away.
+    Reject,
+    /// Leave them unaccepted until a connection closes.
+    Queue,
+}
+
+impl FromStr for Overflow {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Overflow::Reject),
+            "queue" => Ok(Overflow::Queue),
+            _ => Err("expected reject or queue"),
+        }
+    }
+}
+
+/// Where a connection is forwarded to.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    Tcp(&'a str, u16),
+    Unix(&'a Path),
+}
+
+impl fmt::Display for Target<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Tcp(host, port) if host.contains(':') => write!(f, "[{host}]:{port}"),
+            Target::Tcp(host, port) => write!(f, "{host}:{port}"),
+            Target::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Another upstream given with --upstream, forwarded to on the port of the
+/// hostname unless it has its own.
+#[derive(Clone)]
+struct Upstream {
+    host: String,
+    port: Option<u16>,
+}
+
+impl FromStr for Upstream {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest
+                    .split_once(']')
+                    .ok_or("expected ] after an IPv6 host")?;
+                match rest {
+                    "" => (host, None),
+                    _ => (
+                        host,
+                        Some(rest.strip_prefix(':').ok_or("expected :<port>")?),
+                    ),
+                }
+            }
+            // A bare IPv6 address can't have a port.
+            None if s.matches(':').count() > 1 => (s, None),
+            None => match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            },
+        };
+        if host.is_empty() {
+            return Err("expected a host");
+        }
+        Ok(Upstream {
+            host: host.to_string(),
+            port: port
+                .map(str::parse)
+                .transpose()
+                .map_err(|_| "invalid port")?,
+        })
+    }
+}
+
+/// A proxy that TCP upstreams are reached through, with --socks5 or --http-proxy.
+#[derive(Clone, Copy)]
+enum Tunnel<'a> {
+    Socks5(Socks5<'a>),
+    Http(HttpProxy<'a>),
+}
+
+/// An upstream chosen by the SNI of a passed-through TLS connection.
+#[derive(Clone)]
+struct Route {
+    pattern: String,
+    host: String,
+    port: u16,
+}
+
+impl Route {
+    fn target(&self) -> Target<'_> {
+        Target::Tcp(&self.host, self.port)
+    }
+
+    fn matches(&self, server_name: &str) -> bool {
+        host_matches(&self.pattern, server_name)
+    }
+}
+
+/// Matches exactly, or any single label in place of a leading `*.`.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(suffix)),
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// A canned response for requests to a path, given with --stub.
+#[derive(Clone)]
+struct Stub {
+    path: String,
+    status: u16,
+    body: String,
+    /// Added with --stub-header once the options were parsed.
+    headers: Vec<(String, String)>,
+}
+
+impl Stub {
+    /// Matches the path without its query, exactly or by prefix with a trailing `*`.
+    fn matches(&self, path: &str) -> bool {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        }
+    }
+}
+
+impl FromStr for Stub {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const USAGE: &str = "expected /path=STATUS[:BODY]";
+        let (path, response) = s.split_once('=').ok_or(USAGE)?;
+        let (status, body) = response.split_once(':').unwrap_or((response, ""));
+        if !path.starts_with('/') {
+            return Err(USAGE);
+        }
+        let status = match status.parse() {
+            Ok(204 | 304) if !body.is_empty() => {
+                return Err("responses with status 204 or 304 have no body")
+            }
+            Ok(status @ 200..=599) => status,
+            _ => return Err("expected a status between 200 and 599 in --stub"),
+        };
+        Ok(Stub {
+            path: path.to_string(),
+            status,
+            body: body.to_string(),
+            headers: vec![],
+        })
+    }
+}
+
+impl FromStr for Route {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const USAGE: &str = "expected sni=<pattern>:<host>:<port>";
+        let rule = s.strip_prefix("sni=").ok_or(USAGE)?;
+        let (pattern, target) = rule.split_once(':').ok_or(USAGE)?;
+        let (host, port) = target.rsplit_once(':').ok_or(USAGE)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if pattern.is_empty() || host.is_empty() {
+            return Err(USAGE);
+        }
+        Ok(Route {
+            pattern: pattern.to_string(),
+            host: host.to_string(),
+            port: port.parse().map_err(|_| "invalid port in --route")?,
+        })
+    }
+}
+
+fn parse_alpn_protocol(protocol: &str) -> Result<String, String> {
+    if protocol.is_empty() || protocol.len() > 255 {
+        return Err("ALPN protocol names must be between 1 and 255 bytes".to_string());
+    }
+    Ok(protocol.to_string())
+}
+
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value))
+            if httparse::parse_headers(
+                format!("{name}:{value}\r\n\r\n").as_bytes(),
+                &mut [httparse::EMPTY_HEADER],
+            )
+            .is_ok_and(|status| status.is_complete()) =>
+        {
+            Ok((name.to_string(), value.trim().to_string()))
+        }
+        _ => Err("expected a header as \"Name: value\"".to_string()),
+    }
+}
+
+fn parse_bind_source(source: &str) -> Result<SocketAddr, String> {
+    let ip = source.trim_start_matches('[').trim_end_matches(']');
+    source
+        .parse()
+        .or_else(|_| ip.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+        .map_err(|_| "expected an address as ip[:port]".to_string())
+}
+
+fn parse_buffer_size(size: &str) -> Result<usize, String> {
+    match size.parse() {
+        Ok(size @ 1024..=16777216) => Ok(size),
+        _ => Err("expected a size between 1024 and 16777216 bytes".to_string()),
+    }
+}
+
+fn parse_block_status(status: &str) -> Result<u16, String> {
+    match status.parse() {
+        Ok(status @ 400..=599) => Ok(status),
+        _ => Err("expected a status between 400 and 599".to_string()),
+    }
+}
+
+fn parse_stub_header(rule: &str) -> Result<(String, (String, String)), String> {
+    match rule.split_once('=') {
+        Some((path, header)) if path.starts_with('/') => {
+            Ok((path.to_string(), parse_header(header)?))
+        }
+        _ => Err("expected a stub header as \"/path=Name: value\"".to_string()),
+    }
+}
+
+fn parse_path_rewrite(rewrite: &str) -> Result<(String, String), String> {
+    match rewrite.split_once('=') {
+        Some((from, to)) if from.starts_with('/') && to.starts_with('/') => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err("expected a path rewrite as \"/old-prefix=/new-prefix\"".to_string()),
+    }
+}
+
+/// The Authorization header value for --basic-auth, encoded once at startup.
+fn basic_authorization(credentials: &str) -> String {
+    format!("Basic {}", STANDARD.encode(credentials))
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+    STANDARD
+        .decode(pin)
+        .ok()
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or_else(|| "expected the base64 encoded SHA-256 of a public key".to_string())
+}
+
+impl Opt {
+    fn host_port(&self) -> u16 {
+        let default_port = match self.starttls.and_then(StartTls::default_port) {
+            Some(port) => port,
+            None if self.ssl => 443,
+            None => 80,
+        };
+        self.host_port.unwrap_or(default_port)
+    }
+
+    fn unix_target(&self) -> Option<&Path> {
+        self.hostname.strip_prefix("unix:").map(Path::new)
+    }
+
+    fn target(&self) -> String {
+        match self.unix_target() {
+            Some(path) => format!("unix:{}", path.display()),
+            None => format!("{}:{}", self.hostname, self.host_port()),
+        }
+    }
+
+    /// These options with `host`:`port` as the upstream, for a connection that
+    /// asked for it with --socks5-server.
+    fn with_destination(&self, host: String, port: u16) -> Opt {
+        Opt {
+            hostname: host,
+            host_port: Some(port),
+            ..self.clone()
+        }
+    }
+
+    /// Which family --prefer-ipv4 or --prefer-ipv6 puts first, as whether it is IPv6.
+    fn prefer_ipv6(&self) -> Option<bool> {
+        match (self.prefer_ipv4, self.prefer_ipv6) {
+            (true, _) => Some(false),
+            (_, true) => Some(true),
+            _ => None,
+        }
+    }
+
+    /// The addresses to connect to for `host` instead of looking it up.
+    fn resolved(&self, host: &str) -> Option<&[IpAddr]> {
+        self.resolved
+            .iter()
+            .find(|(name, _)| name == host)
+            .map(|(_, ips)| ips.as_slice())
+    }
+
+    /// `target` with the addresses it was resolved to at startup, if it was.
+    fn describe(&self, target: Target) -> String {
+        match target {
+            Target::Tcp(host, _) => match self.resolved(host) {
+                Some(ips) => {
+                    let ips: Vec<_> = ips.iter().map(IpAddr::to_string).collect();
+                    format!("{target} ({})", ips.join(", "))
+                }
+                None => target.to_string(),
+            },
+            Target::Unix(_) => target.to_string(),
+        }
+    }
+
+    fn tunnel(&self) -> Option<Tunnel<'_>> {
+        if let Some(server) = &self.http_proxy {
+            return Some(Tunnel::Http(HttpProxy {
+                server,
+                authorization: self.http_proxy_auth.as_deref(),
+            }));
+        }
+        self.socks5.as_ref().map(|server| {
+            Tunnel::Socks5(Socks5 {
+                server,
+                credentials: self.socks5_auth.as_ref(),
+            })
+        })
+    }
+
+    /// The hostname and each --upstream, which connections take turns between.
+    fn targets(&self) -> Vec<Target<'_>> {
+        let first = match self.unix_target() {
+            Some(path) => Target::Unix(path),
+            None => Target::Tcp(&self.hostname, self.host_port()),
+        };
+        let upstreams = self
+            .upstream
+            .iter()
+            .map(|upstream| Target::Tcp(&upstream.host, upstream.port.unwrap_or(self.host_port())));
+        std::iter::once(first).chain(upstreams).collect()
+    }
+
+    /// Whether Host headers are rewritten to name the upstream, which --transparent
+    /// only knows by address, so it leaves them unless --host-header is given.
+    fn rewrites_host(&self) -> bool {
+        !self.transparent || self.host_header.is_some()
+    }
+
+    /// Whether the client's requests are parsed to rewrite their headers.
+    fn rewrite_http(&self) -> bool {
+        self.rewrite_host_header
+            || self.host_header.is_some()
+            || self.rewrite_origin
+            || self.add_forwarded
+            || !self.remove_header.is_empty()
+            || !self.set_header.is_empty()
+            || self.basic_auth.is_some()
+            || !self.rewrite_path.is_empty()
+    }
+
+    /// The domain cookies the upstream sets get instead of theirs, or empty to
+    /// remove it.
+    fn cookie_domain(&self) -> Option<&str> {
+        match &self.rewrite_cookie_domain {
+            Some(domain) => Some(domain),
+            None => self.rewrite_http().then_some(""),
+        }
+    }
+
+    /// Whether the upstream's responses are parsed to rewrite their headers.
+    fn rewrite_responses(&self) -> bool {
+        self.rewrite_location || self.cookie_domain().is_some() || self.strip_hsts
+    }
+
+    /// Whether the HTTP messages on each connection are parsed.
+    fn parse_http(&self) -> bool {
+        self.rewrite_http()
+            || self.rewrite_responses()
+            || !self.block.is_empty()
+            || !self.stub.is_empty()
+    }
+
+    /// The --replace and --replace-hex replacements.
+    fn replacements(&self) -> Vec<Replacement> {
+        self.replace
+            .iter()
+            .chain(&self.replace_hex)
+            .cloned()
+            .collect()
+    }
+
+    /// The listening address, unless it doesn't name a single host.
+    fn listen_authority(&self) -> Option<String> {
+        let listening = self.listen_unix.is_none() && !self.dual_stack;
+        (listening && !self.listen_addr.is_unspecified())
+            .then(|| SocketAddr::from((self.listen_addr, self.listen_port)).to_string())
+    }
+
+    /// The --host-header, or the upstream as named in a Host header, with the port
+    /// unless it is the default one for HTTP or, with --ssl, HTTPS.
+    fn host_header_value(&self) -> String {
+        if let Some(host) = &self.host_header {
+            return host.clone();
+        }
+        if let Some(path) = self.unix_target() {
+            return path.to_str().unwrap_or(&self.hostname).to_string();
+        }
+        let default_port = if self.ssl { 443 } else { 80 };
+        match self.host_port() {
+            port if port == default_port => self.hostname.clone(),
+            port if self.hostname.contains(':') => format!("[{}]:{port}", self.hostname),
+            port => format!("{}:{port}", self.hostname),
+        }
+    }
+
+    fn listen_addrs(&self) -> Vec<SocketAddr> {
+        if self.dual_stack {
+            vec![
+                (Ipv4Addr::UNSPECIFIED, self.listen_port).into(),
+                (Ipv6Addr::UNSPECIFIED, self.listen_port).into(),
+            ]
+        } else {
+            vec![(self.listen_addr, self.listen_port).into()]
+        }
+    }
+}
+
+/// Flips whether payloads are logged each time the proxy receives SIGUSR1.
+async fn toggle_show_data(mut sigusr1: Signal) {
+    while sigusr1.recv().await.is_some() {
+        let state = if logging::toggle_show_data() {
+            "on"
+        } else {
+            "off"
+        };
+        info!(parent: None, "Received SIGUSR1, turned --show-data {state}");
+    }
+}
+
+/// Lists the connections being handled each time the proxy receives SIGUSR2.
+async fn list_connections(mut sigusr2: Signal, stats: Arc<Stats>) {
+    while sigusr2.recv().await.is_some() {
+        stats.list_connections();
+    }
+}
+
+/// Resolves when the proxy is asked to stop with Ctrl-C or SIGTERM.
+async fn stop_requested(sigterm: &mut Signal) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+async fn join_all(tasks: &mut JoinSet<()>) {
+    while tasks.join_next().await.is_some() {}
+}
+
+/// The options --udp uses, which are all that may be given with it so that options
+/// for TCP connections aren't silently ignored.
+const UDP_OPTIONS: &[&str] = &[
+    "hostname",
+    "udp",
+    "udp-timeout",
+    "host-port",
+    "listen-addr",
+    "listen-port",
+    "show-data",
+    "summary-only",
+    "verbose",
+    "quiet",
+    "log-format",
+    "log-file",
+    "log-max-size",
+    "log-keep",
+    "timestamps",
+    "color",
+    "data-format",
+    "force-text",
+    "max-show-bytes",
+    "max-show-total",
+    "dump-dir",
+];
+
+/// A proxy configured like on the command line, to run it in-process.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let proxy = tcp_proxy::Proxy::new("example.com")
+///     .listen_port(0)
+///     .rewrite_host_header(true)
+///     .spawn()
+///     .await?;
+/// println!("Proxying on {}", proxy.local_addr().unwrap());
+/// proxy.shutdown().await
+/// # }
+/// ```
+pub struct Proxy {
+    opt: Opt,
+}
+
+impl From<Opt> for Proxy {
+    fn from(opt: Opt) -> Self {
+        Proxy { opt }
+    }
+}
+
+impl Proxy {
+    /// A proxy to `target` with the default options, which are those of the
+    /// command line.
+    pub fn new(target: &str) -> Self {
+        // Parsing the target as an argument could make it a flag, like --help.
+        let mut opt = Opt::from_iter_safe(["tcp-proxy"]).expect("no arguments are valid ones");
+        opt.hostname = target.to_string();
+        Self::from(opt)
+    }
+
+    /// A proxy configured by command line arguments, starting with the program
+    /// name.
+    pub fn from_args<I>(args: I) -> Result<Self, structopt::clap::Error>
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = Opt::clap().get_matches_from_safe(args)?;
+        if matches.is_present("udp") {
+            let mut unused: Vec<_> = matches
+                .args
+                .keys()
+                .filter(|name| matches.occurrences_of(name) > 0 && !UDP_OPTIONS.contains(name))
+                .collect();
+            unused.sort();
+            if let Some(name) = unused.first() {
+                return Err(structopt::clap::Error::with_description(
+                    &format!("The argument '--{name}' cannot be used with '--udp'"),
+                    structopt::clap::ErrorKind::ArgumentConflict,
+                ));
+            }
+        }
+        Ok(Self::from(Opt::from_clap(&matches)))
+    }
+
+    pub fn host_port(mut self, port: u16) -> Self {
+        self.opt.host_port = Some(port);
+        self
+    }
+
+    pub fn listen_addr(mut self, addr: IpAddr) -> Self {
+        self.opt.listen_addr = addr;
+        self
+    }
+
+    /// The port to listen on, or 0 to pick a free one.
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.opt.listen_port = port;
+        self
+    }
+
+    pub fn ssl(mut self, ssl: bool) -> Self {
+        self.opt.ssl = ssl;
+        self
+    }
+
+    pub fn ssl_server(mut self, ssl_server: bool) -> Self {
+        self.opt.ssl_server = ssl_server;
+        self
+    }
+
+    pub fn rewrite_host_header(mut self, rewrite: bool) -> Self {
+        self.opt.rewrite_host_header = rewrite;
+        self
+    }
+
+    pub fn show_data(mut self, show_data: bool) -> Self {
+        self.opt.show_data = show_data;
+        self
+    }
+
+    /// How many seconds to wait for the upstream to accept a connection, or 0 for
+    /// no limit.
+    pub fn connect_timeout(mut self, seconds: u64) -> Self {
+        self.opt.connect_timeout = seconds;
+        self
+    }
+
+    /// How many times connecting to the upstream is retried before giving up.
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.opt.connect_retries = retries;
+        self
+    }
+
+    /// How many seconds a connection may forward nothing before it is closed, or 0
+    /// to keep it open.
+    pub fn idle_timeout(mut self, seconds: u64) -> Self {
+        self.opt.idle_timeout = seconds;
+        self
+    }
+
+    /// How many seconds after it was accepted a connection is closed, or 0 for no
+    /// limit.
+    pub fn max_connection_age(mut self, seconds: u64) -> Self {
+        self.opt.max_connection_age = seconds;
+        self
+    }
+
+    /// How many connections are handled at once, or 0 for no limit. Those beyond it
+    /// wait to be accepted.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.opt.max_connections = max;
+        self
+    }
+
+    /// How many seconds `ProxyHandle::shutdown` waits for open connections.
+    pub fn drain_timeout(mut self, seconds: u64) -> Self {
+        self.opt.drain_timeout = seconds;
+        self
+    }
+
+    /// Checks the options that depend on each other and merges --stub-header into
+    /// the stubs.
+    fn prepare(mut self) -> Result<Arc<Opt>> {
+        let opt = &mut self.opt;
+        for (path, header) in std::mem::take(&mut opt.stub_header) {
+            let Some(stub) = opt.stub.iter_mut().find(|stub| stub.path == path) else {
+                bail!("--stub-header for {path}, which has no --stub");
+            };
+            stub.headers.push(header);
+        }
+        let resizing = opt
+            .replacements()
+            .into_iter()
+            .any(|replacement| replacement.from.len() != replacement.to.len());
+        if resizing && opt.parse_http() {
+            bail!("--replace can't change the length of the data when HTTP messages are parsed");
+        }
+        if !opt.hostname.is_empty() && opt.unix_target().is_none() {
+            let target: Upstream = opt
+                .hostname
+                .parse()
+                .map_err(|e| anyhow!("Invalid hostname {}: {e}", opt.hostname))?;
+            if let Some(port) = target.port {
+                if opt.host_port.is_some() {
+                    bail!("The hostname has a port, so --host-port can't be given too");
+                }
+                opt.host_port = Some(port);
+            }
+            opt.hostname = target.host;
+        }
+        match (opt.socks5_server, opt.transparent, opt.hostname.is_empty()) {
+            (false, false, true) => bail!(
+                "The hostname to forward to is required without --socks5-server or --transparent"
+            ),
+            (true, _, false) => {
+                bail!("--socks5-server forwards where clients ask, so it takes no hostname")
+            }
+            (_, true, false) => bail!(
+                "--transparent forwards where connections were originally for, so it takes no hostname"
+            ),
+            _ => {}
+        }
+        if opt.transparent && !cfg!(target_os = "linux") {
+            bail!("--transparent needs SO_ORIGINAL_DST, which only Linux has");
+        }
+        if !(0.0..=1.0).contains(&opt.fault_drop_rate) {
+            bail!("--fault-drop-rate must be between 0 and 1");
+        }
+        if opt.fault_reset && opt.fault_drop_rate == 0.0 && opt.fault_abort_after.is_none() {
+            bail!("--fault-reset needs --fault-drop-rate or --fault-abort-after to fire a fault");
+        }
+        if opt.fragment == Some(0) {
+            bail!("--fragment must be at least 1 byte");
+        }
+        let rates = [opt.rate_limit, opt.rate_limit_up, opt.rate_limit_down];
+        if rates.contains(&Some(0)) {
+            bail!("--rate-limit must be at least 1 byte per second");
+        }
+        if opt.health_check && opt.health_check_interval == 0 {
+            bail!("--health-check-interval must be at least 1");
+        }
+        if opt.use_env_proxy && opt.http_proxy.is_none() && opt.socks5.is_none() {
+            match http_proxy::from_env()? {
+                Some(EnvProxy::Http(server, authorization)) => {
+                    opt.http_proxy = Some(server);
+                    opt.http_proxy_auth = authorization;
+                }
+                Some(EnvProxy::Socks5(server, credentials)) => {
+                    opt.socks5 = Some(server);
+                    opt.socks5_auth = credentials;
+                }
+                None => {}
+            }
+            let direct = opt.bind_source.is_some() || !opt.resolve.is_empty() || opt.resolve_once;
+            if direct && opt.tunnel().is_some() {
+                bail!(
+                    "--bind-source, --resolve and --resolve-once can't be used with the proxy \
+                     --use-env-proxy found"
+                );
+            }
+        }
+        if !opt.resolve.is_empty() {
+            opt.resolved = vec![(opt.hostname.clone(), opt.resolve.clone())];
+        } else if opt.resolve_once {
+            opt.resolved = resolve_targets(opt)?;
+        }
+        Ok(Arc::new(self.opt))
+    }
+
+    /// Sets up what all connections share, which only the first proxy in a
+    /// process gets to do for logging. The capture files are opened once too, so
+    /// later proxies can only ask for the ones already being written.
+    fn open_outputs(opt: &Opt) -> Result<()> {
+        // Proxies spawned at the same time would both try to install the logger.
+        static LOGGING: Mutex<()> = Mutex::new(());
+        static CAPTURES: Mutex<Vec<(&str, PathBuf)>> = Mutex::new(Vec::new());
+        let _logging = LOGGING.lock().unwrap();
+        if !tracing::dispatcher::has_been_set() {
+            logging::init(opt)?;
+        }
+        if let Some(dir) = &opt.dump_dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let captures = [
+            ("--pcap", &opt.pcap),
+            ("--har", &opt.har),
+            ("--access-log", &opt.access_log),
+        ];
+        let mut captured = CAPTURES.lock().unwrap();
+        for (option, path) in captures {
+            let Some(path) = path else { continue };
+            if let Some((_, open)) = captured.iter().find(|(name, _)| *name == option) {
+                if open != path {
+                    bail!(
+                        "{option} {} can't be used, as another proxy in this process writes {}",
+                        path.display(),
+                        open.display()
+                    );
+                }
+            }
+        }
+        pcap::open(opt)?;
+        har::open(opt)?;
+        access_log::open(opt)?;
+        for (option, path) in captures {
+            let Some(path) = path else { continue };
+            if !captured.iter().any(|(name, _)| *name == option) {
+                captured.push((option, path.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts listening and handling connections in the background.
+    pub async fn spawn(self) -> Result<ProxyHandle> {
+        let opt = self.prepare()?;
+        Self::open_outputs(&opt)?;
+        spawn(opt)
+    }
+
+    /// Runs the proxy like the command line does, until Ctrl-C or SIGTERM, and
+    /// reports how it went.
+    pub async fn run(self) -> Result<()> {
+        let opt = self.prepare()?;
+        Self::open_outputs(&opt)?;
+        tokio::spawn(toggle_show_data(signal(SignalKind::user_defined1())?));
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        if opt.udp {
+            let result = tokio::select! {
+                result = udp::run(opt) => result,
+                _ = stop_requested(&mut sigterm) => Ok(()),
+            };
+            logging::flush();
+            return result;
+        }
+
+        let mut proxy = spawn(opt)?;
+        tokio::spawn(list_connections(
+            signal(SignalKind::user_defined2())?,
+            proxy.stats.clone(),
+        ));
+        let stopping = tokio::select! {
+            result = proxy.finished() => {
+                result?;
+                false
+            }
+            _ = stop_requested(&mut sigterm) => true,
+        };
+        if stopping {
+            proxy.stop();
+            tokio::select! {
+                result = proxy.finished() => result?,
+                _ = stop_requested(&mut sigterm) => {}
+            }
+        }
+
+        proxy.stats.report();
+        pcap::flush();
+        har::flush();
+        access_log::flush();
+        logging::flush();
+        Ok(())
+    }
+}
+
+/// A proxy handling connections in the background, stopped when this is dropped.
+pub struct ProxyHandle {
+    local_addrs: Vec<String>,
+    stats: Arc<Stats>,
+    stop: watch::Sender<()>,
+    task: tokio::task::JoinHandle<Result<()>>,
+}
+
+impl ProxyHandle {
+    /// The addresses listened on, or unix:<path> for a Unix domain socket.
+    pub fn local_addrs(&self) -> &[String] {
+        &self.local_addrs
+    }
+
+    /// The first TCP address listened on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.iter().find_map(|addr| addr.parse().ok())
+    }
+
+    /// Stops accepting connections and gives the open ones up to --drain-timeout
+    /// to finish.
+    fn stop(&self) {
+        let _ = self.stop.send(());
+    }
+
+    /// Resolves once the proxy stopped, or failed to accept connections.
+    async fn finished(&mut self) -> Result<()> {
+        (&mut self.task).await?
+    }
+
+    /// Stops the proxy, waiting up to --drain-timeout for the open connections.
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.stop();
+        self.finished().await
+    }
+}
+
+impl Drop for ProxyHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Listens like --listen-* say and handles each connection in a task.
+fn spawn(opt: Arc<Opt>) -> Result<ProxyHandle> {
+    let (listeners, socket_guard) = match &opt.listen_unix {
+        Some(path) => (
+            vec![Listener::bind_unix(path)?],
+            Some(UnixSocketGuard(path.clone())),
+        ),
+        None => (
+            opt.listen_addrs()
+                .into_iter()
+                .map(|addr| Listener::bind_tcp(addr, opt.dual_stack))
+                .collect::<Result<Vec<_>>>()?,
+            None,
+        ),
+    };
+
+    let ssl_acceptor = if opt.ssl_server {
+        Some(Arc::new(generate_acceptor(&opt)?))
+    } else {
+        None
+    };
+    let ssl_connector = if opt.ssl {
+        Some(Arc::new(generate_connector(&opt)?))
+    } else {
+        None
+    };
+
+    let local_addrs = listeners
+        .iter()
+        .map(Listener::local_addr)
+        .collect::<Result<Vec<_>>>()?;
+    for addr in &local_addrs {
+        info!(parent: None, "Listening on {addr}");
+    }
+    if opt.socks5_server {
+        info!(parent: None, "Forwarding SOCKS5 clients to the destinations they ask for");
+    } else if opt.transparent {
+        info!(parent: None, "Forwarding connections to where they were originally for");
+    } else {
+        let targets: Vec<_> = opt
+            .targets()
+            .into_iter()
+            .map(|target| opt.describe(target))
+            .collect();
+        info!(parent: None, "Forwarding to {}", targets.join(", "));
+    }
+
+    let stats = Arc::new(Stats::default());
+    let (stop, stopping) = watch::channel(());
+    let task = tokio::spawn(serve(
+        opt,
+        listeners,
+        socket_guard,
+        stats.clone(),
+        stopping,
+        ssl_acceptor,
+        ssl_connector,
+    ));
+    Ok(ProxyHandle {
+        local_addrs,
+        stats,
+        stop,
+        task,
+    })
+}
+
+/// Accepts connections until `stopping` is set, then waits for the open ones.
+async fn serve(
+    opt: Arc<Opt>,
+    listeners: Vec<Listener>,
+    _socket_guard: Option<UnixSocketGuard>,
+    stats: Arc<Stats>,
+    mut stopping: watch::Receiver<()>,
+    ssl_acceptor: Option<Arc<ssl::Acceptor>>,
+    ssl_connector: Option<Arc<ssl::Connector>>,
+) -> Result<()> {
+    let saved_certs = SavedCerts::default();
+    let balancer = Arc::new(Balancer::new(opt.targets().len()));
+    let mut health_checks = JoinSet::new();
+    health::spawn(
+        &opt,
+        &balancer,
+        ssl_connector.clone(),
+        &saved_certs,
+        &mut health_checks,
+    );
+    let (shutdown, closing) = watch::channel(());
+    let mut tasks = JoinSet::new();
+    let mut i: usize = usize::MAX;
+    let faults = Faults::new(&opt);
+    if opt.fault_drop_rate > 0.0 {
+        info!(
+            parent: None,
+            "Dropping {}% of connections (--fault-drop-rate), repeat with --fault-seed {}",
+            opt.fault_drop_rate * 100.0,
+            faults.seed
+        );
+    }
+    let limit = (opt.max_connections > 0).then(|| Arc::new(Semaphore::new(opt.max_connections)));
+    loop {
+        // Connections beyond --max-connections wait in the listen backlog with
+        // --overflow queue.
+        let queued = match (&limit, opt.overflow) {
+            (Some(limit), Overflow::Queue) => Some(tokio::select! {
+                permit = limit.clone().acquire_owned() => permit?,
+                Some(_) = tasks.join_next() => continue,
+                Ok(()) = stopping.changed() => break,
+            }),
+            _ => None,
+        };
+        let (socket, peer, original, resettable) = tokio::select! {
+            res = accept_any(&listeners, &opt) => res?,
+            Some(_) = tasks.join_next() => continue,
+            Ok(()) = stopping.changed() => break,
+        };
+        let permit = match (queued, &limit) {
+            (Some(permit), _) => Some(permit),
+            (None, Some(limit)) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    stats.reject();
+                    warn!(
+                        parent: None,
+                        "Rejecting connection from {peer}, already handling {} (--max-connections)",
+                        opt.max_connections
+                    );
+                    continue;
+                }
+            },
+            (None, None) => None,
+        };
+        i = i.wrapping_add(1);
+        let dropped = faults.drop_next(&opt);
+        let opt = opt.clone();
+        let ssl_acceptor = ssl_acceptor.clone();
+        let ssl_connector = ssl_connector.clone();
+        let saved_certs = saved_certs.clone();
+        let balancer = balancer.clone();
+        let stats = stats.clone();
+        let mut closing = closing.clone();
+        let span = info_span!("connection", connection = i);
+        tasks.spawn(
+            async move {
+                if !opt.summary_only {
+                    info!(event = "connect", peer = %peer);
+                }
+                pcap::connect(i);
+                let connection = stats.open(i, &peer);
+                let started = Instant::now();
+                let mut data_log = DataLog::new(i);
+                let idle_timeout = Duration::from_secs(opt.idle_timeout);
+                let max_age = tokio::time::sleep(Duration::from_secs(opt.max_connection_age));
+                // Why the proxy closed the connection, if it wasn't one side ending it.
+                let mut reason = None;
+                let result = if dropped {
+                    fault::drop_connection(socket, resettable.as_ref());
+                    reason = Some("fault drop");
+                    Ok(())
+                } else {
+                    tokio::select! {
+                        result = handle_client(
+                            &opt,
+                            &connection,
+                            &mut data_log,
+                            socket,
+                            ssl_acceptor,
+                            ssl_connector,
+                            saved_certs,
+                            &balancer,
+                            original,
+                        ) => result,
+                        _ = connection.idle(idle_timeout), if opt.idle_timeout > 0 => {
+                            info!("Closing, nothing was forwarded for {}s", opt.idle_timeout);
+                            reason = Some("idle timeout");
+                            Ok(())
+                        }
+                        _ = max_age, if opt.max_connection_age > 0 => {
+                            info!("Closing, the connection is {}s old", opt.max_connection_age);
+                            reason = Some("max connection age");
+                            Ok(())
+                        }
+                        _ = fault::abort_after(
+                            opt.fault_abort_after.unwrap_or_default(),
+                            &connection,
+                            resettable.as_ref(),
+                        ), if opt.fault_abort_after.is_some() => {
+                            reason = Some("fault abort");
+                            Ok(())
+                        }
+                        Ok(()) = closing.changed() => {
+                            info!("Closing, --drain-timeout is over");
+                            reason = Some("drain timeout");
+                            Ok(())
+                        }
+                    }
+                };
+                let peer = connection.peer();
+                let upstream = connection.upstream().map(str::to_string);
+                connection.close(result.is_err());
+                let duration = started.elapsed().as_secs_f64();
+                let (incoming_bytes, outgoing_bytes) = data_log.totals();
+                if opt.summary_only {
+                    let error = result.err().map(|e| format!("{e:#}"));
+                    info!(
+                        event = "summary",
+                        peer = %peer,
+                        upstream,
+                        duration,
+                        incoming_bytes,
+                        outgoing_bytes,
+                        error = error.as_deref(),
+                        reason
+                    );
+                } else {
+                    if let Err(e) = result {
+                        error!("Got error: {:?}", e);
+                    }
+                    info!(
+                        event = "close",
+                        duration, incoming_bytes, outgoing_bytes, reason
+                    );
+                }
+                pcap::close(i);
+                drop(permit);
+            }
+            .instrument(span),
+        );
+    }
+    drop(listeners);
+
+    if !tasks.is_empty() {
+        info!(
+            parent: None,
+            "Waiting up to {}s for {} open connections to finish, press Ctrl-C again to exit now",
+            opt.drain_timeout,
+            tasks.len()
+        );
+    }
+    let deadline = tokio::time::sleep(Duration::from_secs(opt.drain_timeout));
+    tokio::select! {
+        _ = join_all(&mut tasks) => return Ok(()),
+        _ = deadline => {}
+    }
+    // Cut the remaining connections, which still logs how each of them ended.
+    let _ = shutdown.send(());
+    join_all(&mut tasks).await;
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+
+/// Where a [`LineWriter`] puts its lines.
+pub trait Lines: Send + 'static {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()>;
+
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+impl Lines for File {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        self.write_all(line.as_bytes())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+enum Message {
+    Line(String),
+    Flush(Sender<()>),
+}
+
+/// Feeds a thread writing lines to a file, so forwarding never waits on the disk.
+pub struct LineWriter {
+    sender: Sender<Message>,
+}
+
+impl LineWriter {
+    pub fn spawn(path: &Path, mut lines: impl Lines) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_path_buf();
+        std::thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Line(line) => {
+                        // Not logged, as the log file itself might be what fails.
+                        if let Err(e) = lines.write_line(&line) {
+                            eprintln!("Failed to write to {}: {e}", path.display());
+                        }
+                    }
+                    Message::Flush(ack) => {
+                        let _ = lines.flush();
+                        let _ = ack.send(());
+                    }
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    /// Queues a line, which should end with a newline.
+    pub fn write(&self, line: String) {
+        let _ = self.sender.send(Message::Line(line));
+    }
+
+    /// Waits until every queued line has been written.
+    pub fn flush(&self) {
+        let (ack, done) = mpsc::channel();
+        if self.sender.send(Message::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+}
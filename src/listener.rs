@@ -0,0 +1,157 @@
+use crate::{fault, sockopt, AsyncStream, Opt};
+use anyhow::{bail, Result};
+use socket2::{Domain, Socket, Type};
+use std::fmt;
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::task::{Context, Poll};
+use tokio::net::unix::UCred;
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{info, warn};
+
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix(Option<UCred>),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix(Some(cred)) => {
+                write!(f, "uid={}", cred.uid())?;
+                if let Some(pid) = cred.pid() {
+                    write!(f, " pid={pid}")?;
+                }
+                Ok(())
+            }
+            Peer::Unix(None) => write!(f, "unknown unix peer"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: SocketAddr, only_v6: bool) -> Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        if addr.is_ipv6() && only_v6 {
+            // Otherwise [::] may also claim the IPv4 port on dual-stack systems.
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+    }
+
+    pub fn bind_unix(path: &Path) -> Result<Self> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                std::fs::remove_file(path)?;
+                info!(parent: None, "Removed stale socket {}", path.display());
+            }
+            Ok(_) => bail!("{} exists and is not a socket", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Listener::Unix(UnixListener::bind(path)?))
+    }
+
+    pub fn local_addr(&self) -> Result<String> {
+        Ok(match self {
+            Listener::Tcp(listener) => listener.local_addr()?.to_string(),
+            Listener::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                Some(path) => format!("unix:{}", path.display()),
+                None => "unix:(unnamed)".to_string(),
+            },
+        })
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>, opt: &Opt) -> Poll<std::io::Result<Accepted>> {
+        match self {
+            Listener::Tcp(listener) => listener.poll_accept(cx).map_ok(|(stream, addr)| {
+                #[cfg(target_os = "linux")]
+                let original = opt
+                    .transparent
+                    .then(|| crate::transparent::original_destination(&stream));
+                // --transparent is refused up front elsewhere.
+                #[cfg(not(target_os = "linux"))]
+                let original = opt.transparent.then(|| unreachable!());
+                if let Err(e) = sockopt::tune(opt, &stream) {
+                    warn!(parent: None, "Failed to set socket options for {addr}: {e}");
+                }
+                let resettable = fault::resettable(opt, &stream);
+                let stream: AsyncStream = Box::pin(stream);
+                (stream, Peer::Tcp(addr), original, resettable)
+            }),
+            Listener::Unix(listener) => listener.poll_accept(cx).map_ok(|(stream, _)| {
+                let cred = stream.peer_cred().ok();
+                let stream: AsyncStream = Box::pin(stream);
+                (stream, Peer::Unix(cred), None, None)
+            }),
+        }
+    }
+}
+
+/// A connection, who made it, with --transparent where it was originally for, and
+/// with --fault-reset a duplicate of its socket.
+pub type Accepted = (
+    AsyncStream,
+    Peer,
+    Option<std::io::Result<SocketAddr>>,
+    Option<Socket>,
+);
+
+pub async fn accept_any(listeners: &[Listener], opt: &Opt) -> std::io::Result<Accepted> {
+    poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(res) = listener.poll_accept(cx, opt) {
+                return Poll::Ready(res);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Removes the listening socket file when dropped.
+pub struct UnixSocketGuard(pub PathBuf);
+
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_stale_sockets_are_replaced() {
+        let dir = std::env::temp_dir().join(format!("tcp-proxy-listen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.sock");
+
+        drop(Listener::bind_unix(&path).unwrap());
+        assert!(path.exists(), "the socket is left behind without a guard");
+        drop(Listener::bind_unix(&path).unwrap());
+
+        let file = dir.join("important.db");
+        std::fs::write(&file, "data").unwrap();
+        let error = Listener::bind_unix(&file).err().unwrap();
+        assert!(
+            error.to_string().ends_with("exists and is not a socket"),
+            "{error}"
+        );
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "data");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use crate::line_writer::{LineWriter, Lines};
+use crate::Opt;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Writes --log-file.
+static WRITER: OnceLock<LineWriter> = OnceLock::new();
+
+pub fn open(opt: &Opt) -> Result<()> {
+    let Some(path) = opt
+        .log_file
+        .as_deref()
+        .filter(|&path| path != Path::new("-"))
+    else {
+        return Ok(());
+    };
+    let log_file = LogFile::open(path, opt.log_max_size, opt.log_keep)
+        .with_context(|| format!("Failed to open log file {}", path.display()))?;
+    let _ = WRITER.set(LineWriter::spawn(path, log_file));
+    Ok(())
+}
+
+/// Queues a line for the log file, without the color codes meant for the console.
+pub fn write(line: &str) {
+    if let Some(writer) = WRITER.get() {
+        let mut line = strip_colors(line);
+        line.push('\n');
+        writer.write(line);
+    }
+}
+
+pub fn flush() {
+    if let Some(writer) = WRITER.get() {
+        writer.flush();
+    }
+}
+
+fn strip_colors(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("\x1b[") {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match rest.find('m') {
+            Some(end) => rest = &rest[end + 1..],
+            None => break,
+        }
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: Option<u64>, keep: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64;
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + len > max_size {
+                self.rotate()?;
+            }
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shifts `log` to `log.1`, `log.1` to `log.2` and so on, dropping the oldest.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for n in (1..self.keep).rev() {
+            match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.keep > 0 {
+            std::fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+}
+
+impl Lines for LogFile {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        self.write(line)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Write::flush(&mut self.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_are_stripped() {
+        assert_eq!(
+            strip_colors("\x1b[36m[0]\x1b[0m \x1b[1;32m==>\x1b[0m 5 bytes"),
+            "[0] ==> 5 bytes"
+        );
+    }
+
+    #[test]
+    fn rotation_keeps_the_newest_files() {
+        let dir = std::env::temp_dir().join(format!("tcp-proxy-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.log");
+        let mut log_file = LogFile::open(&path, Some(10), 2).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            log_file.write(line).unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("proxy.log"), "four\n");
+        assert_eq!(read("proxy.log.1"), "three\n");
+        assert_eq!(read("proxy.log.2"), "one\ntwo\n");
+        assert!(!dir.join("proxy.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
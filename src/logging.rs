@@ -0,0 +1,576 @@
+use crate::{color, log_file, Opt};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+#[derive(Clone, Copy)]
+pub enum Timestamps {
+    Rfc3339,
+    Relative,
+}
+
+impl FromStr for Timestamps {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rfc3339" => Ok(Timestamps::Rfc3339),
+            "relative" => Ok(Timestamps::Relative),
+            _ => Err("expected rfc3339 or relative"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    /// Read from the client.
+    Incoming,
+    /// Read from the upstream.
+    Outgoing,
+}
+
+impl Direction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::Incoming => "incoming",
+            Direction::Outgoing => "outgoing",
+        }
+    }
+}
+
+/// Which directions of a connection an option applies to.
+#[derive(Clone, Copy)]
+pub enum Directions {
+    Incoming,
+    Outgoing,
+    Both,
+}
+
+impl Directions {
+    pub fn includes(self, direction: Direction) -> bool {
+        matches!(
+            (self, direction),
+            (Directions::Both, _)
+                | (Directions::Incoming, Direction::Incoming)
+                | (Directions::Outgoing, Direction::Outgoing)
+        )
+    }
+}
+
+impl FromStr for Directions {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "incoming" => Ok(Directions::Incoming),
+            "outgoing" => Ok(Directions::Outgoing),
+            "both" => Ok(Directions::Both),
+            _ => Err("expected incoming, outgoing or both"),
+        }
+    }
+}
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Whether payloads are logged, starting as --show-data and flipped by SIGUSR1.
+static SHOW_DATA: AtomicBool = AtomicBool::new(false);
+
+/// Installs the subscriber printing all `tracing` events of the proxy.
+pub fn init(opt: &Opt) -> anyhow::Result<()> {
+    START.get_or_init(Instant::now);
+    color::init(opt.color);
+    log_file::open(opt)?;
+    SHOW_DATA.store(opt.show_data, Ordering::Relaxed);
+    tracing::subscriber::set_global_default(Logger {
+        opt: opt.clone(),
+        next_id: AtomicU64::new(1),
+        spans: Mutex::default(),
+    })?;
+    Ok(())
+}
+
+pub fn show_data() -> bool {
+    SHOW_DATA.load(Ordering::Relaxed)
+}
+
+/// Turns logging payloads on or off for all connections, returning whether it is
+/// now on.
+pub fn toggle_show_data() -> bool {
+    let show_data = !SHOW_DATA.fetch_xor(true, Ordering::Relaxed);
+    // The subscriber's answers for each callsite are cached by `tracing`.
+    tracing::callsite::rebuild_interest_cache();
+    show_data
+}
+
+/// Connections and messages are logged at INFO, each chunk read at DEBUG and
+/// payloads at TRACE, which `show_data` turns on. Each -v shows one level more and
+/// each -q one level less, down to only errors.
+fn max_level(opt: &Opt, show_data: bool) -> LevelFilter {
+    const LEVELS: [LevelFilter; 5] = [
+        LevelFilter::ERROR,
+        LevelFilter::WARN,
+        LevelFilter::INFO,
+        LevelFilter::DEBUG,
+        LevelFilter::TRACE,
+    ];
+    let default = if show_data { 4 } else { 2 };
+    let level = default + isize::from(opt.verbose) - isize::from(opt.quiet);
+    LEVELS[level.clamp(0, 4) as usize]
+}
+
+/// Prints a line to stdout and, with --log-file, to the log file.
+fn print(line: &str) {
+    println!("{line}");
+    log_file::write(line);
+}
+
+/// Like `print`, but to stderr.
+fn eprint(line: &str) {
+    eprintln!("{line}");
+    log_file::write(line);
+}
+
+/// Waits for the log file to catch up, before exiting.
+pub fn flush() {
+    log_file::flush();
+}
+
+fn rfc3339_now() -> String {
+    rfc3339(OffsetDateTime::now_utc())
+}
+
+/// Formats `time` like 2024-01-02T03:04:05.678Z.
+pub fn rfc3339(time: OffsetDateTime) -> String {
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        time.millisecond()
+    )
+}
+
+fn timestamp(opt: &Opt) -> String {
+    match opt.timestamps {
+        None => String::new(),
+        Some(Timestamps::Rfc3339) => format!("{} ", rfc3339_now()),
+        Some(Timestamps::Relative) => {
+            let elapsed = START.get_or_init(Instant::now).elapsed();
+            format!("{:10.3} ", elapsed.as_secs_f64())
+        }
+    }
+}
+
+thread_local! {
+    /// The spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The order of the fields in JSON lines, rather than the order `tracing` records them in.
+const JSON_ORDER: [&str; 28] = [
+    "message",
+    "peer",
+    "upstream",
+    "direction",
+    "opcode",
+    "fin",
+    "bytes",
+    "decoded",
+    "text",
+    "base64",
+    "omitted",
+    "header",
+    "from",
+    "to",
+    "version",
+    "status",
+    "reason",
+    "duration",
+    "latency",
+    "age",
+    "connections",
+    "open",
+    "errors",
+    "rejected",
+    "incoming_bytes",
+    "outgoing_bytes",
+    "http_rewrites",
+    "error",
+];
+
+/// Prints events in the --log-format, tagged with the connection of their span.
+struct Logger {
+    opt: Opt,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+struct SpanData {
+    connection: Option<usize>,
+    metadata: &'static Metadata<'static>,
+    /// How many handles to the span exist.
+    handles: usize,
+}
+
+impl Logger {
+    fn connection(&self, span: &Id) -> Option<usize> {
+        self.spans
+            .lock()
+            .unwrap()
+            .get(&span.into_u64())
+            .and_then(|span| span.connection)
+    }
+
+    fn current_connection(&self) -> Option<usize> {
+        let current = ENTERED.with(|entered| entered.borrow().last().copied())?;
+        self.connection(&Id::from_u64(current))
+    }
+
+    fn print_text(&self, level: Level, connection: Option<usize>, kind: &str, fields: &Fields) {
+        let print = if level == Level::ERROR { eprint } else { print };
+        let Some(i) = connection else {
+            match kind {
+                "stats" => print(&format!(
+                    "=== {} connections, {} still open, {} ended in error, {} rejected, {} {} bytes, {} {} bytes, {} HTTP rewrites ===",
+                    fields.u64("connections"),
+                    fields.u64("open"),
+                    fields.u64("errors"),
+                    fields.u64("rejected"),
+                    color::incoming(),
+                    fields.u64("incoming_bytes"),
+                    color::outgoing(),
+                    fields.u64("outgoing_bytes"),
+                    fields.u64("http_rewrites")
+                )),
+                _ => print(fields.str("message")),
+            }
+            return;
+        };
+        let prefix = format!("{}{}", timestamp(&self.opt), color::tag(i));
+        let line = match kind {
+            "connect" => format!("=== Handling connection from {} ===", fields.str("peer")),
+            "data" => {
+                let arrow = match fields.str("direction") {
+                    "incoming" => color::incoming(),
+                    _ => color::outgoing(),
+                };
+                let decoded = match fields.0.get("decoded") {
+                    Some(Value::String(encoding)) => format!(" decoded from {encoding}"),
+                    _ => String::new(),
+                };
+                let bytes = fields.u64("bytes");
+                print(&format!("{prefix} {arrow} {bytes} bytes{decoded}"));
+                if let Some(Value::String(payload)) = fields.0.get("text") {
+                    print(payload);
+                }
+                if let Some(omitted) = fields.0.get("omitted") {
+                    print(&format!("... ({omitted} more bytes)"));
+                }
+                return;
+            }
+            "http_rewrite" => match fields.0.get("to") {
+                // Rewrites of the request path have no header.
+                Some(to) if !fields.0.contains_key("header") => format!(
+                    "Rewrote path from {} to {}",
+                    fields.str("from"),
+                    to.as_str().unwrap_or_default()
+                ),
+                Some(to) => format!(
+                    "Rewrote {} header from {} to {}",
+                    fields.str("header"),
+                    fields.str("from"),
+                    to.as_str().unwrap_or_default()
+                ),
+                None => format!(
+                    "Removed {} header {}",
+                    fields.str("header"),
+                    fields.str("from")
+                ),
+            },
+            "websocket" => format!(
+                "{} WebSocket {} frame{}, {} bytes",
+                match fields.str("direction") {
+                    "incoming" => color::incoming(),
+                    _ => color::outgoing(),
+                },
+                fields.str("opcode"),
+                match fields.0.get("fin") {
+                    Some(Value::Bool(false)) => ", not final",
+                    _ => "",
+                },
+                fields.u64("bytes")
+            ),
+            "response" => format!(
+                "{} {} {} {} ({}{:.0?})",
+                color::outgoing(),
+                fields.str("version"),
+                fields.u64("status"),
+                fields.str("reason"),
+                match fields.0.get("bytes") {
+                    Some(bytes) => format!("{bytes} bytes, "),
+                    None => String::new(),
+                },
+                Duration::from_secs_f64(fields.0["latency"].as_f64().unwrap_or_default())
+            ),
+            "close" => format!(
+                "=== Done after {:.3?}, {} {} bytes, {} {} bytes{} ===",
+                Duration::from_secs_f64(fields.0["duration"].as_f64().unwrap_or_default()),
+                color::incoming(),
+                fields.u64("incoming_bytes"),
+                color::outgoing(),
+                fields.u64("outgoing_bytes"),
+                match fields.0.get("reason") {
+                    Some(Value::String(reason)) => format!(", {reason}"),
+                    _ => String::new(),
+                }
+            ),
+            "summary" => format!(
+                "=== {}{} done after {:.3?}, {} {} bytes, {} {} bytes, {} ===",
+                fields.str("peer"),
+                match fields.0.get("upstream") {
+                    Some(Value::String(upstream)) => format!(" to {upstream}"),
+                    _ => String::new(),
+                },
+                Duration::from_secs_f64(fields.0["duration"].as_f64().unwrap_or_default()),
+                color::incoming(),
+                fields.u64("incoming_bytes"),
+                color::outgoing(),
+                fields.u64("outgoing_bytes"),
+                match fields.0.get("error") {
+                    Some(Value::String(e)) => format!("error: {e}"),
+                    _ => match fields.0.get("reason") {
+                        Some(Value::String(reason)) => reason.clone(),
+                        _ => "clean EOF".to_string(),
+                    },
+                }
+            ),
+            "status" => format!(
+                "{} to {}, open for {:.3?}, {} {} bytes, {} {} bytes",
+                fields.str("peer"),
+                fields
+                    .0
+                    .get("upstream")
+                    .and_then(Value::as_str)
+                    .unwrap_or("(connecting)"),
+                Duration::from_secs_f64(fields.0["age"].as_f64().unwrap_or_default()),
+                color::incoming(),
+                fields.u64("incoming_bytes"),
+                color::outgoing(),
+                fields.u64("outgoing_bytes")
+            ),
+            _ => fields.str("message").to_string(),
+        };
+        print(&format!("{prefix} {line}"));
+    }
+
+    fn print_json(&self, connection: Option<usize>, kind: String, fields: Fields) {
+        let mut line = Map::new();
+        line.insert("timestamp".into(), rfc3339_now().into());
+        if let Some(i) = connection {
+            line.insert("connection".into(), i.into());
+        }
+        line.insert("event".into(), kind.into());
+        let mut fields: Vec<_> = fields.0.into_iter().collect();
+        fields.sort_by_key(|(name, _)| JSON_ORDER.iter().position(|known| known == name));
+        line.extend(fields);
+        print(&Value::Object(line).to_string());
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Spans are always enabled, so errors are tagged with their connection even
+        // with -q.
+        metadata.target().starts_with("tcp_proxy")
+            && (metadata.is_span() || *metadata.level() <= max_level(&self.opt, show_data()))
+    }
+
+    fn new_span(&self, span: &Attributes) -> Id {
+        let mut fields = Fields::default();
+        span.record(&mut fields);
+        let connection = match fields.0.get("connection").and_then(Value::as_u64) {
+            Some(i) => Some(i as usize),
+            None if span.is_root() => None,
+            None => match span.parent() {
+                Some(parent) => self.connection(parent),
+                None => self.current_connection(),
+            },
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.spans.lock().unwrap().insert(
+            id,
+            SpanData {
+                connection,
+                metadata: span.metadata(),
+                handles: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let level = *event.metadata().level();
+        let connection = if event.is_root() {
+            None
+        } else {
+            match event.parent() {
+                Some(parent) => self.connection(parent),
+                None => self.current_connection(),
+            }
+        };
+        let kind = match fields.0.remove("event") {
+            Some(Value::String(kind)) => kind,
+            _ if level == Level::ERROR => "error".into(),
+            _ => "log".into(),
+        };
+        match self.opt.log_format {
+            LogFormat::Text => self.print_text(level, connection, &kind, &fields),
+            LogFormat::Json => self.print_json(connection, kind, fields),
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|&id| id == span.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn current_span(&self) -> Current {
+        let Some(current) = ENTERED.with(|entered| entered.borrow().last().copied()) else {
+            return Current::none();
+        };
+        match self.spans.lock().unwrap().get(&current) {
+            Some(data) => Current::new(Id::from_u64(current), data.metadata),
+            None => Current::none(),
+        }
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.handles += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let Some(data) = spans.get_mut(&span.into_u64()) else {
+            return false;
+        };
+        data.handles -= 1;
+        if data.handles > 0 {
+            return false;
+        }
+        spans.remove(&span.into_u64());
+        true
+    }
+}
+
+/// The fields of an event or span, in the order they were given.
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl Fields {
+    fn str(&self, name: &str) -> &str {
+        self.0.get(name).and_then(Value::as_str).unwrap_or_default()
+    }
+
+    fn u64(&self, name: &str) -> u64 {
+        self.0.get(name).and_then(Value::as_u64).unwrap_or_default()
+    }
+}
+
+impl Visit for Fields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn level(args: &[&str]) -> LevelFilter {
+        let args = ["tcp-proxy", "localhost"].iter().chain(args);
+        let opt = Opt::from_iter(args);
+        max_level(&opt, opt.show_data)
+    }
+
+    #[test]
+    fn verbosity_flags_shift_the_level() {
+        assert_eq!(level(&[]), LevelFilter::INFO);
+        assert_eq!(level(&["-v"]), LevelFilter::DEBUG);
+        assert_eq!(level(&["-vvv"]), LevelFilter::TRACE);
+        assert_eq!(level(&["-qq"]), LevelFilter::ERROR);
+        assert_eq!(level(&["-qqq"]), LevelFilter::ERROR);
+        assert_eq!(level(&["--show-data"]), LevelFilter::TRACE);
+        assert_eq!(level(&["--show-data", "-q"]), LevelFilter::DEBUG);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{connect, AsyncStream, Opt, Target};
+use anyhow::anyhow;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::{info, warn, Instrument, Span};
+
+/// How many chunks may wait for the mirror before more are dropped.
+const QUEUE_SIZE: usize = 64;
+
+/// How long the mirror may keep answering once everything was sent to it.
+const LINGER: Duration = Duration::from_secs(10);
+
+/// Copies the data read from a stream to --mirror. Reading never waits for the
+/// mirror: what it can't keep up with is dropped.
+pub struct Mirrored {
+    inner: AsyncStream,
+    /// Taken once `inner` ended, which ends the data sent to the mirror.
+    queue: Option<mpsc::Sender<Vec<u8>>>,
+    /// Bytes dropped since the mirror fell behind.
+    dropped: u64,
+}
+
+impl Mirrored {
+    /// `inner` with what is read from it, after `sent` which was read before, also
+    /// sent to --mirror if it was given.
+    pub fn wrap(opt: &Opt, sent: &[u8], inner: AsyncStream) -> AsyncStream {
+        let Some(mirror) = &opt.mirror else {
+            return inner;
+        };
+        let (queue, chunks) = mpsc::channel(QUEUE_SIZE);
+        let host = mirror.host.clone();
+        let port = mirror.port.unwrap_or(opt.host_port());
+        let connect_timeout = opt.connect_timeout;
+        tokio::spawn(
+            async move {
+                let target = Target::Tcp(&host, port);
+                if let Err(e) = run(target, connect_timeout, chunks).await {
+                    warn!("Mirroring to {target} failed, not mirroring the rest: {e:#}");
+                }
+            }
+            .instrument(Span::current()),
+        );
+        let mut mirrored = Mirrored {
+            inner,
+            queue: Some(queue),
+            dropped: 0,
+        };
+        if !sent.is_empty() {
+            mirrored.send(sent.to_vec());
+        }
+        Box::pin(mirrored)
+    }
+
+    fn send(&mut self, data: Vec<u8>) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        if let Err(mpsc::error::TrySendError::Full(data)) = queue.try_send(data) {
+            self.dropped += data.len() as u64;
+        }
+    }
+}
+
+/// Writes the queued chunks to the mirror, reading and discarding what it answers.
+async fn run(
+    target: Target<'_>,
+    connect_timeout: u64,
+    mut chunks: mpsc::Receiver<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let stream = match connect_timeout {
+        0 => connect(target).await?,
+        seconds => tokio::time::timeout(Duration::from_secs(seconds), connect(target))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Connecting timed out after {seconds}s")))?,
+    };
+    let (mut read, mut write) = tokio::io::split(stream);
+    let discard = async {
+        let mut buf = vec![0; 1 << 16];
+        while read.read(&mut buf).await? > 0 {}
+        Ok::<_, io::Error>(())
+    };
+    tokio::pin!(discard);
+    let send = async {
+        while let Some(chunk) = chunks.recv().await {
+            write.write_all(&chunk).await?;
+        }
+        write.shutdown().await
+    };
+    tokio::select! {
+        sent = send => sent?,
+        discarded = &mut discard => return Ok(discarded?),
+    }
+    let _ = tokio::time::timeout(LINGER, discard).await;
+    Ok(())
+}
+
+impl AsyncRead for Mirrored {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let room = buf.remaining() > 0;
+        let poll = self.inner.as_mut().poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let data = buf.filled()[filled..].to_vec();
+            if !data.is_empty() {
+                self.send(data);
+            } else if room {
+                self.queue = None;
+            }
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for Mirrored {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+impl Drop for Mirrored {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            info!(
+                "Dropped {} bytes the --mirror couldn't keep up with",
+                self.dropped
+            );
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use crate::logging::Direction;
+use crate::Opt;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Fake addresses of the synthesized TCP streams, one client port per connection.
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Payload bytes that fit in one IPv4 packet along with the IP and TCP headers.
+const MAX_PAYLOAD: usize = 65535 - 40;
+
+const LINKTYPE_RAW: u16 = 101;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+enum Message {
+    Connect {
+        i: usize,
+        time: SystemTime,
+    },
+    Data {
+        i: usize,
+        time: SystemTime,
+        direction: Direction,
+        data: Vec<u8>,
+    },
+    Close {
+        i: usize,
+        time: SystemTime,
+    },
+    Flush(Sender<()>),
+}
+
+/// Feeds the thread writing --pcap, which all connections share.
+static SENDER: OnceLock<Sender<Message>> = OnceLock::new();
+
+pub fn open(opt: &Opt) -> Result<()> {
+    let Some(path) = &opt.pcap else {
+        return Ok(());
+    };
+    if SENDER.get().is_some() {
+        return Ok(());
+    }
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = Writer {
+        file: BufWriter::new(file),
+        server_port: opt.host_port(),
+        streams: HashMap::new(),
+    };
+    writer
+        .write_header()
+        .with_context(|| format!("Failed to write to {}", path.display()))?;
+    let path = path.clone();
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        if let Err(e) = writer.run(receiver) {
+            error!("Failed to write to {}: {e}", path.display());
+        }
+    });
+    let _ = SENDER.set(sender);
+    Ok(())
+}
+
+fn send(message: impl FnOnce() -> Message) {
+    if let Some(sender) = SENDER.get() {
+        let _ = sender.send(message());
+    }
+}
+
+/// Starts the stream of connection `i` with a handshake.
+pub fn connect(i: usize) {
+    send(|| Message::Connect {
+        i,
+        time: SystemTime::now(),
+    })
+}
+
+/// Adds a chunk read from one side of connection `i`.
+pub fn data(i: usize, direction: Direction, data: &[u8]) {
+    send(|| Message::Data {
+        i,
+        time: SystemTime::now(),
+        direction,
+        data: data.to_vec(),
+    })
+}
+
+/// Ends the stream of connection `i`.
+pub fn close(i: usize) {
+    send(|| Message::Close {
+        i,
+        time: SystemTime::now(),
+    })
+}
+
+/// Waits until every queued packet has been written.
+pub fn flush() {
+    if let Some(sender) = SENDER.get() {
+        let (ack, done) = mpsc::channel();
+        if sender.send(Message::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+}
+
+/// The next sequence numbers of both sides of a connection.
+struct Stream {
+    client_seq: u32,
+    server_seq: u32,
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    server_port: u16,
+    streams: HashMap<usize, Stream>,
+}
+
+impl Writer {
+    fn run(&mut self, receiver: Receiver<Message>) -> std::io::Result<()> {
+        while let Ok(message) = receiver.recv() {
+            self.handle(message)?;
+            // Drain whatever else is queued before flushing, so the file can be
+            // followed while the proxy runs without a write per packet.
+            while let Ok(message) = receiver.try_recv() {
+                self.handle(message)?;
+            }
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, message: Message) -> std::io::Result<()> {
+        match message {
+            Message::Connect { i, time } => {
+                let mut stream = Stream {
+                    client_seq: 0,
+                    server_seq: 0,
+                };
+                self.segment(i, time, Direction::Incoming, &mut stream, SYN, &[])?;
+                self.segment(i, time, Direction::Outgoing, &mut stream, SYN | ACK, &[])?;
+                self.segment(i, time, Direction::Incoming, &mut stream, ACK, &[])?;
+                self.streams.insert(i, stream);
+            }
+            Message::Data {
+                i,
+                time,
+                direction,
+                data,
+            } => {
+                if let Some(mut stream) = self.streams.remove(&i) {
+                    for payload in data.chunks(MAX_PAYLOAD) {
+                        self.segment(i, time, direction, &mut stream, PSH | ACK, payload)?;
+                    }
+                    self.streams.insert(i, stream);
+                }
+            }
+            Message::Close { i, time } => {
+                if let Some(mut stream) = self.streams.remove(&i) {
+                    self.segment(i, time, Direction::Incoming, &mut stream, FIN | ACK, &[])?;
+                    self.segment(i, time, Direction::Outgoing, &mut stream, FIN | ACK, &[])?;
+                    self.segment(i, time, Direction::Incoming, &mut stream, ACK, &[])?;
+                }
+            }
+            Message::Flush(ack) => {
+                self.file.flush()?;
+                let _ = ack.send(());
+            }
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        // Section Header Block: byte-order magic, version 1.0, unknown section length.
+        let mut section = vec![];
+        section.extend(0x1A2B3C4Du32.to_le_bytes());
+        section.extend(1u16.to_le_bytes());
+        section.extend(0u16.to_le_bytes());
+        section.extend((-1i64).to_le_bytes());
+        self.block(0x0A0D0D0A, &section)?;
+
+        // Interface Description Block: raw IP packets, no snapshot length.
+        let mut interface = vec![];
+        interface.extend(LINKTYPE_RAW.to_le_bytes());
+        interface.extend(0u16.to_le_bytes());
+        interface.extend(0u32.to_le_bytes());
+        self.block(1, &interface)
+    }
+
+    fn block(&mut self, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+        let padding = (4 - body.len() % 4) % 4;
+        let total_length = (12 + body.len() + padding) as u32;
+        self.file.write_all(&block_type.to_le_bytes())?;
+        self.file.write_all(&total_length.to_le_bytes())?;
+        self.file.write_all(body)?;
+        self.file.write_all(&[0; 3][..padding])?;
+        self.file.write_all(&total_length.to_le_bytes())
+    }
+
+    /// Writes one TCP segment of connection `i` as an Enhanced Packet Block.
+    fn segment(
+        &mut self,
+        i: usize,
+        time: SystemTime,
+        direction: Direction,
+        stream: &mut Stream,
+        flags: u8,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        let client = (CLIENT_IP, 1024 + (i % (65536 - 1024)) as u16);
+        let server = (SERVER_IP, self.server_port);
+        let (source, destination, seq, ack) = match direction {
+            Direction::Incoming => (client, server, &mut stream.client_seq, stream.server_seq),
+            Direction::Outgoing => (server, client, &mut stream.server_seq, stream.client_seq),
+        };
+        let packet = tcp_packet(
+            source,
+            destination,
+            *seq,
+            if flags & ACK != 0 { ack } else { 0 },
+            flags,
+            payload,
+        );
+        // SYN and FIN each take up a sequence number.
+        let consumed = payload.len() + usize::from(flags & (SYN | FIN) != 0);
+        *seq = seq.wrapping_add(consumed as u32);
+
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut body = vec![];
+        body.extend(0u32.to_le_bytes());
+        body.extend(((micros >> 32) as u32).to_le_bytes());
+        body.extend((micros as u32).to_le_bytes());
+        body.extend((packet.len() as u32).to_le_bytes());
+        body.extend((packet.len() as u32).to_le_bytes());
+        body.extend(&packet);
+        self.block(6, &body)
+    }
+}
+
+fn tcp_packet(
+    (source_ip, source_port): (Ipv4Addr, u16),
+    (destination_ip, destination_port): (Ipv4Addr, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp_length = 20 + payload.len();
+    let mut tcp = Vec::with_capacity(tcp_length);
+    tcp.extend(source_port.to_be_bytes());
+    tcp.extend(destination_port.to_be_bytes());
+    tcp.extend(seq.to_be_bytes());
+    tcp.extend(ack.to_be_bytes());
+    tcp.push(5 << 4);
+    tcp.push(flags);
+    tcp.extend(u16::MAX.to_be_bytes());
+    tcp.extend([0, 0, 0, 0]);
+    tcp.extend(payload);
+
+    let mut pseudo_header = vec![];
+    pseudo_header.extend(source_ip.octets());
+    pseudo_header.extend(destination_ip.octets());
+    pseudo_header.extend([0, 6]);
+    pseudo_header.extend((tcp_length as u16).to_be_bytes());
+    let checksum = internet_checksum(&[&pseudo_header, &tcp]);
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut ip = Vec::with_capacity(20 + tcp_length);
+    ip.push(0x45);
+    ip.push(0);
+    ip.extend(((20 + tcp_length) as u16).to_be_bytes());
+    ip.extend([0, 0, 0x40, 0]);
+    ip.push(64);
+    ip.push(6);
+    ip.extend([0, 0]);
+    ip.extend(source_ip.octets());
+    ip.extend(destination_ip.octets());
+    let checksum = internet_checksum(&[&ip]);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    ip.extend(tcp);
+    ip
+}
+
+/// The ones' complement sum used by IP and TCP, over `parts` laid end to end.
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for word in part.chunks(2) {
+            let high = u32::from(word[0]) << 8;
+            sum += high | word.get(1).copied().map_or(0, u32::from);
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_of_known_ip_header() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(internet_checksum(&[&header]), 0xb861);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::Opt;
+use anyhow::{bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// What every version 2 header starts with.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a version 1 header can be, with its CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// The PROXY protocol header for --send-proxy or --send-proxy-v2, naming the
+/// client and the address it was proxied from as `(source, destination)`. Without
+/// addresses, as for clients on a Unix domain socket, it says they are unknown.
+pub fn header(opt: &Opt, addresses: Option<(SocketAddr, SocketAddr)>) -> Option<Vec<u8>> {
+    if opt.send_proxy {
+        Some(v1(addresses))
+    } else if opt.send_proxy_v2 {
+        Some(v2(addresses))
+    } else {
+        None
+    }
+}
+
+/// The header saying that the proxy itself connected, for health checks.
+pub fn local_header(opt: &Opt) -> Option<Vec<u8>> {
+    if opt.send_proxy {
+        Some(v1(None))
+    } else if opt.send_proxy_v2 {
+        Some(v2_local())
+    } else {
+        None
+    }
+}
+
+/// Both addresses in the same family, mapping an IPv4 one to IPv6 if the other
+/// is IPv6.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_v6(source), to_v6(destination))
+    }
+}
+
+fn v1(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let Some((source, destination)) = addresses else {
+        return b"PROXY UNKNOWN\r\n".to_vec();
+    };
+    let (source, destination) = same_family(source, destination);
+    let protocol = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {protocol} {} {} {} {}\r\n",
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn v2(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    let Some((source, destination)) = addresses else {
+        // The PROXY command with an unspecified family, and no addresses.
+        header.extend([0x21, 0x00, 0x00, 0x00]);
+        return header;
+    };
+    let (source, destination) = same_family(source, destination);
+    let mut addresses = vec![];
+    let family = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            addresses.extend(source.octets());
+            addresses.extend(destination.octets());
+            0x11
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            addresses.extend(source.octets());
+            addresses.extend(destination.octets());
+            0x21
+        }
+        _ => unreachable!("the addresses are in the same family"),
+    };
+    addresses.extend(source.port().to_be_bytes());
+    addresses.extend(destination.port().to_be_bytes());
+
+    // Version 2 with the PROXY command, over TCP.
+    header.extend([0x21, family]);
+    header.extend((addresses.len() as u16).to_be_bytes());
+    header.extend(addresses);
+    header
+}
+
+fn v2_local() -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    // The LOCAL command, with no addresses.
+    header.extend([0x20, 0x00, 0x00, 0x00]);
+    header
+}
+
+/// Reads the PROXY protocol v1 or v2 header that starts `stream` for
+/// --accept-proxy, and nothing after it. Returns the client's address and the one
+/// it connected to as `(source, destination)`, unless the header doesn't say.
+pub async fn read_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    // Shorter than any header, so this doesn't read past one.
+    let mut start = [0; 12];
+    stream
+        .read_exact(&mut start)
+        .await
+        .context("Connection closed before the PROXY protocol header")?;
+    if start == SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        let [version_command, family, len @ ..] = fixed;
+        let mut rest = vec![0; u16::from_be_bytes(len).into()];
+        stream.read_exact(&mut rest).await?;
+        parse_v2(version_command, family, &rest)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                bail!("Invalid PROXY protocol header: no CRLF in {V1_MAX_LEN} bytes");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else {
+        bail!(
+            "Expected a PROXY protocol header, got {:?}",
+            String::from_utf8_lossy(&start)
+        )
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let invalid = || {
+        format!(
+            "Invalid PROXY protocol header: {:?}",
+            String::from_utf8_lossy(line)
+        )
+    };
+    let line = std::str::from_utf8(line).ok().with_context(invalid)?;
+    let fields: Vec<_> = line.trim_end_matches("\r\n").split(' ').collect();
+    let (ipv4, [source, destination, source_port, destination_port]) = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => return Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            (
+                protocol == "TCP4",
+                [source, destination, source_port, destination_port],
+            )
+        }
+        _ => bail!(invalid()),
+    };
+    let ip = |ip: &str| {
+        ip.parse::<IpAddr>()
+            .ok()
+            .filter(|ip| ip.is_ipv4() == ipv4)
+            .with_context(invalid)
+    };
+    let port = |port: &str| port.parse::<u16>().ok().with_context(invalid);
+    Ok(Some((
+        SocketAddr::new(ip(source)?, port(source_port)?),
+        SocketAddr::new(ip(destination)?, port(destination_port)?),
+    )))
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    rest: &[u8],
+) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    match version_command {
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => bail!(
+            "Invalid PROXY protocol header: unknown version and command {version_command:#04x}"
+        ),
+    }
+    let port = |at: usize| u16::from_be_bytes([rest[at], rest[at + 1]]);
+    // The high nibble is the address family, the low one TCP or UDP.
+    match family >> 4 {
+        0x1 if rest.len() >= 12 => {
+            let ip = |at: usize| Ipv4Addr::from(<[u8; 4]>::try_from(&rest[at..at + 4]).unwrap());
+            Ok(Some((
+                SocketAddr::new(ip(0).into(), port(8)),
+                SocketAddr::new(ip(4).into(), port(10)),
+            )))
+        }
+        0x2 if rest.len() >= 36 => {
+            let ip = |at: usize| Ipv6Addr::from(<[u8; 16]>::try_from(&rest[at..at + 16]).unwrap());
+            Ok(Some((
+                SocketAddr::new(ip(0).into(), port(32)),
+                SocketAddr::new(ip(16).into(), port(34)),
+            )))
+        }
+        0x1 | 0x2 => bail!(
+            "Invalid PROXY protocol header: {} bytes are too few for the addresses",
+            rest.len()
+        ),
+        // Unspecified or Unix domain sockets, which don't make a client address.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(source: &str, destination: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((source.parse().unwrap(), destination.parse().unwrap()))
+    }
+
+    #[test]
+    fn v1_headers_name_both_addresses() {
+        let ipv4 = v1(addresses("192.168.0.1:56324", "192.168.0.11:443"));
+        assert_eq!(ipv4, b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n");
+        let ipv6 = v1(addresses("[2001:db8::1]:4000", "[::1]:80"));
+        assert_eq!(ipv6, b"PROXY TCP6 2001:db8::1 ::1 4000 80\r\n");
+        let mixed = v1(addresses("10.0.0.1:4000", "[::1]:80"));
+        assert_eq!(mixed, b"PROXY TCP6 ::ffff:10.0.0.1 ::1 4000 80\r\n");
+        assert_eq!(v1(None), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_headers_are_encoded_exactly() {
+        let signature = [
+            0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+        ];
+        let mut ipv4 = signature.to_vec();
+        ipv4.extend([0x21, 0x11, 0x00, 0x0c]);
+        ipv4.extend([192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(v2(addresses("192.168.0.1:56324", "192.168.0.11:443")), ipv4);
+
+        let mut ipv6 = signature.to_vec();
+        ipv6.extend([0x21, 0x21, 0x00, 0x24]);
+        ipv6.extend([0x20, 0x01, 0x0d, 0xb8]);
+        ipv6.extend([0; 11]);
+        ipv6.push(0x01);
+        ipv6.extend([0; 15]);
+        ipv6.push(0x01);
+        ipv6.extend([0x0f, 0xa0, 0x00, 0x50]);
+        assert_eq!(v2(addresses("[2001:db8::1]:4000", "[::1]:80")), ipv6);
+
+        let mut unknown = signature.to_vec();
+        unknown.extend([0x21, 0x00, 0x00, 0x00]);
+        assert_eq!(v2(None), unknown);
+
+        let mut local = signature.to_vec();
+        local.extend([0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(v2_local(), local);
+    }
+
+    async fn read(data: &[u8]) -> (Result<Option<(SocketAddr, SocketAddr)>>, Vec<u8>) {
+        let mut stream = data;
+        let header = read_header(&mut stream).await;
+        (header, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn headers_are_read_without_what_follows() {
+        let ipv4 = addresses("192.168.0.1:56324", "192.168.0.11:443");
+        let ipv6 = addresses("[2001:db8::1]:4000", "[::1]:80");
+        for header in [v1(ipv4), v2(ipv4)] {
+            let (read, rest) = read(&[header, b"GET".to_vec()].concat()).await;
+            assert_eq!(read.unwrap(), ipv4);
+            assert_eq!(rest, b"GET");
+        }
+        for header in [v1(ipv6), v2(ipv6)] {
+            assert_eq!(read(&header).await.0.unwrap(), ipv6);
+        }
+        for header in [v1(None), v2(None), v2_local()] {
+            let (read, rest) = read(&[header, b"GET".to_vec()].concat()).await;
+            assert_eq!(read.unwrap(), None);
+            assert_eq!(rest, b"GET");
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_headers_are_errors() {
+        for header in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            b"PROXY TCP4 ::1 ::1 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 65536\r\n",
+            b"PROXY TCP9 192.168.0.1 192.168.0.11 56324 443\r\n",
+            &[&b"PROXY "[..], &[b'x'; 200]].concat(),
+            &[&SIGNATURE[..], &[0x21, 0x11, 0x00, 0x04, 1, 2, 3, 4]].concat(),
+            &[&SIGNATURE[..], &[0x11, 0x00, 0x00, 0x00]].concat(),
+            &SIGNATURE[..],
+        ] {
+            let (read, _) = read(header).await;
+            assert!(read.is_err(), "{:?}", String::from_utf8_lossy(header));
+        }
+    }
+}
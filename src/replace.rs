@@ -0,0 +1,248 @@
+use crate::logging::{Direction, Directions};
+use crate::AsyncStream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+use tracing::info;
+
+/// How long the end of a read is held back while it may be the start of a match,
+/// before it is passed on as it is.
+const HOLD_BACK: Duration = Duration::from_millis(50);
+
+/// A byte sequence substituted with --replace or --replace-hex.
+#[derive(Clone)]
+pub struct Replacement {
+    pub from: Vec<u8>,
+    pub to: Vec<u8>,
+}
+
+pub fn parse_text(replacement: &str) -> Result<Replacement, String> {
+    match replacement.split_once('=') {
+        Some((from, to)) if !from.is_empty() => Ok(Replacement {
+            from: from.into(),
+            to: to.into(),
+        }),
+        _ => Err("expected a replacement as \"from=to\"".to_string()),
+    }
+}
+
+pub fn parse_hex(replacement: &str) -> Result<Replacement, String> {
+    const USAGE: &str = "expected a replacement as hex digits \"from=to\"";
+    let hex = |digits: &str| -> Option<Vec<u8>> {
+        if !digits.len().is_multiple_of(2) {
+            return None;
+        }
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok())
+            .collect()
+    };
+    let (from, to) = replacement.split_once('=').ok_or(USAGE)?;
+    match (hex(from), hex(to)) {
+        (Some(from), Some(to)) if !from.is_empty() => Ok(Replacement { from, to }),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+/// Substitutes the replacements in data that arrives in parts.
+struct Replacer {
+    replacements: Vec<Replacement>,
+    /// The end of the data so far, which may be the start of a match.
+    held: Vec<u8>,
+    /// Data with the replacements made, ready to be read.
+    ready: Vec<u8>,
+    count: u64,
+}
+
+impl Replacer {
+    /// Replaces the longest match at each position of `data`, holding back its end
+    /// while more data could still complete a longer match there.
+    fn feed(&mut self, data: &[u8]) {
+        self.held.extend(data);
+        self.replace(false);
+    }
+
+    /// Replaces the matches in the data held back, passing it on.
+    fn flush(&mut self) {
+        self.replace(true);
+    }
+
+    fn replace(&mut self, end: bool) {
+        let data = std::mem::take(&mut self.held);
+        let mut i = 0;
+        while i < data.len() {
+            let rest = &data[i..];
+            let partial = self.replacements.iter().any(|replacement| {
+                replacement.from.len() > rest.len() && replacement.from.starts_with(rest)
+            });
+            if partial && !end {
+                self.held = rest.to_vec();
+                return;
+            }
+            let found = self
+                .replacements
+                .iter()
+                .filter(|replacement| rest.starts_with(&replacement.from))
+                .max_by_key(|replacement| replacement.from.len());
+            match found {
+                Some(replacement) => {
+                    self.ready.extend(&replacement.to);
+                    i += replacement.from.len();
+                    self.count += 1;
+                }
+                None => {
+                    self.ready.push(data[i]);
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Makes the --replace substitutions in the data read from `inner`.
+pub struct Replaced {
+    inner: AsyncStream,
+    direction: Direction,
+    replacer: Replacer,
+    /// Set once `inner` was read to the end.
+    eof: bool,
+    /// Runs while data is held back and `inner` has nothing more.
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Replaced {
+    /// `inner` with the replacements made in what is read from it, if there are
+    /// any for `direction`.
+    pub fn wrap(
+        replacements: &[Replacement],
+        directions: Directions,
+        direction: Direction,
+        inner: AsyncStream,
+    ) -> AsyncStream {
+        if replacements.is_empty() || !directions.includes(direction) {
+            return inner;
+        }
+        Box::pin(Replaced {
+            inner,
+            direction,
+            replacer: Replacer {
+                replacements: replacements.to_vec(),
+                held: vec![],
+                ready: vec![],
+                count: 0,
+            },
+            eof: false,
+            timer: None,
+        })
+    }
+}
+
+impl AsyncRead for Replaced {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let ready = &mut this.replacer.ready;
+            if !ready.is_empty() {
+                let n = ready.len().min(buf.remaining());
+                buf.put_slice(&ready[..n]);
+                ready.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut data = [0; 1 << 14];
+            let mut read = ReadBuf::new(&mut data);
+            match this.inner.as_mut().poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) if read.filled().is_empty() => {
+                    this.replacer.flush();
+                    this.eof = true;
+                }
+                Poll::Ready(Ok(())) => {
+                    this.replacer.feed(read.filled());
+                    this.timer = None;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending if this.replacer.held.is_empty() => return Poll::Pending,
+                Poll::Pending => {
+                    let timer = this
+                        .timer
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(HOLD_BACK)));
+                    if timer.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    this.timer = None;
+                    this.replacer.flush();
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Replaced {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.inner.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+impl Drop for Replaced {
+    fn drop(&mut self) {
+        let from = match self.direction {
+            Direction::Incoming => "client",
+            Direction::Outgoing => "upstream",
+        };
+        info!(
+            "Replaced {} matches in the data from the {from}",
+            self.replacer.count
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_across_parts_are_replaced() {
+        let mut replacer = Replacer {
+            replacements: vec![
+                parse_text("cat=dog").unwrap(),
+                parse_text("category=kind").unwrap(),
+                parse_hex("00ff=").unwrap(),
+            ],
+            held: vec![],
+            ready: vec![],
+            count: 0,
+        };
+        for part in [&b"a ca"[..], b"t, a categ", b"ory\0", b"\xff a cat"] {
+            replacer.feed(part);
+        }
+        assert_eq!(replacer.ready, b"a dog, a kind a ");
+        assert_eq!(replacer.held, b"cat");
+        replacer.flush();
+        assert_eq!(replacer.ready, b"a dog, a kind a dog");
+        assert_eq!(replacer.count, 4);
+
+        assert!(parse_hex("0g=00").is_err() && parse_hex("=00").is_err());
+    }
+}
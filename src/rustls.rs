@@ -0,0 +1,596 @@
+use crate::certgen::{generate_der, self_signed_params};
+use crate::save_certs::{save_chain, SavedCerts};
+use crate::{AsyncStream, Opt, TlsVersion};
+use ::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use ::rustls::client::WebPkiServerVerifier;
+use ::rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use ::rustls::pki_types::pem::PemObject;
+use ::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use ::rustls::{
+    CertificateError, ClientConfig, CommonState, DigitallySignedStruct, HandshakeKind, KeyLog,
+    OtherError, ProtocolVersion, RootCertStore, ServerConfig, SignatureScheme,
+    SupportedProtocolVersion,
+};
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context as TaskContext, Poll};
+use time::{Duration, OffsetDateTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{error, info, warn};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+pub type Acceptor = TlsAcceptor;
+pub type Connector = TlsConnector;
+
+/// Rejects options that only the OpenSSL backend implements.
+fn check_supported(opt: &Opt) -> Result<()> {
+    let unsupported = [
+        ("--ciphers", opt.ciphers.is_some()),
+        ("--ciphersuites", opt.ciphersuites.is_some()),
+        ("--cert-cache-dir", opt.cert_cache_dir.is_some()),
+        ("--ca-cert", opt.ca_cert.is_some()),
+        ("--dynamic-certs", opt.dynamic_certs),
+        ("--request-client-cert", opt.request_client_cert),
+        ("--require-client-cert", opt.require_client_cert),
+        ("--client-ca", opt.client_ca.is_some()),
+    ];
+    if let Some((flag, _)) = unsupported.iter().find(|(_, used)| *used) {
+        bail!("{flag} is not supported by the rustls backend, rebuild with the ssl feature");
+    }
+
+    Ok(())
+}
+
+fn protocol_versions(opt: &Opt) -> Result<Vec<&'static SupportedProtocolVersion>> {
+    let min = opt.tls_min_version.unwrap_or(TlsVersion::Tls1_2);
+    let max = opt.tls_max_version.unwrap_or(TlsVersion::Tls1_3);
+    if min < TlsVersion::Tls1_2 || max < TlsVersion::Tls1_2 {
+        bail!("rustls only supports TLS 1.2 and 1.3");
+    }
+
+    let versions: Vec<_> = [
+        (TlsVersion::Tls1_2, &::rustls::version::TLS12),
+        (TlsVersion::Tls1_3, &::rustls::version::TLS13),
+    ]
+    .into_iter()
+    .filter(|(version, _)| (min..=max).contains(version))
+    .map(|(_, supported)| supported)
+    .collect();
+    if versions.is_empty() {
+        bail!("--tls-min-version is higher than --tls-max-version");
+    }
+
+    Ok(versions)
+}
+
+/// Writes secrets in the NSS key log format, like OpenSSL's keylog callback.
+#[derive(Debug)]
+struct KeyLogFile(Mutex<File>);
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut file = self.0.lock().unwrap();
+        if let Err(e) = writeln!(file, "{label} {} {}", hex(client_random), hex(secret)) {
+            error!("Failed to write to key log: {e}");
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Opens the NSS key log file once, shared by every config that logs to it.
+fn open_keylog(path: &Path) -> Option<Arc<KeyLogFile>> {
+    static KEYLOG: OnceLock<Option<Arc<KeyLogFile>>> = OnceLock::new();
+    KEYLOG
+        .get_or_init(
+            || match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Some(Arc::new(KeyLogFile(Mutex::new(file)))),
+                Err(e) => {
+                    warn!(
+                        parent: None,
+                        "Not logging TLS keys, failed to open {}: {e}",
+                        path.display()
+                    );
+                    None
+                }
+            },
+        )
+        .clone()
+}
+
+/// Accepts any upstream certificate but still checks the handshake signatures.
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, ::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, ::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, ::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn public_key_pin(der: &CertificateDer) -> Option<[u8; 32]> {
+    let (_, certificate) = X509Certificate::from_der(der).ok()?;
+    Some(Sha256::digest(certificate.public_key().raw).into())
+}
+
+#[derive(Debug)]
+struct PinMismatch(String);
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "public key {} matches no --pin-sha256", self.0)
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// Fails the handshake unless the upstream's public key matches a --pin-sha256,
+/// after whatever chain check the inner verifier does.
+#[derive(Debug)]
+struct PinVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, ::rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let pin = public_key_pin(end_entity).ok_or(::rustls::Error::InvalidCertificate(
+            CertificateError::BadEncoding,
+        ))?;
+        if !self.pins.contains(&pin) {
+            return Err(::rustls::Error::InvalidCertificate(
+                CertificateError::Other(OtherError(Arc::new(PinMismatch(STANDARD.encode(pin))))),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, ::rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, ::rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn root_store(opt: &Opt) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    if let Some(ca_file) = &opt.upstream_ca {
+        for certificate in load_certificate_chain(ca_file)
+            .with_context(|| format!("Failed to load CA bundle {}", ca_file.display()))?
+        {
+            roots.add(certificate)?;
+        }
+    }
+
+    Ok(roots)
+}
+
+fn load_certificate_chain(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let chain = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to read certificate {}", path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid PEM certificate in {}", path.display()))?;
+    if chain.is_empty() {
+        bail!("No certificate found in {}", path.display());
+    }
+
+    Ok(chain)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .with_context(|| format!("Invalid PEM private key in {}", path.display()))
+}
+
+fn alpn_protocols(opt: &Opt) -> Vec<Vec<u8>> {
+    opt.alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect()
+}
+
+pub fn generate_connector(opt: &Opt) -> Result<TlsConnector> {
+    check_supported(opt)?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&protocol_versions(opt)?)?;
+    let mut verifier: Arc<dyn ServerCertVerifier> = if opt.verify_upstream {
+        WebPkiServerVerifier::builder_with_provider(Arc::new(root_store(opt)?), provider.clone())
+            .build()?
+    } else {
+        Arc::new(NoVerifier(provider))
+    };
+    if !opt.pin_sha256.is_empty() {
+        verifier = Arc::new(PinVerifier {
+            inner: verifier,
+            pins: opt.pin_sha256.clone(),
+        });
+    }
+    let builder = builder
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+    let mut config = match (&opt.client_cert, &opt.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certificate_chain(cert)?, load_private_key(key)?)
+            .with_context(|| {
+                format!(
+                    "Private key {} does not match certificate {}",
+                    key.display(),
+                    cert.display()
+                )
+            })?,
+        _ => builder.with_no_client_auth(),
+    };
+    config.enable_sni = !opt.no_sni;
+    config.alpn_protocols = alpn_protocols(opt);
+    if let Some(keylog) = opt.keylog.as_deref().and_then(open_keylog) {
+        config.key_log = keylog;
+    }
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Treats a peer closing without close_notify as a normal EOF, like OpenSSL does.
+struct IgnoreUnexpectedEof<S>(S);
+
+impl<S: AsyncRead + Unpin> AsyncRead for IgnoreUnexpectedEof<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Poll::Ready(Ok(()))
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IgnoreUnexpectedEof<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+fn version_str(state: &CommonState) -> &'static str {
+    match state.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => "TLSv1.2",
+        Some(ProtocolVersion::TLSv1_3) => "TLSv1.3",
+        _ => "unknown",
+    }
+}
+
+fn current_cipher(state: &CommonState) -> String {
+    match state.negotiated_cipher_suite() {
+        Some(suite) => format!("{:?}", suite.suite()),
+        None => "none".to_string(),
+    }
+}
+
+fn selected_alpn(state: &CommonState) -> String {
+    match state.alpn_protocol() {
+        Some(protocol) => String::from_utf8_lossy(protocol).into_owned(),
+        None => "none".to_string(),
+    }
+}
+
+fn subject_alt_names(certificate: &X509Certificate) -> Vec<String> {
+    let Ok(Some(names)) = certificate.subject_alternative_name() else {
+        return vec![];
+    };
+    names
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(dns_name) => Some(dns_name.to_string()),
+            GeneralName::IPAddress(&[a, b, c, d]) => Some(IpAddr::from([a, b, c, d]).to_string()),
+            GeneralName::IPAddress(octets) => <[u8; 16]>::try_from(*octets)
+                .ok()
+                .map(|octets| IpAddr::from(octets).to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A one-line description of a completed handshake and the peer's certificate.
+fn handshake_summary(state: &CommonState, sni: Option<&str>) -> String {
+    let mut fields = vec![
+        format!("version {}", version_str(state)),
+        format!("cipher {}", current_cipher(state)),
+        format!("ALPN {}", selected_alpn(state)),
+        format!("SNI {}", sni.unwrap_or("none")),
+    ];
+    if let Some(der) = state.peer_certificates().and_then(<[_]>::first) {
+        match X509Certificate::from_der(der) {
+            Ok((_, certificate)) => {
+                fields.push(format!("subject {}", certificate.subject()));
+                fields.push(format!("issuer {}", certificate.issuer()));
+                fields.push(format!(
+                    "SANs {}",
+                    subject_alt_names(&certificate).join(", ")
+                ));
+                fields.push(format!("expires {}", certificate.validity().not_after));
+            }
+            Err(e) => fields.push(format!("certificate <{e}>")),
+        }
+    }
+    fields.join("; ")
+}
+
+fn save_peer_chain(opt: &Opt, saved_certs: &SavedCerts, chain: &[CertificateDer]) {
+    let Some(leaf) = chain.first() else {
+        return;
+    };
+    save_chain(opt, saved_certs, &hex(&Sha256::digest(leaf)), || {
+        let mut pem = String::new();
+        for certificate in chain {
+            pem.push_str("-----BEGIN CERTIFICATE-----\n");
+            for line in STANDARD.encode(certificate).as_bytes().chunks(64) {
+                pem.push_str(std::str::from_utf8(line)?);
+                pem.push('\n');
+            }
+            pem.push_str("-----END CERTIFICATE-----\n");
+        }
+        Ok(pem.into_bytes())
+    });
+}
+
+/// Upstream certificates expiring within this many days get a warning.
+const EXPIRY_WARNING_DAYS: i64 = 30;
+
+fn warn_on_expiry(state: &CommonState) {
+    let Some(der) = state.peer_certificates().and_then(<[_]>::first) else {
+        return;
+    };
+    let Ok((_, certificate)) = X509Certificate::from_der(der) else {
+        return;
+    };
+    let not_after = certificate.validity().not_after;
+    let now = OffsetDateTime::now_utc();
+    if not_after.timestamp() < now.unix_timestamp() {
+        warn!("Upstream certificate expired at {not_after}");
+    } else if not_after.timestamp() < (now + Duration::days(EXPIRY_WARNING_DAYS)).unix_timestamp() {
+        warn!("Upstream certificate expires soon, at {not_after}");
+    }
+}
+
+pub async fn wrap_ssl_client(
+    opt: &Opt,
+    stream: AsyncStream,
+    connector: &TlsConnector,
+    saved_certs: &SavedCerts,
+) -> Result<(AsyncStream, Option<String>)> {
+    let server_name = opt.sni.as_deref().unwrap_or(&opt.hostname);
+    let name = ServerName::try_from(server_name.to_string())
+        .with_context(|| format!("Invalid server name {server_name:?}"))?;
+
+    // Like OpenSSL, rustls never sends an IP address as SNI.
+    let sni = match name {
+        ServerName::DnsName(_) if !opt.no_sni => Some(server_name),
+        _ => None,
+    };
+    match sni {
+        Some(sni) => info!("Sending SNI {sni}"),
+        None => info!("Sending no SNI"),
+    }
+    let stream = connector.connect(name, stream).await.map_err(|e| {
+        let verify_error = e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<::rustls::Error>())
+            .filter(|inner| matches!(inner, ::rustls::Error::InvalidCertificate(_)));
+        if let Some(::rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(
+            other,
+        )))) = verify_error
+        {
+            if let Some(mismatch) = other.downcast_ref::<PinMismatch>() {
+                info!("Upstream {mismatch}");
+                return anyhow::Error::new(e).context("Upstream public key pin mismatch");
+            }
+        }
+        match verify_error.map(ToString::to_string) {
+            Some(verify_error) => anyhow::Error::new(e).context(format!(
+                "Upstream certificate verification failed: {verify_error}"
+            )),
+            None => anyhow::Error::new(e).context("TLS handshake with upstream failed"),
+        }
+    })?;
+
+    let (_, connection) = stream.get_ref();
+    if opt.tls_info || opt.show_data {
+        info!("Upstream TLS: {}", handshake_summary(connection, sni));
+    } else {
+        info!(
+            "Upstream TLS version: {}, cipher: {}",
+            version_str(connection),
+            current_cipher(connection)
+        );
+    }
+    // rustls caches client sessions per server name by default.
+    if connection.handshake_kind() == Some(HandshakeKind::Resumed) {
+        info!("Upstream TLS session resumed");
+    } else {
+        info!("Upstream TLS full handshake");
+    }
+    warn_on_expiry(connection);
+    if let Some(chain) = connection.peer_certificates() {
+        save_peer_chain(opt, saved_certs, chain);
+    }
+    let mut alpn = None;
+    if !opt.alpn.is_empty() {
+        let protocol = selected_alpn(connection);
+        if !(opt.tls_info || opt.show_data) {
+            info!("Upstream ALPN: {protocol}");
+        }
+        alpn = Some(protocol);
+    }
+
+    Ok((Box::pin(IgnoreUnexpectedEof(stream)), alpn))
+}
+
+pub async fn wrap_ssl_server(
+    opt: &Opt,
+    stream: AsyncStream,
+    acceptor: &TlsAcceptor,
+) -> Result<(AsyncStream, Option<String>)> {
+    let stream = acceptor
+        .accept(stream)
+        .await
+        .context("Client TLS handshake failed")?;
+
+    let (_, connection) = stream.get_ref();
+    if opt.tls_info || opt.show_data {
+        info!(
+            "Client TLS: {}",
+            handshake_summary(connection, connection.server_name())
+        );
+    } else {
+        info!(
+            "Client TLS version: {}, cipher: {}",
+            version_str(connection),
+            current_cipher(connection)
+        );
+    }
+    let mut alpn = None;
+    if !opt.alpn.is_empty() {
+        let protocol = selected_alpn(connection);
+        if !(opt.tls_info || opt.show_data) {
+            info!("Client ALPN: {protocol}");
+        }
+        alpn = Some(protocol);
+    }
+
+    Ok((Box::pin(IgnoreUnexpectedEof(stream)), alpn))
+}
+
+pub fn generate_acceptor(opt: &Opt) -> Result<TlsAcceptor> {
+    check_supported(opt)?;
+    let builder = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&protocol_versions(opt)?)?
+        .with_no_client_auth();
+    let mut config = match (&opt.cert, &opt.key) {
+        (Some(cert), Some(key)) => builder
+            .with_single_cert(load_certificate_chain(cert)?, load_private_key(key)?)
+            .with_context(|| {
+                format!(
+                    "Private key {} does not match certificate {}",
+                    key.display(),
+                    cert.display()
+                )
+            })?,
+        _ => {
+            let (key_der, cert_der) = generate_der(self_signed_params(opt), None)?;
+            builder.with_single_cert(
+                vec![CertificateDer::from(cert_der)],
+                PrivateKeyDer::Pkcs8(key_der.into()),
+            )?
+        }
+    };
+    config.alpn_protocols = alpn_protocols(opt);
+    if let Some(keylog) = opt.keylog.as_deref().and_then(open_keylog) {
+        config.key_log = keylog;
+    }
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
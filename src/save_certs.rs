@@ -0,0 +1,61 @@
+use crate::Opt;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+/// Leaf fingerprints of the upstream chains already written to --save-certs.
+pub type SavedCerts = Arc<Mutex<HashSet<String>>>;
+
+fn file_name_component(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Writes an upstream chain to --save-certs unless one with the same leaf was already saved.
+pub fn save_chain(
+    opt: &Opt,
+    saved_certs: &SavedCerts,
+    leaf_fingerprint: &str,
+    chain_pem: impl FnOnce() -> Result<Vec<u8>>,
+) {
+    let Some(dir) = &opt.save_certs else {
+        return;
+    };
+    if !saved_certs
+        .lock()
+        .unwrap()
+        .insert(leaf_fingerprint.to_string())
+    {
+        return;
+    }
+
+    let server_name = opt.sni.as_deref().unwrap_or(&opt.hostname);
+    let path = dir.join(format!(
+        "{}_{leaf_fingerprint}.pem",
+        file_name_component(server_name)
+    ));
+    let res = chain_pem().and_then(|pem| {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(&path, pem)?;
+        Ok(())
+    });
+    match res {
+        Ok(()) => info!("Saved upstream certificate chain to {}", path.display()),
+        Err(e) => {
+            // Let a later connection try again.
+            saved_certs.lock().unwrap().remove(leaf_fingerprint);
+            error!(
+                "Failed to save certificate chain to {}: {e:#}",
+                path.display()
+            );
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::Opt;
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Applies --nodelay, --tcp-keepalive, --so-rcvbuf and --so-sndbuf to `stream`.
+pub fn tune(opt: &Opt, stream: &TcpStream) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    if opt.nodelay {
+        socket.set_nodelay(true)?;
+    }
+    if let Some(secs) = opt.tcp_keepalive {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = opt.so_rcvbuf {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = opt.so_sndbuf {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+/// A number of seconds or bytes, which can't be 0.
+pub fn parse_positive<T: std::str::FromStr + Default + PartialEq>(
+    value: &str,
+) -> Result<T, String> {
+    match value.parse() {
+        Ok(n) if n != T::default() => Ok(n),
+        Ok(_) => Err("must be at least 1".to_string()),
+        Err(_) => Err(format!("invalid number: {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn options_are_set_on_the_socket() {
+        let opt = Opt::from_iter([
+            "tcp-proxy",
+            "localhost",
+            "--nodelay",
+            "--tcp-keepalive",
+            "30",
+            "--so-rcvbuf",
+            "65536",
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        tune(&opt, &stream).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        // Linux doubles what is asked for to leave room for bookkeeping.
+        assert!(socket.recv_buffer_size().unwrap() >= 65536);
+    }
+
+    #[test]
+    fn zero_and_garbage_are_refused() {
+        for args in [
+            ["--tcp-keepalive", "0"],
+            ["--so-sndbuf", "0"],
+            ["--so-rcvbuf", "x"],
+        ] {
+            let args = ["tcp-proxy", "localhost"].into_iter().chain(args);
+            assert!(Opt::from_iter_safe(args).is_err());
+        }
+    }
+}
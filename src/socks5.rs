@@ -0,0 +1,432 @@
+use crate::{host_matches, Opt, Target, Upstream};
+use anyhow::{bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The port of --socks5 unless it has one, as for ssh -D.
+const DEFAULT_PORT: u16 = 1080;
+
+/// A username and password for --socks5-auth.
+#[derive(Clone)]
+pub struct Credentials {
+    user: String,
+    password: String,
+}
+
+impl FromStr for Credentials {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, password) = s.split_once(':').ok_or("expected <user>:<pass>")?;
+        if user.is_empty() || user.len() > 255 || password.len() > 255 {
+            return Err("expected a user and a password of at most 255 bytes each");
+        }
+        Ok(Credentials {
+            user: user.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+/// A destination that --socks5-server clients may ask for, as <pattern>[:<port>]
+/// with a pattern matching exactly or any single label in place of a leading `*.`.
+#[derive(Clone)]
+pub struct Allowed {
+    pattern: String,
+    port: Option<u16>,
+}
+
+impl FromStr for Allowed {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Upstream { host, port } = s.parse()?;
+        Ok(Allowed {
+            pattern: host,
+            port,
+        })
+    }
+}
+
+impl Allowed {
+    fn matches(&self, host: &str, port: u16) -> bool {
+        host_matches(&self.pattern, host) && self.port.is_none_or(|allowed| allowed == port)
+    }
+}
+
+/// The --socks5 proxy to connect to upstreams through.
+#[derive(Clone, Copy)]
+pub struct Socks5<'a> {
+    pub server: &'a Upstream,
+    pub credentials: Option<&'a Credentials>,
+}
+
+impl Socks5<'_> {
+    /// Connects to the proxy and has it connect to `target`, resolving its hostname
+    /// itself unless it is an IP address.
+    pub async fn connect(self, target: Target<'_>) -> Result<TcpStream> {
+        let Target::Tcp(host, port) = target else {
+            bail!("SOCKS5 can't connect to {target}");
+        };
+        let server_port = self.server.port.unwrap_or(DEFAULT_PORT);
+        let server = Target::Tcp(&self.server.host, server_port);
+        let mut stream = TcpStream::connect((self.server.host.as_str(), server_port))
+            .await
+            .with_context(|| format!("Failed to connect to SOCKS5 proxy {server}"))?;
+        handshake(&mut stream, self.credentials, host, port)
+            .await
+            .with_context(|| format!("SOCKS5 proxy {server} failed to connect to {host}:{port}"))?;
+        Ok(stream)
+    }
+}
+
+async fn handshake(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    credentials: Option<&Credentials>,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    // Offer no authentication, and username/password if there are credentials.
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[5, 2, 0x00, 0x02],
+        None => &[5, 1, 0x00],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match choice {
+        [5, 0x00] => {}
+        [5, 0x02] => {
+            let Some(credentials) = credentials else {
+                bail!("The proxy requires authentication, see --socks5-auth")
+            };
+            let mut request = vec![1, credentials.user.len() as u8];
+            request.extend(credentials.user.as_bytes());
+            request.push(credentials.password.len() as u8);
+            request.extend(credentials.password.as_bytes());
+            stream.write_all(&request).await?;
+            let mut status = [0; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                bail!("The proxy rejected the --socks5-auth credentials");
+            }
+        }
+        [5, 0xff] => bail!("The proxy accepts none of the offered authentication methods"),
+        [5, method] => {
+            bail!("The proxy chose authentication method {method:#04x}, which wasn't offered")
+        }
+        [version, _] => bail!("Not a SOCKS5 proxy, it answered with version {version}"),
+    }
+
+    let mut request = vec![5, 0x01, 0];
+    match host.parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend(ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend(ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).context("The hostname is too long for SOCKS5")?;
+            request.extend([0x03, len]);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        bail!("{}", reply_error(reply[1]));
+    }
+    // Skip the address the proxy connected from.
+    let len = match reply[3] {
+        0x01 => 4,
+        0x03 => stream.read_u8().await?.into(),
+        0x04 => 16,
+        kind => bail!("The proxy answered with unknown address type {kind:#04x}"),
+    };
+    stream.read_exact(&mut vec![0; len + 2]).await?;
+    Ok(())
+}
+
+/// Does the handshake of a --socks5-server client up to its CONNECT request, and
+/// returns the host and port it asked for. Requests that can't be served are
+/// answered here, while served ones need a [`reply`] once connecting is done.
+pub async fn accept(
+    opt: &Opt,
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> Result<(String, u16)> {
+    let [version, count] = read_array(stream).await?;
+    if version != 5 {
+        bail!("Not a SOCKS5 client, it sent version {version}");
+    }
+    let mut methods = vec![0; count.into()];
+    stream.read_exact(&mut methods).await?;
+    let method = match opt.socks5_server_auth {
+        Some(_) => 0x02,
+        None => 0x00,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[5, 0xff]).await?;
+        match &opt.socks5_server_auth {
+            Some(_) => bail!("The SOCKS5 client doesn't offer username/password authentication"),
+            None => bail!("The SOCKS5 client requires authentication, see --socks5-server-auth"),
+        }
+    }
+    stream.write_all(&[5, method]).await?;
+    if let Some(credentials) = &opt.socks5_server_auth {
+        let [_, len] = read_array(stream).await?;
+        let mut user = vec![0; len.into()];
+        stream.read_exact(&mut user).await?;
+        let mut password = vec![0; stream.read_u8().await?.into()];
+        stream.read_exact(&mut password).await?;
+        let valid =
+            user == credentials.user.as_bytes() && password == credentials.password.as_bytes();
+        stream.write_all(&[1, if valid { 0 } else { 1 }]).await?;
+        if !valid {
+            bail!(
+                "The SOCKS5 client authenticated as {:?} with the wrong credentials",
+                String::from_utf8_lossy(&user)
+            );
+        }
+    }
+
+    let [_, command, _, kind] = read_array(stream).await?;
+    let host = match kind {
+        0x01 => Ipv4Addr::from(read_array::<4>(stream).await?).to_string(),
+        0x03 => {
+            let mut host = vec![0; stream.read_u8().await?.into()];
+            stream.read_exact(&mut host).await?;
+            String::from_utf8_lossy(&host).into_owned()
+        }
+        0x04 => Ipv6Addr::from(read_array::<16>(stream).await?).to_string(),
+        kind => {
+            send_reply(stream, 0x08).await?;
+            bail!("The SOCKS5 client asked for unknown address type {kind:#04x}");
+        }
+    };
+    let port = u16::from_be_bytes(read_array(stream).await?);
+    let destination = Target::Tcp(&host, port);
+    if command != 0x01 {
+        send_reply(stream, 0x07).await?;
+        bail!("The SOCKS5 client asked for command {command:#04x} to {destination}, only CONNECT is supported");
+    }
+    // A hostname like this would name a Unix domain socket for the upstream.
+    let unix = host.starts_with("unix:");
+    let allowed = opt.socks5_allow.is_empty()
+        || opt
+            .socks5_allow
+            .iter()
+            .any(|allowed| allowed.matches(&host, port));
+    if unix || !allowed {
+        send_reply(stream, 0x02).await?;
+        bail!("The SOCKS5 client asked for {destination}, which --socks5-allow doesn't allow");
+    }
+    Ok((host, port))
+}
+
+/// Tells the client whether connecting to its destination worked.
+pub async fn reply(
+    stream: &mut (impl AsyncWrite + Unpin),
+    result: Result<(), &anyhow::Error>,
+) -> Result<()> {
+    let code = match result {
+        Ok(()) => 0x00,
+        Err(e) => match e.root_cause().downcast_ref::<std::io::Error>() {
+            Some(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => 0x05,
+            _ => 0x01,
+        },
+    };
+    send_reply(stream, code).await
+}
+
+async fn send_reply(stream: &mut (impl AsyncWrite + Unpin), code: u8) -> Result<()> {
+    // The address connected from isn't one the client can use, so it's left out.
+    stream
+        .write_all(&[5, code, 0, 0x01, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+async fn read_array<const N: usize>(stream: &mut (impl AsyncRead + Unpin)) -> Result<[u8; N]> {
+    let mut array = [0; N];
+    stream.read_exact(&mut array).await?;
+    Ok(array)
+}
+
+fn reply_error(code: u8) -> String {
+    match code {
+        0x01 => "general SOCKS server failure".to_string(),
+        0x02 => "connection not allowed by ruleset".to_string(),
+        0x03 => "network unreachable".to_string(),
+        0x04 => "host unreachable".to_string(),
+        0x05 => "connection refused".to_string(),
+        0x06 => "TTL expired".to_string(),
+        0x07 => "command not supported".to_string(),
+        0x08 => "address type not supported".to_string(),
+        code => format!("unknown reply code {code:#04x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    /// Runs the handshake against a proxy that expects each request of `exchanges`
+    /// in turn and answers it with the reply.
+    async fn run(
+        credentials: Option<&str>,
+        host: &str,
+        exchanges: &'static [(&'static [u8], &'static [u8])],
+    ) -> Result<()> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let proxy = tokio::spawn(async move {
+            for (request, reply) in exchanges {
+                let mut got = vec![0; request.len()];
+                server.read_exact(&mut got).await.unwrap();
+                assert_eq!(&got, request);
+                server.write_all(reply).await.unwrap();
+            }
+        });
+        let credentials = credentials.map(|s| s.parse().unwrap());
+        let result = handshake(&mut client, credentials.as_ref(), host, 443).await;
+        proxy.await.unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn hostnames_are_resolved_by_the_proxy() {
+        run(
+            Some("user:pass"),
+            "example.com",
+            &[
+                (&[5, 2, 0, 2], &[5, 2]),
+                (b"\x01\x04user\x04pass", &[1, 0]),
+                (
+                    b"\x05\x01\x00\x03\x0bexample.com\x01\xbb",
+                    &[5, 0, 0, 1, 127, 0, 0, 1, 0x1f, 0x90],
+                ),
+            ],
+        )
+        .await
+        .unwrap();
+
+        run(
+            None,
+            "192.0.2.1",
+            &[
+                (&[5, 1, 0], &[5, 0]),
+                (
+                    &[5, 1, 0, 1, 192, 0, 2, 1, 0x01, 0xbb],
+                    &[5, 0, 0, 3, 1, b'x', 0, 0],
+                ),
+            ],
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn failures_are_explained() {
+        let refused = run(
+            None,
+            "::1",
+            &[
+                (&[5, 1, 0], &[5, 0]),
+                (
+                    &[
+                        5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x01, 0xbb,
+                    ],
+                    &[5, 5, 0, 1],
+                ),
+            ],
+        );
+        assert_eq!(refused.await.unwrap_err().to_string(), "connection refused");
+
+        let rejected = run(
+            Some("user:wrong"),
+            "example.com",
+            &[
+                (&[5, 2, 0, 2], &[5, 2]),
+                (b"\x01\x04user\x05wrong", &[1, 1]),
+            ],
+        );
+        assert_eq!(
+            rejected.await.unwrap_err().to_string(),
+            "The proxy rejected the --socks5-auth credentials"
+        );
+
+        let unauthenticated = run(None, "example.com", &[(&[5, 1, 0], &[5, 0xff])]);
+        assert_eq!(
+            unauthenticated.await.unwrap_err().to_string(),
+            "The proxy accepts none of the offered authentication methods"
+        );
+    }
+
+    /// Has a --socks5-server with `args` accept a client sending `request`, and
+    /// returns what it asked for and what the server answered.
+    async fn accept_request(args: &[&str], request: &[u8]) -> (Result<(String, u16)>, Vec<u8>) {
+        let opt = Opt::from_iter(["tcp-proxy", "--socks5-server"].iter().chain(args));
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+        let accepted = accept(&opt, &mut server).await;
+        drop(server);
+        let mut answer = vec![];
+        client.read_to_end(&mut answer).await.unwrap();
+        (accepted, answer)
+    }
+
+    #[tokio::test]
+    async fn clients_are_asked_for_their_destination() {
+        let request = b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+        let (accepted, answer) = accept_request(&[], request).await;
+        assert_eq!(accepted.unwrap(), ("example.com".to_string(), 443));
+        assert_eq!(answer, [5, 0]);
+
+        let args = ["--socks5-server-auth", "user:pass"];
+        let request = b"\x05\x02\x00\x02\x01\x04user\x04pass\x05\x01\x00\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x00\x50";
+        let (accepted, answer) = accept_request(&args, request).await;
+        assert_eq!(accepted.unwrap(), ("::1".to_string(), 80));
+        assert_eq!(answer, [5, 2, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn clients_are_refused_what_isnt_allowed() {
+        let args = ["--socks5-server-auth", "user:pass"];
+        let (accepted, answer) = accept_request(&args, b"\x05\x01\x00").await;
+        assert!(accepted.is_err());
+        assert_eq!(answer, [5, 0xff]);
+
+        let request = b"\x05\x01\x02\x01\x04user\x05wrong";
+        let (accepted, answer) = accept_request(&args, request).await;
+        assert!(accepted.is_err());
+        assert_eq!(answer, [5, 2, 1, 1]);
+
+        let args = ["--socks5-allow", "*.example.com:443"];
+        let refused = [5, 0, 5, 2, 0, 1, 0, 0, 0, 0, 0, 0];
+        for request in [
+            &b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x01\xbb"[..],
+            b"\x05\x01\x00\x05\x01\x00\x03\x0fwww.example.com\x00\x50",
+            b"\x05\x01\x00\x05\x01\x00\x03\x0aunix:/sock\x01\xbb",
+        ] {
+            let (accepted, answer) = accept_request(&args, request).await;
+            assert!(accepted.is_err());
+            assert_eq!(answer, refused);
+        }
+        let request = b"\x05\x01\x00\x05\x01\x00\x03\x0fwww.example.com\x01\xbb";
+        let (accepted, _) = accept_request(&args, request).await;
+        assert_eq!(accepted.unwrap(), ("www.example.com".to_string(), 443));
+
+        let bind = b"\x05\x01\x00\x05\x02\x00\x01\x7f\x00\x00\x01\x01\xbb";
+        let (accepted, answer) = accept_request(&[], bind).await;
+        assert!(accepted.is_err());
+        assert_eq!(answer[3], 7);
+    }
+}
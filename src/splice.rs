@@ -0,0 +1,208 @@
+use crate::data_log::DataLog;
+use crate::logging::Direction;
+use crate::stats::Connection;
+use crate::{AsyncStream, Opt};
+use anyhow::Result;
+use socket2::SockRef;
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tracing::Level;
+
+/// The plain TCP sockets of both sides, if nothing needs to see the data
+/// forwarded between them, so it can be spliced.
+pub fn tcp_streams<'a>(
+    opt: &Opt,
+    incoming: &'a AsyncStream,
+    outgoing: &'a AsyncStream,
+) -> Option<(&'a TcpStream, &'a TcpStream)> {
+    // Data is shown by TRACE events, which SIGUSR1 may have turned on.
+    let observed = opt.no_splice
+        || opt.delay > 0
+        || opt.dump_dir.is_some()
+        || opt.pcap.is_some()
+        || opt.har.is_some()
+        || tracing::enabled!(Level::TRACE);
+    if observed {
+        return None;
+    }
+    // Anything that wraps the sockets, like TLS or --replace, needs the data too.
+    let tcp = |stream: &'a AsyncStream| stream.as_ref().get_ref().as_any().downcast_ref();
+    Some((tcp(incoming)?, tcp(outgoing)?))
+}
+
+/// Like `crate::forward` for both directions at once, but moving the data through a
+/// pipe with splice(2) so it never is copied to the proxy.
+pub async fn forward(
+    opt: &Opt,
+    connection: &Connection,
+    data_log: &Mutex<&mut DataLog>,
+    incoming: &TcpStream,
+    outgoing: &TcpStream,
+) -> Result<()> {
+    tokio::try_join!(
+        forward_one(
+            opt,
+            connection,
+            data_log,
+            Direction::Incoming,
+            incoming,
+            outgoing
+        ),
+        forward_one(
+            opt,
+            connection,
+            data_log,
+            Direction::Outgoing,
+            outgoing,
+            incoming
+        ),
+    )?;
+    Ok(())
+}
+
+async fn forward_one(
+    opt: &Opt,
+    connection: &Connection,
+    data_log: &Mutex<&mut DataLog>,
+    direction: Direction,
+    from: &TcpStream,
+    to: &TcpStream,
+) -> Result<()> {
+    let pipe = Pipe::new(opt.buffer_size)?;
+    loop {
+        // The pipe is always emptied before it is filled again, so only `from` can
+        // make this block.
+        let n = from
+            .async_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), opt.buffer_size)
+            })
+            .await?;
+        data_log.lock().unwrap().spliced(opt, direction, n);
+        connection.forwarded(direction, n);
+        if n == 0 {
+            // Shutting down fails if the peer is already gone, which is no error.
+            let _ = SockRef::from(to).shutdown(Shutdown::Write);
+            return Ok(());
+        }
+        let mut left = n;
+        while left > 0 {
+            left -= to
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)
+                })
+                .await?;
+        }
+    }
+}
+
+/// Moves up to `len` bytes from `from` to `to` without blocking, where one of them
+/// is a pipe.
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    // SAFETY: Both descriptors are open for the duration of the call, and null
+    // offsets make the kernel use and update the file positions.
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            flags,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// The two ends of a pipe, closed when dropped.
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    /// A pipe holding `size` bytes if the kernel allows that much.
+    fn new(size: usize) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both are new descriptors owned by nothing else.
+        let pipe = unsafe {
+            Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            }
+        };
+        // The default of 64 KiB is used if this fails, like beyond
+        // /proc/sys/fs/pipe-max-size.
+        // SAFETY: F_SETPIPE_SZ only takes an integer.
+        unsafe {
+            libc::fcntl(
+                pipe.write.as_raw_fd(),
+                libc::F_SETPIPE_SZ,
+                size as libc::c_int,
+            )
+        };
+        Ok(pipe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Peer;
+    use crate::stats::Stats;
+    use std::sync::Arc;
+    use structopt::StructOpt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn both_directions_are_spliced_until_each_side_closes() {
+        let opt = Opt::from_iter(["tcp-proxy", "localhost"]);
+        let stats = Arc::new(Stats::default());
+        let connection = stats.open(0, &Peer::Tcp(([127, 0, 0, 1], 1).into()));
+        let mut data_log = DataLog::new(0);
+        let data_log = Mutex::new(&mut data_log);
+        let (mut client, incoming) = pair().await;
+        let (outgoing, mut upstream) = pair().await;
+
+        let request: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        let client_side = async {
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        };
+        let upstream_side = async {
+            let mut received = vec![];
+            upstream.read_to_end(&mut received).await.unwrap();
+            upstream.write_all(b"done").await.unwrap();
+            upstream.shutdown().await.unwrap();
+            received
+        };
+        let splicing = forward(&opt, &connection, &data_log, &incoming, &outgoing);
+        let (result, response, received) = tokio::join!(splicing, client_side, upstream_side);
+        result.unwrap();
+        assert!(received == request, "got {} bytes", received.len());
+        assert_eq!(response, b"done");
+        assert_eq!(data_log.lock().unwrap().totals(), (1 << 20, 4));
+    }
+}
@@ -0,0 +1,933 @@
+use crate::certgen::{certificate_params, generate_der, self_signed_names, self_signed_params};
+use crate::save_certs::{save_chain, SavedCerts};
+use crate::{AsyncStream, Opt, TlsVersion};
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use openssl::asn1::Asn1Time;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::ssl::{
+    select_next_proto, AlpnError, NameType, Ssl, SslAcceptor, SslAcceptorBuilder, SslConnector,
+    SslContext, SslContextBuilder, SslMethod, SslOptions, SslRef, SslSession, SslSessionCacheMode,
+    SslVerifyMode, SslVersion,
+};
+use openssl::stack::StackRef;
+use openssl::x509::{X509Name, X509NameRef, X509Ref, X509VerifyResult, X509};
+use rcgen::{Certificate, CertificateParams, KeyPair};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslStream;
+use tracing::{error, info, warn};
+
+fn ssl_version(version: TlsVersion) -> SslVersion {
+    match version {
+        TlsVersion::Tls1_0 => SslVersion::TLS1,
+        TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+        TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+        TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+    }
+}
+
+/// Opens the NSS key log file once, shared by every context that logs to it.
+fn open_keylog(path: &Path) -> Option<&'static Mutex<File>> {
+    static KEYLOG: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+    KEYLOG
+        .get_or_init(
+            || match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    warn!(
+                        parent: None,
+                        "Not logging TLS keys, failed to open {}: {e}",
+                        path.display()
+                    );
+                    None
+                }
+            },
+        )
+        .as_ref()
+}
+
+/// Settings shared by the connector and the acceptor.
+fn configure_context(opt: &Opt, context_builder: &mut SslContextBuilder) -> Result<()> {
+    allow_legacy_versions(opt, context_builder)?;
+    if let Some(version) = opt.tls_min_version {
+        context_builder.set_min_proto_version(Some(ssl_version(version)))?;
+    }
+    if let Some(version) = opt.tls_max_version {
+        context_builder.set_max_proto_version(Some(ssl_version(version)))?;
+    }
+    let wants_tls1_3 = opt.tls_min_version == Some(TlsVersion::Tls1_3)
+        || opt.tls_max_version == Some(TlsVersion::Tls1_3)
+        || opt.ciphersuites.is_some();
+    if wants_tls1_3 {
+        // The acceptor's Mozilla intermediate profile disables TLS 1.3 by default.
+        context_builder.clear_options(SslOptions::NO_TLSV1_3);
+    }
+
+    if let Some(path) = &opt.keylog {
+        if let Some(keylog) = open_keylog(path) {
+            context_builder.set_keylog_callback(move |_, line| {
+                let mut file = keylog.lock().unwrap();
+                if let Err(e) = writeln!(file, "{line}") {
+                    error!("Failed to write to key log: {e}");
+                }
+            });
+        }
+    }
+
+    if let Some(ciphers) = &opt.ciphers {
+        context_builder
+            .set_cipher_list(ciphers)
+            .with_context(|| format!("Invalid --ciphers {ciphers:?}"))?;
+    }
+    if let Some(ciphersuites) = &opt.ciphersuites {
+        context_builder
+            .set_ciphersuites(ciphersuites)
+            .with_context(|| format!("Invalid --ciphersuites {ciphersuites:?}"))?;
+    }
+
+    Ok(())
+}
+
+fn allow_legacy_versions(opt: &Opt, context_builder: &mut SslContextBuilder) -> Result<()> {
+    let legacy = [opt.tls_min_version, opt.tls_max_version]
+        .into_iter()
+        .flatten()
+        .any(|version| version < TlsVersion::Tls1_2);
+    if legacy {
+        // OpenSSL refuses TLS 1.0 and 1.1 at its default security level.
+        context_builder.clear_options(SslOptions::NO_TLSV1 | SslOptions::NO_TLSV1_1);
+        context_builder.set_cipher_list("DEFAULT:@SECLEVEL=0")?;
+    }
+
+    Ok(())
+}
+
+pub type Acceptor = SslAcceptor;
+
+pub struct Connector {
+    connector: SslConnector,
+    /// The newest session for each server name, offered by later connections to it.
+    sessions: Arc<Mutex<HashMap<String, SslSession>>>,
+}
+
+/// Where each upstream `Ssl` remembers the server name its session is cached under.
+fn session_key_index() -> Index<Ssl, String> {
+    static INDEX: OnceLock<Index<Ssl, String>> = OnceLock::new();
+    *INDEX.get_or_init(|| Ssl::new_ex_index().unwrap())
+}
+
+fn public_key_pin(certificate: &X509Ref) -> Result<[u8; 32]> {
+    Ok(sha256(&certificate.public_key()?.public_key_to_der()?))
+}
+
+/// Fails the handshake unless the upstream's public key matches a --pin-sha256,
+/// checking the chain as well only with --verify-upstream.
+fn set_pin_verification(opt: &Opt, ssl: &mut SslRef) {
+    let opt = opt.clone();
+    ssl.set_verify_callback(SslVerifyMode::PEER, move |preverify_ok, ctx| {
+        if opt.verify_upstream && !preverify_ok {
+            return false;
+        }
+        if ctx.error_depth() != 0 {
+            return true;
+        }
+        let Some(pin) = ctx
+            .current_cert()
+            .and_then(|certificate| public_key_pin(certificate).ok())
+        else {
+            return false;
+        };
+        if !opt.pin_sha256.contains(&pin) {
+            info!(
+                "Upstream public key {} matches no --pin-sha256",
+                STANDARD.encode(pin)
+            );
+            ctx.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+            return false;
+        }
+        true
+    });
+}
+
+pub fn generate_connector(opt: &Opt) -> Result<Connector> {
+    let mut connector_builder =
+        SslConnector::builder(SslMethod::tls()).context("Failed to create TLS connector")?;
+    configure_context(opt, &mut connector_builder)?;
+    if opt.verify_upstream {
+        connector_builder.set_verify(SslVerifyMode::PEER);
+        if let Some(ca_file) = &opt.upstream_ca {
+            connector_builder
+                .set_ca_file(ca_file)
+                .with_context(|| format!("Failed to load CA bundle {}", ca_file.display()))?;
+        }
+    } else {
+        connector_builder.set_verify(SslVerifyMode::NONE);
+    }
+    if let (Some(cert), Some(key)) = (&opt.client_cert, &opt.client_key) {
+        set_certificate_from_files(&mut connector_builder, cert, key)?;
+    }
+    if !opt.alpn.is_empty() {
+        connector_builder.set_alpn_protos(&alpn_wire_format(&opt.alpn))?;
+    }
+
+    let sessions = Arc::<Mutex<HashMap<String, SslSession>>>::default();
+    connector_builder.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+    let new_sessions = sessions.clone();
+    connector_builder.set_new_session_callback(move |ssl, session| {
+        if let Some(key) = ssl.ex_data(session_key_index()) {
+            new_sessions.lock().unwrap().insert(key.clone(), session);
+        }
+    });
+
+    Ok(Connector {
+        connector: connector_builder.build(),
+        sessions,
+    })
+}
+
+pub async fn wrap_ssl_client(
+    opt: &Opt,
+    stream: AsyncStream,
+    connector: &Connector,
+    saved_certs: &SavedCerts,
+) -> Result<(AsyncStream, Option<String>)> {
+    let server_name = opt.sni.as_deref().unwrap_or(&opt.hostname);
+    let mut ssl = connector
+        .connector
+        .configure()
+        .context("Failed to configure upstream TLS")?
+        .use_server_name_indication(!opt.no_sni)
+        .into_ssl(server_name)
+        .with_context(|| format!("Failed to set up upstream TLS for {server_name}"))?;
+    if !opt.pin_sha256.is_empty() {
+        set_pin_verification(opt, &mut ssl);
+    }
+    ssl.set_ex_data(session_key_index(), server_name.to_string());
+    let session = connector.sessions.lock().unwrap().get(server_name).cloned();
+    if let Some(session) = session {
+        // SAFETY: every cached session was negotiated with this connector's context.
+        unsafe { ssl.set_session(&session)? };
+    }
+    let mut stream = SslStream::new(ssl, stream).context("Failed to set up upstream TLS")?;
+
+    match sent_sni(&stream) {
+        Some(sni) => info!("Sending SNI {sni}"),
+        None => info!("Sending no SNI"),
+    }
+    connect_ssl(&mut stream).await?;
+    if opt.show_data {
+        if let Some(certificate) = stream.ssl().certificate() {
+            info!(
+                "Presented client certificate {}",
+                format_name(certificate.subject_name())
+            );
+        }
+    }
+    if opt.tls_info || opt.show_data {
+        info!("Upstream TLS: {}", handshake_summary(&stream));
+    } else {
+        info!(
+            "Upstream TLS version: {}, cipher: {}",
+            stream.ssl().version_str(),
+            current_cipher(&stream)
+        );
+    }
+    if stream.ssl().session_reused() {
+        info!("Upstream TLS session resumed");
+    } else {
+        info!("Upstream TLS full handshake");
+    }
+    if let Some(certificate) = stream.ssl().peer_certificate() {
+        warn_on_expiry(&certificate)?;
+    }
+    if let Some(chain) = stream.ssl().peer_cert_chain() {
+        save_peer_chain(opt, saved_certs, chain);
+    }
+    let mut alpn = None;
+    if !opt.alpn.is_empty() {
+        let protocol = selected_alpn(&stream);
+        if !(opt.tls_info || opt.show_data) {
+            info!("Upstream ALPN: {protocol}");
+        }
+        alpn = Some(protocol);
+    }
+
+    Ok((Box::pin(stream), alpn))
+}
+
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = match entry.data().as_utf8() {
+                Ok(value) => value.to_string(),
+                Err(_) => String::from_utf8_lossy(entry.data().as_slice()).into_owned(),
+            };
+            format!("{key}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn fingerprint(certificate: &X509Ref) -> String {
+    match certificate.digest(MessageDigest::sha256()) {
+        Ok(digest) => digest
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<Vec<_>>()
+            .join(":"),
+        Err(e) => format!("<{e}>"),
+    }
+}
+
+fn alpn_wire_format(protocols: &[String]) -> Vec<u8> {
+    let mut wire = vec![];
+    for protocol in protocols {
+        wire.push(protocol.len() as u8);
+        wire.extend(protocol.as_bytes());
+    }
+    wire
+}
+
+fn current_cipher<S>(stream: &SslStream<S>) -> &'static str {
+    match stream.ssl().current_cipher() {
+        Some(cipher) => cipher.name(),
+        None => "none",
+    }
+}
+
+fn selected_alpn<S>(stream: &SslStream<S>) -> String {
+    match stream.ssl().selected_alpn_protocol() {
+        Some(protocol) => String::from_utf8_lossy(protocol).into_owned(),
+        None => "none".to_string(),
+    }
+}
+
+fn subject_alt_names(certificate: &X509Ref) -> Vec<String> {
+    let Some(names) = certificate.subject_alt_names() else {
+        return vec![];
+    };
+    names
+        .iter()
+        .filter_map(|name| {
+            if let Some(dns_name) = name.dnsname() {
+                return Some(dns_name.to_string());
+            }
+            match name.ipaddress()? {
+                &[a, b, c, d] => Some(IpAddr::from([a, b, c, d]).to_string()),
+                octets => <[u8; 16]>::try_from(octets)
+                    .ok()
+                    .map(|octets| IpAddr::from(octets).to_string()),
+            }
+        })
+        .collect()
+}
+
+/// A one-line description of a completed handshake and the peer's certificate.
+fn handshake_summary<S>(stream: &SslStream<S>) -> String {
+    let ssl = stream.ssl();
+    let mut fields = vec![
+        format!("version {}", ssl.version_str()),
+        format!("cipher {}", current_cipher(stream)),
+        format!("ALPN {}", selected_alpn(stream)),
+        format!(
+            "SNI {}",
+            ssl.servername(NameType::HOST_NAME).unwrap_or("none")
+        ),
+    ];
+    if let Some(certificate) = ssl.peer_certificate() {
+        fields.push(format!(
+            "subject {}",
+            format_name(certificate.subject_name())
+        ));
+        fields.push(format!("issuer {}", format_name(certificate.issuer_name())));
+        fields.push(format!(
+            "SANs {}",
+            subject_alt_names(&certificate).join(", ")
+        ));
+        fields.push(format!("expires {}", certificate.not_after()));
+    }
+    fields.join("; ")
+}
+
+fn save_peer_chain(opt: &Opt, saved_certs: &SavedCerts, chain: &StackRef<X509>) {
+    let Some(leaf) = chain.iter().next() else {
+        return;
+    };
+    let leaf_fingerprint = match leaf.digest(MessageDigest::sha256()) {
+        Ok(digest) => digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>(),
+        Err(e) => {
+            error!("Failed to fingerprint upstream certificate: {e}");
+            return;
+        }
+    };
+    save_chain(opt, saved_certs, &leaf_fingerprint, || {
+        let mut pem = vec![];
+        for certificate in chain {
+            pem.extend(certificate.to_pem()?);
+        }
+        Ok(pem)
+    });
+}
+
+/// Upstream certificates expiring within this many days get a warning.
+const EXPIRY_WARNING_DAYS: u32 = 30;
+
+fn warn_on_expiry(certificate: &X509Ref) -> Result<()> {
+    let not_after = certificate.not_after();
+    if not_after < Asn1Time::days_from_now(0)? {
+        warn!("Upstream certificate expired at {not_after}");
+    } else if not_after < Asn1Time::days_from_now(EXPIRY_WARNING_DAYS)? {
+        warn!("Upstream certificate expires soon, at {not_after}");
+    }
+    Ok(())
+}
+
+/// The server name a client-side stream will send in its ClientHello, if any.
+fn sent_sni<S>(stream: &SslStream<S>) -> Option<&str> {
+    stream.ssl().servername(NameType::HOST_NAME)
+}
+
+async fn connect_ssl<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut SslStream<S>) -> Result<()> {
+    if let Err(e) = Pin::new(&mut *stream).connect().await {
+        let verify_result = stream.ssl().verify_result();
+        if verify_result == X509VerifyResult::APPLICATION_VERIFICATION {
+            return Err(e).context("Upstream public key pin mismatch");
+        }
+        if verify_result != X509VerifyResult::OK {
+            return Err(e).context(format!(
+                "Upstream certificate verification failed: {}",
+                verify_result.error_string()
+            ));
+        }
+        return Err(e).context("TLS handshake with upstream failed");
+    }
+    Ok(())
+}
+
+pub async fn wrap_ssl_server(
+    opt: &Opt,
+    stream: AsyncStream,
+    acceptor: &SslAcceptor,
+) -> Result<(AsyncStream, Option<String>)> {
+    let ssl = Ssl::new(acceptor.context()).context("Failed to set up client TLS")?;
+    let mut stream = SslStream::new(ssl, stream).context("Failed to set up client TLS")?;
+    Pin::new(&mut stream)
+        .accept()
+        .await
+        .context("Client TLS handshake failed")?;
+
+    if let Some(certificate) = stream.ssl().peer_certificate() {
+        info!(
+            "Client certificate subject: {}, issuer: {}, SHA-256: {}",
+            format_name(certificate.subject_name()),
+            format_name(certificate.issuer_name()),
+            fingerprint(&certificate)
+        );
+    } else if opt.request_client_cert {
+        info!("No client certificate presented");
+    }
+    if opt.tls_info || opt.show_data {
+        info!("Client TLS: {}", handshake_summary(&stream));
+    } else {
+        info!(
+            "Client TLS version: {}, cipher: {}",
+            stream.ssl().version_str(),
+            current_cipher(&stream)
+        );
+    }
+    let mut alpn = None;
+    if !opt.alpn.is_empty() {
+        let protocol = selected_alpn(&stream);
+        if !(opt.tls_info || opt.show_data) {
+            info!("Client ALPN: {protocol}");
+        }
+        alpn = Some(protocol);
+    }
+
+    Ok((Box::pin(stream), alpn))
+}
+
+fn generate_certificate(
+    params: CertificateParams,
+    ca: Option<&Certificate>,
+) -> Result<(PKey<Private>, X509)> {
+    let (key_der, cert_der) = generate_der(params, ca)?;
+    let private_key = PKey::private_key_from_pkcs8(&key_der)?;
+    let certificate = X509::from_der(&cert_der)?;
+
+    Ok((private_key, certificate))
+}
+
+fn load_certificate_authority(cert_path: &Path, key_path: &Path) -> Result<Certificate> {
+    let cert_pem = std::fs::read_to_string(cert_path)
+        .with_context(|| format!("Failed to read CA certificate {}", cert_path.display()))?;
+    let key_pem = std::fs::read(key_path)
+        .with_context(|| format!("Failed to read CA private key {}", key_path.display()))?;
+
+    let certificate = X509::from_pem(cert_pem.as_bytes())
+        .with_context(|| format!("Invalid PEM CA certificate in {}", cert_path.display()))?;
+    let private_key = PKey::private_key_from_pem(&key_pem)
+        .with_context(|| format!("Invalid PEM CA private key in {}", key_path.display()))?;
+    if !certificate.public_key()?.public_eq(&private_key) {
+        bail!(
+            "CA private key {} does not match CA certificate {}",
+            key_path.display(),
+            cert_path.display()
+        );
+    }
+
+    // rcgen only understands PKCS#8 keys, so normalize whatever was given.
+    let key_pair = KeyPair::from_pem(std::str::from_utf8(
+        &private_key.private_key_to_pem_pkcs8()?,
+    )?)
+    .with_context(|| format!("Unsupported CA private key type in {}", key_path.display()))?;
+    let params = CertificateParams::from_ca_cert_pem(&cert_pem, key_pair)
+        .with_context(|| format!("Unsupported CA certificate in {}", cert_path.display()))?;
+
+    Ok(Certificate::from_params(params)?)
+}
+
+fn load_cached_certificate(
+    opt: &Opt,
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(PKey<Private>, X509)> {
+    let certificate = X509::from_pem(&std::fs::read(cert_path)?).context("corrupt certificate")?;
+    let private_key =
+        PKey::private_key_from_pem(&std::fs::read(key_path)?).context("corrupt private key")?;
+
+    if certificate.not_after() < Asn1Time::days_from_now(0)? {
+        bail!("certificate expired at {}", certificate.not_after());
+    }
+    if !certificate.public_key()?.public_eq(&private_key) {
+        bail!("private key does not match certificate");
+    }
+    check_cached_names(opt, &certificate)?;
+
+    Ok((private_key, certificate))
+}
+
+/// Fails unless `certificate` names what a newly generated one would, so changing
+/// the hostname or --san doesn't keep serving the old names.
+fn check_cached_names(opt: &Opt, certificate: &X509Ref) -> Result<()> {
+    let common_name = certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string())
+        .unwrap_or_default();
+    if common_name != opt.hostname {
+        bail!("certificate is for {common_name}, not {}", opt.hostname);
+    }
+    // Written like `subject_alt_names` gives them, and in any order.
+    let mut wanted: Vec<String> = self_signed_names(opt)
+        .into_iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => name.to_string(),
+        })
+        .collect();
+    let mut names = subject_alt_names(certificate);
+    wanted.sort();
+    names.sort();
+    if names != wanted {
+        bail!(
+            "certificate names {}, not {}",
+            names.join(", "),
+            wanted.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn store_cached_certificate(
+    cert_path: &Path,
+    key_path: &Path,
+    private_key: &PKey<Private>,
+    certificate: &X509,
+) -> Result<()> {
+    std::fs::write(cert_path, certificate.to_pem()?)?;
+
+    let mut key_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(key_path)?;
+    // The mode above only applies when the file is newly created.
+    key_file.set_permissions(Permissions::from_mode(0o600))?;
+    key_file.write_all(&private_key.private_key_to_pem_pkcs8()?)?;
+
+    Ok(())
+}
+
+fn cached_self_signed_certificate(
+    opt: &Opt,
+    ca: Option<&Certificate>,
+    dir: &Path,
+) -> Result<(PKey<Private>, X509)> {
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+
+    if cert_path.exists() || key_path.exists() {
+        match load_cached_certificate(opt, &cert_path, &key_path) {
+            Ok(pair) => {
+                info!(parent: None, "Using cached certificate from {}", cert_path.display());
+                return Ok(pair);
+            }
+            Err(e) => {
+                info!(parent: None, "Regenerating cached certificate in {}: {e:#}", dir.display())
+            }
+        }
+    }
+
+    let (private_key, certificate) = generate_certificate(self_signed_params(opt), ca)?;
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    store_cached_certificate(&cert_path, &key_path, &private_key, &certificate)
+        .with_context(|| format!("Failed to write certificate to {}", dir.display()))?;
+    info!(parent: None, "Stored generated certificate in {}", cert_path.display());
+
+    Ok((private_key, certificate))
+}
+
+fn set_self_signed_certificate(
+    opt: &Opt,
+    ca: Option<&Certificate>,
+    acceptor_builder: &mut SslAcceptorBuilder,
+) -> Result<()> {
+    let (private_key, certificate) = match &opt.cert_cache_dir {
+        Some(dir) => cached_self_signed_certificate(opt, ca, dir)?,
+        None => generate_certificate(self_signed_params(opt), ca)?,
+    };
+
+    acceptor_builder.set_private_key(&private_key)?;
+    acceptor_builder.set_certificate(&certificate)?;
+
+    Ok(())
+}
+
+fn set_certificate_from_files(
+    context_builder: &mut SslContextBuilder,
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<()> {
+    let cert_pem = std::fs::read(cert_path)
+        .with_context(|| format!("Failed to read certificate {}", cert_path.display()))?;
+    let key_pem = std::fs::read(key_path)
+        .with_context(|| format!("Failed to read private key {}", key_path.display()))?;
+
+    let mut chain = X509::stack_from_pem(&cert_pem)
+        .with_context(|| format!("Invalid PEM certificate in {}", cert_path.display()))?
+        .into_iter();
+    let leaf = chain
+        .next()
+        .with_context(|| format!("No certificate found in {}", cert_path.display()))?;
+    let private_key = PKey::private_key_from_pem(&key_pem)
+        .with_context(|| format!("Invalid PEM private key in {}", key_path.display()))?;
+
+    context_builder.set_certificate(&leaf)?;
+    for intermediate in chain {
+        context_builder.add_extra_chain_cert(intermediate)?;
+    }
+    context_builder
+        .set_private_key(&private_key)
+        .with_context(|| {
+            format!(
+                "Private key {} does not match certificate {}",
+                key_path.display(),
+                cert_path.display()
+            )
+        })?;
+
+    Ok(())
+}
+
+/// Certificates generated on demand for the server names clients ask for.
+struct DynamicCerts {
+    opt: Opt,
+    ca: Option<Arc<Certificate>>,
+    contexts: Mutex<(HashMap<String, SslContext>, VecDeque<String>)>,
+}
+
+impl DynamicCerts {
+    fn new(opt: &Opt, ca: Option<Arc<Certificate>>) -> Self {
+        Self {
+            opt: opt.clone(),
+            ca,
+            contexts: Mutex::default(),
+        }
+    }
+
+    fn context_for(&self, server_name: &str) -> Result<SslContext> {
+        let mut guard = self.contexts.lock().unwrap();
+        let (contexts, order) = &mut *guard;
+        if let Some(context) = contexts.get(server_name) {
+            return Ok(context.clone());
+        }
+
+        let (private_key, certificate) = generate_certificate(
+            certificate_params(server_name, &[server_name]),
+            self.ca.as_deref(),
+        )?;
+        let mut acceptor_builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        configure_acceptor(&self.opt, &mut acceptor_builder)?;
+        acceptor_builder.set_private_key(&private_key)?;
+        acceptor_builder.set_certificate(&certificate)?;
+        let context = acceptor_builder.build().into_context();
+        info!(parent: None, "Generated certificate for {server_name}");
+
+        if contexts.len() >= self.opt.dynamic_cert_cache_size {
+            if let Some(oldest) = order.pop_front() {
+                contexts.remove(&oldest);
+            }
+        }
+        contexts.insert(server_name.to_string(), context.clone());
+        order.push_back(server_name.to_string());
+
+        Ok(context)
+    }
+}
+
+fn set_dynamic_certificates(
+    opt: &Opt,
+    ca: Option<Arc<Certificate>>,
+    acceptor_builder: &mut SslAcceptorBuilder,
+) {
+    let dynamic_certs = DynamicCerts::new(opt, ca);
+    acceptor_builder.set_servername_callback(move |ssl, _alert| {
+        let Some(server_name) = ssl.servername(NameType::HOST_NAME).map(str::to_string) else {
+            return Ok(());
+        };
+        match dynamic_certs.context_for(&server_name) {
+            Ok(context) => {
+                if let Err(e) = ssl.set_ssl_context(&context) {
+                    error!("Failed to use certificate for {server_name}: {e}");
+                }
+            }
+            Err(e) => error!("Failed to generate certificate for {server_name}: {e:#}"),
+        }
+        Ok(())
+    });
+}
+
+/// Settings of the acceptor that the context of each --dynamic-certs certificate
+/// needs too, as it replaces the acceptor's for the rest of the handshake.
+fn configure_acceptor(opt: &Opt, acceptor_builder: &mut SslAcceptorBuilder) -> Result<()> {
+    configure_context(opt, acceptor_builder)?;
+    if !opt.alpn.is_empty() {
+        let protocols = alpn_wire_format(&opt.alpn);
+        acceptor_builder.set_alpn_select_callback(move |_, client_protocols| {
+            select_next_proto(&protocols, client_protocols).ok_or(AlpnError::NOACK)
+        });
+    }
+
+    if opt.request_client_cert || opt.require_client_cert {
+        let mut mode = SslVerifyMode::PEER;
+        if opt.require_client_cert {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        match &opt.client_ca {
+            Some(ca_file) => {
+                acceptor_builder
+                    .set_ca_file(ca_file)
+                    .with_context(|| format!("Failed to load client CA {}", ca_file.display()))?;
+                acceptor_builder.set_client_ca_list(X509Name::load_client_ca_file(ca_file)?);
+                acceptor_builder.set_verify(mode);
+            }
+            // Accept whatever the client presents; it is only logged.
+            None => acceptor_builder.set_verify_callback(mode, |_, _| true),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn generate_acceptor(opt: &Opt) -> Result<SslAcceptor> {
+    let ca = match (&opt.ca_cert, &opt.ca_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(load_certificate_authority(cert, key)?)),
+        _ => None,
+    };
+
+    let mut acceptor_builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .context("Failed to create TLS acceptor")?;
+    configure_acceptor(opt, &mut acceptor_builder)?;
+    match (&opt.cert, &opt.key) {
+        (Some(cert), Some(key)) => set_certificate_from_files(&mut acceptor_builder, cert, key)?,
+        _ => set_self_signed_certificate(opt, ca.as_deref(), &mut acceptor_builder)?,
+    }
+    if opt.dynamic_certs {
+        set_dynamic_certificates(opt, ca, &mut acceptor_builder);
+    }
+
+    acceptor_builder
+        .check_private_key()
+        .context("Private key does not match the certificate")?;
+
+    Ok(acceptor_builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ssl::SslConnectorBuilder;
+    use structopt::StructOpt;
+    use tokio::io::DuplexStream;
+
+    /// Completes a handshake with the acceptor for `opt` as a client sending
+    /// `server_name` and set up by `configure`.
+    async fn handshake(
+        opt: &Opt,
+        server_name: &str,
+        configure: impl FnOnce(&mut SslConnectorBuilder),
+    ) -> Result<SslStream<DuplexStream>> {
+        let acceptor = generate_acceptor(opt)?;
+        let mut connector_builder = SslConnector::builder(SslMethod::tls())?;
+        connector_builder.set_verify(SslVerifyMode::NONE);
+        configure(&mut connector_builder);
+        let ssl = connector_builder
+            .build()
+            .configure()?
+            .into_ssl(server_name)?;
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut client = SslStream::new(ssl, client)?;
+        let (connected, accepted) = tokio::join!(
+            Pin::new(&mut client).connect(),
+            wrap_ssl_server(opt, Box::pin(server), &acceptor)
+        );
+        connected?;
+        accepted?;
+        Ok(client)
+    }
+
+    fn peer_names(client: &SslStream<DuplexStream>) -> Vec<String> {
+        subject_alt_names(&client.ssl().peer_certificate().unwrap())
+    }
+
+    #[test]
+    fn self_signed_certificate_has_sans() {
+        let opt = Opt::from_iter(["tcp-proxy", "example.com", "--san", "extra.test"]);
+        let (_, certificate) = generate_certificate(self_signed_params(&opt), None).unwrap();
+
+        let sans = certificate.subject_alt_names().unwrap();
+        let dns_names: Vec<_> = sans.iter().filter_map(|san| san.dnsname()).collect();
+        let ip_addresses: Vec<_> = sans.iter().filter_map(|san| san.ipaddress()).collect();
+        assert_eq!(dns_names, ["example.com", "localhost", "extra.test"]);
+        assert_eq!(ip_addresses, [&[127, 0, 0, 1][..]]);
+
+        let common_name = certificate
+            .subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .next()
+            .unwrap();
+        assert_eq!(
+            common_name.data().as_utf8().unwrap().to_string(),
+            "example.com"
+        );
+    }
+
+    #[test]
+    fn cached_certificate_is_regenerated_for_other_names() {
+        let dir = std::env::temp_dir().join(format!("tcp-proxy-certs-{}", std::process::id()));
+        let cached = |args: &[&str]| {
+            let opt = Opt::from_iter(["tcp-proxy"].iter().chain(args));
+            let (_, certificate) = cached_self_signed_certificate(&opt, None, &dir).unwrap();
+            certificate.to_der().unwrap()
+        };
+
+        let first = cached(&["example.com"]);
+        assert_eq!(cached(&["example.com"]), first);
+        let renamed = cached(&["other.test"]);
+        assert_ne!(renamed, first);
+        assert_ne!(cached(&["other.test", "--san", "extra.test"]), renamed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dynamic_certificates_check_client_certificates() {
+        let dir = std::env::temp_dir().join(format!("tcp-proxy-client-ca-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_path = dir.join("ca.pem");
+        let mut ca_params = certificate_params("Client CA", &[]);
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        std::fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+        let (client_key, client_certificate) =
+            generate_certificate(certificate_params("client", &["client"]), Some(&ca)).unwrap();
+
+        let opt = Opt::from_iter([
+            "tcp-proxy",
+            "localhost",
+            "--ssl-server",
+            "--dynamic-certs",
+            "--require-client-cert",
+            "--client-ca",
+            ca_path.to_str().unwrap(),
+        ]);
+        let with_certificate = |connector_builder: &mut SslConnectorBuilder| {
+            connector_builder
+                .set_certificate(&client_certificate)
+                .unwrap();
+            connector_builder.set_private_key(&client_key).unwrap();
+        };
+        let client = handshake(&opt, "foo.example", with_certificate)
+            .await
+            .unwrap();
+        assert_eq!(peer_names(&client), ["foo.example"]);
+        assert!(handshake(&opt, "foo.example", |_| {}).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dynamic_certificates_negotiate_alpn() {
+        let opt = Opt::from_iter([
+            "tcp-proxy",
+            "localhost",
+            "--ssl-server",
+            "--dynamic-certs",
+            "--alpn",
+            "h2",
+        ]);
+        let offer_h2 = |connector_builder: &mut SslConnectorBuilder| {
+            connector_builder.set_alpn_protos(b"\x02h2").unwrap();
+        };
+        let client = handshake(&opt, "foo.example", offer_h2).await.unwrap();
+        assert_eq!(peer_names(&client), ["foo.example"]);
+        assert_eq!(client.ssl().selected_alpn_protocol(), Some(&b"h2"[..]));
+    }
+
+    #[tokio::test]
+    async fn dynamic_certificates_log_keys() {
+        let path = std::env::temp_dir().join(format!("tcp-proxy-keylog-{}", std::process::id()));
+        let opt = Opt::from_iter([
+            "tcp-proxy",
+            "localhost",
+            "--ssl-server",
+            "--dynamic-certs",
+            "--keylog",
+            path.to_str().unwrap(),
+        ]);
+        let client = handshake(&opt, "foo.example", |_| {}).await.unwrap();
+        assert_eq!(peer_names(&client), ["foo.example"]);
+        let keylog = std::fs::read_to_string(&path).unwrap();
+        assert!(keylog.lines().count() > 0, "no keys logged");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
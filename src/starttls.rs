@@ -0,0 +1,470 @@
+use crate::data_log::DataLog;
+use crate::{AsyncStream, Opt, Prepend};
+use anyhow::Result;
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::info;
+
+#[derive(Clone, Copy)]
+pub enum StartTls {
+    Smtp,
+    Imap,
+    /// Answer the SSLRequest that precedes the handshake.
+    Postgres,
+    /// Upgrade as soon as the client starts a TLS handshake.
+    Manual,
+}
+
+impl StartTls {
+    pub fn default_port(self) -> Option<u16> {
+        match self {
+            StartTls::Smtp => Some(25),
+            StartTls::Imap => Some(143),
+            StartTls::Postgres => Some(5432),
+            StartTls::Manual => None,
+        }
+    }
+}
+
+impl FromStr for StartTls {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smtp" => Ok(StartTls::Smtp),
+            "imap" => Ok(StartTls::Imap),
+            "postgres" => Ok(StartTls::Postgres),
+            "manual" => Ok(StartTls::Manual),
+            _ => Err("expected one of smtp, imap, postgres or manual"),
+        }
+    }
+}
+
+/// Lines longer than this are only matched on their start.
+const MAX_LINE: usize = 1024;
+
+/// Splits one direction of the plaintext conversation into lines.
+#[derive(Default)]
+struct Lines {
+    line: Vec<u8>,
+}
+
+impl Lines {
+    /// Feeds `data` and returns the offset just past the first line `f` accepts.
+    fn feed(&mut self, data: &[u8], mut f: impl FnMut(&[u8]) -> bool) -> Option<usize> {
+        for (offset, &byte) in data.iter().enumerate() {
+            if self.line.len() < MAX_LINE {
+                self.line.push(byte);
+            }
+            if byte == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                if f(trim_line(&line)) {
+                    return Some(offset + 1);
+                }
+            }
+        }
+        None
+    }
+}
+
+fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Watches both directions for the point where they switch to TLS.
+struct Detector {
+    protocol: StartTls,
+    client_lines: Lines,
+    server_lines: Lines,
+    /// The SMTP STARTTLS command, or the tag of the IMAP one, awaiting its response.
+    pending: Option<Vec<u8>>,
+}
+
+impl Detector {
+    fn new(protocol: StartTls) -> Self {
+        Detector {
+            protocol,
+            client_lines: Lines::default(),
+            server_lines: Lines::default(),
+            pending: None,
+        }
+    }
+
+    /// Returns the offset in `data` where the client's TLS handshake starts.
+    fn client_data(&mut self, data: &[u8]) -> Option<usize> {
+        match self.protocol {
+            StartTls::Manual => {
+                (self.client_lines.line.is_empty() && data.starts_with(&[22, 3])).then_some(0)
+            }
+            StartTls::Postgres => None,
+            StartTls::Smtp => {
+                let pending = &mut self.pending;
+                self.client_lines.feed(data, |line| {
+                    if line.eq_ignore_ascii_case(b"STARTTLS") {
+                        *pending = Some(vec![]);
+                    }
+                    false
+                });
+                None
+            }
+            StartTls::Imap => {
+                let pending = &mut self.pending;
+                self.client_lines.feed(data, |line| {
+                    let mut words = line.split(|&b| b == b' ');
+                    if let (Some(tag), Some(command), None) =
+                        (words.next(), words.next(), words.next())
+                    {
+                        if command.eq_ignore_ascii_case(b"STARTTLS") {
+                            *pending = Some(tag.to_vec());
+                        }
+                    }
+                    false
+                });
+                None
+            }
+        }
+    }
+
+    /// Returns the offset in `data` just past the server's go-ahead for TLS.
+    fn server_data(&mut self, data: &[u8]) -> Option<usize> {
+        let protocol = self.protocol;
+        let pending = &mut self.pending;
+        self.server_lines.feed(data, |line| {
+            let Some(tag) = pending.as_deref() else {
+                return false;
+            };
+            let upgrade = match protocol {
+                StartTls::Smtp => {
+                    // "220-" continues a multiline reply, only the last line counts.
+                    if line.get(3) == Some(&b'-') {
+                        return false;
+                    }
+                    line.starts_with(b"220")
+                }
+                StartTls::Imap => {
+                    // Untagged responses like "* OK" may come before the tagged one.
+                    let Some(status) = line
+                        .strip_prefix(tag)
+                        .and_then(|rest| rest.strip_prefix(b" "))
+                    else {
+                        return false;
+                    };
+                    status.len() >= 2 && status[..2].eq_ignore_ascii_case(b"OK")
+                }
+                StartTls::Postgres | StartTls::Manual => false,
+            };
+            if !upgrade {
+                // The server refused, keep going in plaintext.
+                *pending = None;
+            }
+            upgrade
+        })
+    }
+}
+
+/// Request codes of the 8 byte packets a Postgres client may open with.
+const POSTGRES_SSL_REQUEST: u32 = 80877103;
+const POSTGRES_GSSENC_REQUEST: u32 = 80877104;
+
+/// Bytes read past the upgrade point, to be fed to the TLS handshakes.
+#[derive(Default)]
+pub struct Upgrade {
+    client_rest: Vec<u8>,
+    server_rest: Vec<u8>,
+}
+
+impl Upgrade {
+    pub fn client(&mut self, stream: AsyncStream) -> AsyncStream {
+        Prepend::wrap(std::mem::take(&mut self.client_rest), stream)
+    }
+
+    pub fn server(&mut self, stream: AsyncStream) -> AsyncStream {
+        Prepend::wrap(std::mem::take(&mut self.server_rest), stream)
+    }
+}
+
+/// Forwards the plaintext start of the connection until both sides are about to
+/// switch to TLS. Returns `None` if the connection stays plaintext, e.g. because
+/// either side closed first.
+pub async fn negotiate(
+    opt: &Opt,
+    protocol: StartTls,
+    data_log: &mut DataLog,
+    incoming_stream: &mut AsyncStream,
+    outgoing_stream: &mut AsyncStream,
+) -> Result<Option<Upgrade>> {
+    if let StartTls::Postgres = protocol {
+        return negotiate_postgres(opt, data_log, incoming_stream, outgoing_stream).await;
+    }
+
+    let mut detector = Detector::new(protocol);
+    let mut incoming_buf = vec![0; 1 << 16];
+    let mut outgoing_buf = vec![0; 1 << 16];
+    loop {
+        tokio::select! {
+            n = incoming_stream.read(&mut incoming_buf) => {
+                let n = n?;
+                let data = &incoming_buf[..n];
+                let handshake_start = detector.client_data(data);
+                let plaintext = &data[..handshake_start.unwrap_or(n)];
+                data_log.incoming(opt, plaintext);
+                outgoing_stream.write_all(plaintext).await?;
+                if let Some(start) = handshake_start {
+                    return Ok(Some(Upgrade {
+                        client_rest: data[start..].to_vec(),
+                        server_rest: vec![],
+                    }));
+                }
+                if n == 0 {
+                    return Ok(None);
+                }
+            }
+            n = outgoing_stream.read(&mut outgoing_buf) => {
+                let n = n?;
+                let data = &outgoing_buf[..n];
+                let go_ahead_end = detector.server_data(data);
+                let plaintext = &data[..go_ahead_end.unwrap_or(n)];
+                data_log.outgoing(opt, plaintext);
+                incoming_stream.write_all(plaintext).await?;
+                if let Some(end) = go_ahead_end {
+                    return Ok(Some(Upgrade {
+                        client_rest: vec![],
+                        server_rest: data[end..].to_vec(),
+                    }));
+                }
+                if n == 0 {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// Handles the SSLRequest a Postgres client sends before its TLS handshake. The
+/// proxy sends its own SSLRequest upstream and relays the one byte answer.
+async fn negotiate_postgres(
+    opt: &Opt,
+    data_log: &mut DataLog,
+    incoming_stream: &mut AsyncStream,
+    outgoing_stream: &mut AsyncStream,
+) -> Result<Option<Upgrade>> {
+    loop {
+        let mut packet = [0; 8];
+        incoming_stream.read_exact(&mut packet).await?;
+        data_log.incoming(opt, &packet);
+        let len = u32::from_be_bytes(packet[..4].try_into().unwrap());
+        let code = u32::from_be_bytes(packet[4..].try_into().unwrap());
+        if len == 8 && code == POSTGRES_GSSENC_REQUEST {
+            // GSSAPI encryption can't be proxied, so refuse it and let the client
+            // fall back to an SSLRequest.
+            info!("Refusing Postgres GSSENCRequest");
+            incoming_stream.write_all(b"N").await?;
+            continue;
+        }
+        if len != 8 || code != POSTGRES_SSL_REQUEST {
+            info!("Postgres client didn't request SSL, continuing in plaintext");
+            outgoing_stream.write_all(&packet).await?;
+            return Ok(None);
+        }
+
+        outgoing_stream.write_all(&packet).await?;
+        let mut answer = [0; 1];
+        outgoing_stream.read_exact(&mut answer).await?;
+        data_log.outgoing(opt, &answer);
+        incoming_stream.write_all(&answer).await?;
+        if answer == *b"S" {
+            return Ok(Some(Upgrade::default()));
+        }
+        info!("Postgres server refused SSL, continuing in plaintext");
+        return Ok(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+    use tokio::io::DuplexStream;
+
+    const SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+    const GSSENC_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x30];
+
+    async fn negotiate_postgres_with(
+        client: impl FnOnce(DuplexStream) -> tokio::task::JoinHandle<Vec<u8>>,
+        server: impl FnOnce(DuplexStream) -> tokio::task::JoinHandle<Vec<u8>>,
+    ) -> (bool, Vec<u8>, Vec<u8>) {
+        let opt = Opt::from_iter(["tcp-proxy", "localhost"]);
+        let (client_end, proxy_incoming) = tokio::io::duplex(64);
+        let (proxy_outgoing, server_end) = tokio::io::duplex(64);
+        let client = client(client_end);
+        let server = server(server_end);
+        let mut incoming: AsyncStream = Box::pin(proxy_incoming);
+        let mut outgoing: AsyncStream = Box::pin(proxy_outgoing);
+        let upgrade = negotiate(
+            &opt,
+            StartTls::Postgres,
+            &mut DataLog::new(0),
+            &mut incoming,
+            &mut outgoing,
+        )
+        .await
+        .unwrap();
+        drop((incoming, outgoing));
+        (
+            upgrade.is_some(),
+            client.await.unwrap(),
+            server.await.unwrap(),
+        )
+    }
+
+    /// Writes `packets` and then collects everything read until EOF.
+    fn script(
+        mut stream: DuplexStream,
+        packets: &'static [&'static [u8]],
+    ) -> tokio::task::JoinHandle<Vec<u8>> {
+        tokio::spawn(async move {
+            let mut read = vec![];
+            for packet in packets {
+                stream.write_all(packet).await.unwrap();
+            }
+            stream.read_to_end(&mut read).await.unwrap();
+            read
+        })
+    }
+
+    #[tokio::test]
+    async fn postgres_upgrades_on_s() {
+        let (upgraded, client_read, server_read) = negotiate_postgres_with(
+            |stream| script(stream, &[&SSL_REQUEST]),
+            |stream| script(stream, &[b"S"]),
+        )
+        .await;
+        assert!(upgraded);
+        assert_eq!(client_read, b"S");
+        assert_eq!(server_read, SSL_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn postgres_stays_plaintext_on_n() {
+        let (upgraded, client_read, server_read) = negotiate_postgres_with(
+            |stream| script(stream, &[&SSL_REQUEST]),
+            |stream| script(stream, &[b"N"]),
+        )
+        .await;
+        assert!(!upgraded);
+        assert_eq!(client_read, b"N");
+        assert_eq!(server_read, SSL_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn postgres_refuses_gssenc_without_asking_upstream() {
+        let (upgraded, client_read, server_read) = negotiate_postgres_with(
+            |stream| script(stream, &[&GSSENC_REQUEST, &SSL_REQUEST]),
+            |stream| script(stream, &[b"S"]),
+        )
+        .await;
+        assert!(upgraded);
+        assert_eq!(client_read, b"NS");
+        assert_eq!(server_read, SSL_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn postgres_forwards_startup_message_unchanged() {
+        const STARTUP: [u8; 8] = [0, 0, 0, 8, 0, 3, 0, 0];
+        let (upgraded, client_read, server_read) = negotiate_postgres_with(
+            |stream| script(stream, &[&STARTUP]),
+            |stream| script(stream, &[]),
+        )
+        .await;
+        assert!(!upgraded);
+        assert_eq!(client_read, b"");
+        assert_eq!(server_read, STARTUP);
+    }
+
+    const CLIENT_HELLO_START: &[u8] = &[0x16, 0x03, 0x01];
+
+    #[test]
+    fn smtp_upgrades_after_the_last_line_of_a_multiline_go_ahead() {
+        let mut detector = Detector::new(StartTls::Smtp);
+        assert_eq!(detector.server_data(b"220 mx ESMTP\r\n"), None);
+        assert_eq!(detector.client_data(b"EHLO client\r\nSTARTTLS\r\n"), None);
+        let reply = b"220-Go ahead\r\n220 Ready to start TLS\r\n";
+        let data = [&reply[..], CLIENT_HELLO_START].concat();
+        assert_eq!(detector.server_data(&data), Some(reply.len()));
+    }
+
+    #[test]
+    fn smtp_go_ahead_can_be_split_across_reads() {
+        let mut detector = Detector::new(StartTls::Smtp);
+        detector.client_data(b"STARTTLS\r");
+        detector.client_data(b"\n");
+        assert_eq!(detector.server_data(b"220 Ready to st"), None);
+        let data = [&b"art TLS\r\n"[..], CLIENT_HELLO_START].concat();
+        assert_eq!(detector.server_data(&data), Some(9));
+    }
+
+    #[test]
+    fn smtp_refused_starttls_stays_plaintext() {
+        let mut detector = Detector::new(StartTls::Smtp);
+        detector.client_data(b"STARTTLS\r\n");
+        assert_eq!(detector.server_data(b"454 TLS not available\r\n"), None);
+        assert_eq!(detector.server_data(b"220 Later reply\r\n"), None);
+    }
+
+    #[test]
+    fn imap_upgrades_on_the_tagged_ok_only() {
+        let mut detector = Detector::new(StartTls::Imap);
+        assert_eq!(detector.server_data(b"* OK IMAP4rev1 ready\r\n"), None);
+        detector.client_data(b"a1 STARTTLS\r\n");
+        let data = b"* OK Still here\r\na1 OK Begin TLS\r\n";
+        assert_eq!(detector.server_data(data), Some(data.len()));
+
+        let mut detector = Detector::new(StartTls::Imap);
+        detector.client_data(b"a1 STARTTLS\r\n");
+        assert_eq!(detector.server_data(b"a1 NO Not now\r\n"), None);
+        assert_eq!(detector.server_data(b"a1 OK Begin TLS\r\n"), None);
+    }
+
+    #[test]
+    fn manual_upgrades_when_the_client_starts_a_handshake() {
+        let mut detector = Detector::new(StartTls::Manual);
+        assert_eq!(detector.client_data(CLIENT_HELLO_START), Some(0));
+        let mut detector = Detector::new(StartTls::Manual);
+        assert_eq!(detector.client_data(b"PING\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn bytes_past_the_go_ahead_are_kept_for_the_handshake() {
+        let opt = Opt::from_iter(["tcp-proxy", "localhost"]);
+        let (client_end, proxy_incoming) = tokio::io::duplex(64);
+        let (proxy_outgoing, server_end) = tokio::io::duplex(64);
+        let client = script(client_end, &[b"STARTTLS\r\n"]);
+        let server = tokio::spawn(async move {
+            let mut server_end = server_end;
+            let mut command = [0; 10];
+            server_end.read_exact(&mut command).await.unwrap();
+            server_end
+                .write_all(b"220 Ready to start TLS\r\n\x16\x03\x01")
+                .await
+                .unwrap();
+            server_end
+        });
+        let mut incoming: AsyncStream = Box::pin(proxy_incoming);
+        let mut outgoing: AsyncStream = Box::pin(proxy_outgoing);
+        let upgrade = negotiate(
+            &opt,
+            StartTls::Smtp,
+            &mut DataLog::new(0),
+            &mut incoming,
+            &mut outgoing,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(upgrade.server_rest, CLIENT_HELLO_START);
+        assert!(upgrade.client_rest.is_empty());
+        drop((incoming, outgoing, server.await.unwrap()));
+        assert_eq!(client.await.unwrap(), b"220 Ready to start TLS\r\n");
+    }
+}
@@ -0,0 +1,259 @@
+use crate::listener::Peer;
+use crate::logging::Direction;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{info, Span};
+
+/// Counters over all connections, reported when the proxy stops, and the
+/// connections currently being handled, listed on SIGUSR2.
+#[derive(Default)]
+pub struct Stats {
+    connections: AtomicU64,
+    open: AtomicU64,
+    errors: AtomicU64,
+    /// Connections closed right away for --max-connections.
+    rejected: AtomicU64,
+    incoming_bytes: AtomicU64,
+    outgoing_bytes: AtomicU64,
+    http_rewrites: AtomicU64,
+    active: Mutex<HashMap<usize, Arc<ConnInfo>>>,
+}
+
+/// A connection being handled. Its counters are atomics so the copy loop doesn't
+/// take the lock of `Stats::active`.
+struct ConnInfo {
+    /// The connection's span, so its row in the table is tagged with its id.
+    span: Span,
+    peer: String,
+    client_addr: Option<SocketAddr>,
+    /// The client named by an --accept-proxy header, instead of `client_addr`.
+    proxied: OnceLock<SocketAddr>,
+    upstream: OnceLock<String>,
+    started: Instant,
+    incoming_bytes: AtomicU64,
+    outgoing_bytes: AtomicU64,
+    /// When data was last forwarded either way, in milliseconds after `started`.
+    last_active: AtomicU64,
+    /// How many bytes [`Connection::forwarded_total`] waits for, or 0.
+    awaited_bytes: AtomicU64,
+    forwarded_enough: Notify,
+}
+
+/// Counts the traffic of one connection, which is listed until this is dropped.
+pub struct Connection {
+    stats: Arc<Stats>,
+    i: usize,
+    info: Arc<ConnInfo>,
+}
+
+impl Stats {
+    /// Registers connection `i`, tagging its row with the current span.
+    pub fn open(self: &Arc<Self>, i: usize, peer: &Peer) -> Connection {
+        self.connections.fetch_add(1, Relaxed);
+        self.open.fetch_add(1, Relaxed);
+        let info = Arc::new(ConnInfo {
+            span: Span::current(),
+            peer: peer.to_string(),
+            client_addr: match peer {
+                Peer::Tcp(addr) => Some(*addr),
+                Peer::Unix(_) => None,
+            },
+            proxied: OnceLock::new(),
+            upstream: OnceLock::new(),
+            started: Instant::now(),
+            incoming_bytes: AtomicU64::new(0),
+            outgoing_bytes: AtomicU64::new(0),
+            last_active: AtomicU64::new(0),
+            awaited_bytes: AtomicU64::new(0),
+            forwarded_enough: Notify::new(),
+        });
+        self.active.lock().unwrap().insert(i, info.clone());
+        Connection {
+            stats: self.clone(),
+            i,
+            info,
+        }
+    }
+
+    pub fn report(&self) {
+        info!(
+            parent: None,
+            event = "stats",
+            connections = self.connections.load(Relaxed),
+            open = self.open.load(Relaxed),
+            errors = self.errors.load(Relaxed),
+            rejected = self.rejected.load(Relaxed),
+            incoming_bytes = self.incoming_bytes.load(Relaxed),
+            outgoing_bytes = self.outgoing_bytes.load(Relaxed),
+            http_rewrites = self.http_rewrites.load(Relaxed)
+        );
+    }
+
+    pub fn reject(&self) {
+        self.rejected.fetch_add(1, Relaxed);
+    }
+
+    /// Logs a line for each connection being handled, oldest first.
+    pub fn list_connections(&self) {
+        let mut active: Vec<_> = self.active.lock().unwrap().values().cloned().collect();
+        active.sort_by_key(|info| info.started);
+        match self.rejected.load(Relaxed) {
+            0 => info!(parent: None, "=== {} open connections ===", active.len()),
+            rejected => info!(
+                parent: None,
+                "=== {} open connections, {rejected} rejected ===",
+                active.len()
+            ),
+        }
+        for info in active {
+            info!(
+                parent: &info.span,
+                event = "status",
+                peer = %info.peer(),
+                upstream = info.upstream.get().map(String::as_str),
+                age = info.started.elapsed().as_secs_f64(),
+                incoming_bytes = info.incoming_bytes.load(Relaxed),
+                outgoing_bytes = info.outgoing_bytes.load(Relaxed)
+            );
+        }
+    }
+}
+
+impl ConnInfo {
+    fn peer(&self) -> String {
+        match self.proxied.get() {
+            Some(client) => format!("{client} via {}", self.peer),
+            None => self.peer.clone(),
+        }
+    }
+}
+
+impl Connection {
+    /// The number of the connection, counting from 0 in the order they came.
+    pub fn index(&self) -> usize {
+        self.i
+    }
+
+    /// The client's address, unless it connected over a Unix domain socket.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_addr().map(|addr| addr.ip())
+    }
+
+    /// The client's address and port, unless it connected over a Unix domain socket.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.info.proxied.get().copied().or(self.info.client_addr)
+    }
+
+    /// Records the client named by the PROXY protocol header the peer sent.
+    pub fn proxied(&self, client: SocketAddr) {
+        let _ = self.info.proxied.set(client);
+    }
+
+    /// Who connected, including the client the peer proxied for.
+    pub fn peer(&self) -> String {
+        self.info.peer()
+    }
+
+    /// Records the upstream once it was chosen.
+    pub fn connected(&self, upstream: String) {
+        let _ = self.info.upstream.set(upstream);
+    }
+
+    /// The upstream, once it was chosen.
+    pub fn upstream(&self) -> Option<&str> {
+        self.info.upstream.get().map(String::as_str)
+    }
+
+    pub fn forwarded(&self, direction: Direction, bytes: usize) {
+        let (total, own) = match direction {
+            Direction::Incoming => (&self.stats.incoming_bytes, &self.info.incoming_bytes),
+            Direction::Outgoing => (&self.stats.outgoing_bytes, &self.info.outgoing_bytes),
+        };
+        total.fetch_add(bytes as u64, Relaxed);
+        own.fetch_add(bytes as u64, Relaxed);
+        if bytes > 0 {
+            let now = self.info.started.elapsed().as_millis() as u64;
+            self.info.last_active.store(now, Relaxed);
+        }
+        let awaited = self.info.awaited_bytes.load(Relaxed);
+        if awaited > 0 && self.total() >= awaited {
+            self.info.forwarded_enough.notify_one();
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.info.incoming_bytes.load(Relaxed) + self.info.outgoing_bytes.load(Relaxed)
+    }
+
+    /// Resolves once at least `bytes` were forwarded, both ways together.
+    pub async fn forwarded_total(&self, bytes: u64) {
+        self.info.awaited_bytes.store(bytes.max(1), Relaxed);
+        while self.total() < bytes {
+            self.info.forwarded_enough.notified().await;
+        }
+    }
+
+    /// Sleeps for `duration`, which counts as activity for [`Connection::idle`].
+    pub async fn busy_for(&self, duration: Duration) {
+        let until = (self.info.started.elapsed() + duration).as_millis() as u64;
+        self.info.last_active.fetch_max(until, Relaxed);
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Resolves once nothing was forwarded either way for `timeout`.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let last_active = Duration::from_millis(self.info.last_active.load(Relaxed));
+            let idle = self.info.started.elapsed().saturating_sub(last_active);
+            if idle >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - idle).await;
+        }
+    }
+
+    pub fn http_rewrite(&self) {
+        self.stats.http_rewrites.fetch_add(1, Relaxed);
+    }
+
+    pub fn close(self, failed: bool) {
+        if failed {
+            self.stats.errors.fetch_add(1, Relaxed);
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.stats.open.fetch_sub(1, Relaxed);
+        self.stats.active.lock().unwrap().remove(&self.i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_connections_leave_the_table_but_keep_their_bytes() {
+        let stats = Arc::new(Stats::default());
+        let first = stats.open(0, &Peer::Unix(None));
+        let second = stats.open(1, &Peer::Unix(None));
+        first.forwarded(Direction::Incoming, 3);
+        second.forwarded(Direction::Incoming, 4);
+        second.forwarded(Direction::Outgoing, 5);
+        first.close(true);
+
+        let active = stats.active.lock().unwrap();
+        assert_eq!(active.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(active[&1].incoming_bytes.load(Relaxed), 4);
+        assert_eq!(stats.open.load(Relaxed), 1);
+        assert_eq!(stats.errors.load(Relaxed), 1);
+        assert_eq!(stats.incoming_bytes.load(Relaxed), 7);
+        assert_eq!(stats.outgoing_bytes.load(Relaxed), 5);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::logging::Direction;
+use crate::{AsyncStream, Opt};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Any more time's worth of bytes than this would be sent in a burst.
+const BURST: Duration = Duration::from_millis(50);
+
+/// A stream that what is written to is limited to a number of bytes per second by
+/// a token bucket, so writes wait for the bucket to fill rather than sending in
+/// bursts. What is read from it is unchanged.
+pub struct Throttled {
+    inner: AsyncStream,
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    filled: Instant,
+    /// Until the bucket has enough for the next write, once it ran dry.
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Throttled {
+    /// `inner` with writes of data going in `direction` limited by --rate-limit,
+    /// --rate-limit-up or --rate-limit-down if any was given.
+    pub fn wrap(opt: &Opt, direction: Direction, inner: AsyncStream) -> AsyncStream {
+        let rate = match direction {
+            Direction::Incoming => opt.rate_limit_up,
+            Direction::Outgoing => opt.rate_limit_down,
+        };
+        match rate.or(opt.rate_limit) {
+            Some(rate) => Box::pin(Throttled::new(rate, inner)),
+            None => inner,
+        }
+    }
+
+    fn new(rate: u64, inner: AsyncStream) -> Self {
+        let rate = rate as f64;
+        let capacity = (rate * BURST.as_secs_f64()).max(1.0);
+        let now = Instant::now();
+        Throttled {
+            inner,
+            rate,
+            capacity,
+            tokens: capacity,
+            filled: now,
+            sleep: Box::pin(tokio::time::sleep_until(now)),
+        }
+    }
+
+    fn fill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.filled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.filled = now;
+    }
+}
+
+impl AsyncRead for Throttled {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Throttled {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return self.inner.as_mut().poll_write(cx, buf);
+        }
+        loop {
+            let now = Instant::now();
+            self.fill(now);
+            if self.tokens >= 1.0 {
+                break;
+            }
+            // Wait until the bucket holds as much of `buf` as it can.
+            let wanted = (buf.len() as f64).min(self.capacity) - self.tokens;
+            let deadline = now + Duration::from_secs_f64(wanted / self.rate);
+            self.sleep.as_mut().reset(deadline);
+            if self.sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        let len = buf.len().min(self.tokens as usize);
+        let poll = self.inner.as_mut().poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.tokens -= written as f64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_are_limited_to_the_rate() {
+        let (client, mut server) = tokio::io::duplex(1 << 16);
+        let mut throttled = Throttled::new(100_000, Box::pin(client));
+        let started = Instant::now();
+        let writing = async {
+            throttled.write_all(&vec![0; 1_000_000]).await.unwrap();
+            throttled.shutdown().await.unwrap();
+        };
+        let reading = async {
+            let mut data = vec![];
+            server.read_to_end(&mut data).await.unwrap();
+            data.len()
+        };
+        let ((), received) = tokio::join!(writing, reading);
+        assert_eq!(received, 1_000_000);
+        let elapsed = started.elapsed().as_secs_f64();
+        assert!((9.8..10.2).contains(&elapsed), "took {elapsed}s");
+    }
+}
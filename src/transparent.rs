@@ -0,0 +1,99 @@
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::fd::AsRawFd;
+use tokio::net::TcpStream;
+
+/// The address a connection redirected to the proxy with iptables REDIRECT was
+/// originally for, as `SO_ORIGINAL_DST` tells, for --transparent.
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    let ipv6 = match local {
+        SocketAddr::V4(_) => false,
+        SocketAddr::V6(addr) => addr.ip().to_ipv4_mapped().is_none(),
+    };
+    let original = if ipv6 {
+        // SAFETY: sockaddr_in6 is plain integers, for which all zeroes are valid.
+        let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        // SAFETY: The kernel writes a sockaddr_in6 for IP6T_SO_ORIGINAL_DST.
+        unsafe {
+            getsockopt(
+                stream,
+                libc::SOL_IPV6,
+                libc::IP6T_SO_ORIGINAL_DST,
+                &mut addr,
+            )?
+        };
+        let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+        let port = u16::from_be(addr.sin6_port);
+        SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id).into()
+    } else {
+        // SAFETY: sockaddr_in is plain integers, for which all zeroes are valid.
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        // SAFETY: The kernel writes a sockaddr_in for SO_ORIGINAL_DST.
+        unsafe { getsockopt(stream, libc::SOL_IP, libc::SO_ORIGINAL_DST, &mut addr)? };
+        let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+        SocketAddr::from((ip, u16::from_be(addr.sin_port)))
+    };
+    // Without a redirect this is the proxy itself, which it would keep connecting to.
+    if original == local || Some(original) == mapped_to_ipv4(local) {
+        return Err(io::Error::other(
+            "the connection wasn't redirected to the proxy",
+        ));
+    }
+    Ok(original)
+}
+
+fn mapped_to_ipv4(addr: SocketAddr) -> Option<SocketAddr> {
+    match addr {
+        SocketAddr::V6(addr) => addr
+            .ip()
+            .to_ipv4_mapped()
+            .map(|ip| SocketAddr::from((ip, addr.port()))),
+        SocketAddr::V4(_) => None,
+    }
+}
+
+/// Reads the socket option `name` at `level` into `value`.
+///
+/// # Safety
+///
+/// What the kernel writes for the option must be a valid `T`, of at most its size.
+unsafe fn getsockopt<T>(
+    stream: &TcpStream,
+    level: i32,
+    name: i32,
+    value: &mut T,
+) -> io::Result<()> {
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    // SAFETY: The descriptor is open while `stream` is borrowed, and `value` is
+    // writable for the `len` bytes the kernel is told it may fill in, which the
+    // caller guarantees leave it a valid `T`.
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            (value as *mut T).cast(),
+            &mut len,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connections_that_werent_redirected_have_no_original_destination() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (_client, (accepted, _)) = tokio::try_join!(client, listener.accept()).unwrap();
+        assert!(original_destination(&accepted).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::data_log::DataLog;
+use crate::Opt;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{error, info, info_span, Instrument, Span};
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Arc<Session>>>>;
+
+struct Session {
+    /// The span of the session's connection id, entered for everything it logs.
+    span: Span,
+    peer: SocketAddr,
+    upstream: UdpSocket,
+    started: Instant,
+    last_activity: Mutex<Instant>,
+    data_log: Mutex<DataLog>,
+}
+
+impl Session {
+    async fn connect(upstream_addr: SocketAddr, i: usize, peer: SocketAddr) -> Result<Self> {
+        let local_addr: SocketAddr = if upstream_addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let upstream = UdpSocket::bind(local_addr).await?;
+        upstream
+            .connect(upstream_addr)
+            .await
+            .with_context(|| format!("Failed to connect to {upstream_addr}"))?;
+
+        Ok(Self {
+            span: Span::current(),
+            peer,
+            upstream,
+            started: Instant::now(),
+            last_activity: Mutex::new(Instant::now()),
+            data_log: Mutex::new(DataLog::new(i)),
+        })
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().unwrap()
+    }
+}
+
+async fn relay_replies(
+    opt: Arc<Opt>,
+    downstream: Arc<UdpSocket>,
+    session: Arc<Session>,
+    sessions: Sessions,
+) {
+    let timeout = Duration::from_secs(opt.udp_timeout);
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let deadline = session.last_activity() + timeout;
+        tokio::select! {
+            res = session.upstream.recv(&mut buf) => match res {
+                Ok(n) => {
+                    let data = &buf[..n];
+                    session.data_log.lock().unwrap().outgoing(&opt, data);
+                    session.touch();
+                    if let Err(e) = downstream.send_to(data, session.peer).await {
+                        error!("Got error: {:?}", e);
+                    }
+                }
+                Err(e) => error!("Got error: {:?}", e),
+            },
+            _ = tokio::time::sleep_until(deadline) => {
+                if session.last_activity() + timeout <= Instant::now() {
+                    break;
+                }
+            }
+        }
+    }
+
+    sessions.lock().unwrap().remove(&session.peer);
+    if opt.summary_only {
+        let upstream = session
+            .upstream
+            .peer_addr()
+            .ok()
+            .map(|addr| addr.to_string());
+        let duration = session.started.elapsed().as_secs_f64();
+        let (incoming_bytes, outgoing_bytes) = session.data_log.lock().unwrap().totals();
+        info!(
+            event = "summary",
+            peer = %session.peer,
+            upstream,
+            duration,
+            incoming_bytes,
+            outgoing_bytes,
+            reason = "udp timeout"
+        );
+    } else {
+        info!("=== Session expired ===");
+    }
+}
+
+/// Relays datagrams until receiving one fails.
+pub async fn run(opt: Arc<Opt>) -> Result<()> {
+    if opt.unix_target().is_some() {
+        bail!("--udp can't forward to a Unix domain socket");
+    }
+
+    // Looked up once, so a slow answer can't hold up the datagrams of every session.
+    let upstream_addr = tokio::net::lookup_host((&*opt.hostname, opt.host_port()))
+        .await?
+        .next()
+        .with_context(|| format!("No addresses found for {}", opt.hostname))?;
+    let downstream = Arc::new(UdpSocket::bind((opt.listen_addr, opt.listen_port)).await?);
+
+    info!(parent: None, "Listening on udp:{}", downstream.local_addr()?);
+    info!(parent: None, "Forwarding to udp:{} ({upstream_addr})", opt.target());
+
+    let sessions = Sessions::default();
+    let mut buf = vec![0; 1 << 16];
+    let mut i: usize = usize::MAX;
+    loop {
+        let (n, peer) = downstream.recv_from(&mut buf).await?;
+        let data = &buf[..n];
+
+        let existing = sessions.lock().unwrap().get(&peer).cloned();
+        let session = match existing {
+            Some(session) => session,
+            None => {
+                i = i.wrapping_add(1);
+                let span = info_span!("connection", connection = i);
+                if !opt.summary_only {
+                    info!(parent: &span, "=== Handling UDP session from {peer} ===");
+                }
+                let connect = Session::connect(upstream_addr, i, peer).instrument(span.clone());
+                let session = match connect.await {
+                    Ok(session) => Arc::new(session),
+                    Err(e) => {
+                        error!(parent: &span, "Got error: {:?}", e);
+                        continue;
+                    }
+                };
+                sessions.lock().unwrap().insert(peer, session.clone());
+                tokio::spawn(
+                    relay_replies(
+                        opt.clone(),
+                        downstream.clone(),
+                        session.clone(),
+                        sessions.clone(),
+                    )
+                    .instrument(span),
+                );
+                session
+            }
+        };
+
+        session
+            .span
+            .in_scope(|| session.data_log.lock().unwrap().incoming(&opt, data));
+        session.touch();
+        if let Err(e) = session.upstream.send(data).await {
+            error!(parent: &session.span, "Got error: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::data_log::DataLog;
+use crate::logging::Direction;
+use crate::stats::Connection;
+use crate::{latency, AsyncStream, Opt};
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tracing::{debug, info};
+
+/// Text messages longer than this are only shown up to here.
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// The header of the frame being read.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    length: u64,
+    /// Payload bytes read so far.
+    read: u64,
+    /// Whether the payload is part of a text message.
+    text: bool,
+}
+
+/// Parses the WebSocket frames in one direction of a connection to log them.
+#[derive(Default)]
+pub struct Frames {
+    /// The start of a frame header that wasn't read completely yet.
+    header: Vec<u8>,
+    frame: Option<Frame>,
+    /// The text message whose frames are being read, up to MAX_MESSAGE_SIZE.
+    message: Option<Vec<u8>>,
+}
+
+fn opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        0 => "continuation",
+        1 => "text",
+        2 => "binary",
+        8 => "close",
+        9 => "ping",
+        10 => "pong",
+        _ => "reserved",
+    }
+}
+
+/// The size of a frame header and the frame it starts, once `header` holds all of
+/// it.
+fn parse_header(header: &[u8]) -> Option<(usize, Frame)> {
+    let [first, second, ..] = *header else {
+        return None;
+    };
+    let (length_size, length) = match second & 0x7f {
+        126 => (2, None),
+        127 => (8, None),
+        length => (0, Some(length.into())),
+    };
+    let mask_size = if second & 0x80 != 0 { 4 } else { 0 };
+    let size = 2 + length_size + mask_size;
+    if header.len() < size {
+        return None;
+    }
+    let length = length.unwrap_or_else(|| {
+        let mut bytes = [0; 8];
+        bytes[8 - length_size..].copy_from_slice(&header[2..2 + length_size]);
+        u64::from_be_bytes(bytes)
+    });
+    let frame = Frame {
+        fin: first & 0x80 != 0,
+        opcode: first & 0x0f,
+        mask: (mask_size != 0).then(|| header[size - 4..size].try_into().unwrap()),
+        length,
+        read: 0,
+        text: false,
+    };
+    Some((size, frame))
+}
+
+impl Frames {
+    /// Logs the frames starting in `data`, returning the text messages they
+    /// completed.
+    pub fn read(&mut self, direction: Direction, mut data: &[u8]) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+        while !data.is_empty() || self.frame.as_ref().is_some_and(|f| f.read == f.length) {
+            let Some(frame) = &mut self.frame else {
+                let taken = data.len().min(14 - self.header.len());
+                self.header.extend(&data[..taken]);
+                let Some((size, mut frame)) = parse_header(&self.header) else {
+                    data = &data[taken..];
+                    continue;
+                };
+                data = &data[taken - (self.header.len() - size)..];
+                self.header.clear();
+                debug!(
+                    event = "websocket",
+                    direction = direction.name(),
+                    opcode = opcode_name(frame.opcode),
+                    fin = frame.fin,
+                    bytes = frame.length
+                );
+                match frame.opcode {
+                    0 => frame.text = self.message.is_some(),
+                    1 => {
+                        self.message = Some(vec![]);
+                        frame.text = true;
+                    }
+                    // Control frames may come between the frames of a message.
+                    8.. => {}
+                    _ => self.message = None,
+                }
+                self.frame = Some(frame);
+                continue;
+            };
+
+            let n = data.len().min((frame.length - frame.read) as usize);
+            if let Some(message) = self.message.as_mut().filter(|_| frame.text) {
+                let start = message.len();
+                let kept = n.min(MAX_MESSAGE_SIZE.saturating_sub(start));
+                message.extend(&data[..kept]);
+                if let Some(mask) = frame.mask {
+                    for (i, byte) in message[start..].iter_mut().enumerate() {
+                        *byte ^= mask[(frame.read as usize + i) % 4];
+                    }
+                }
+            }
+            frame.read += n as u64;
+            data = &data[n..];
+            if frame.read == frame.length {
+                if frame.text && frame.fin {
+                    messages.extend(self.message.take());
+                }
+                self.frame = None;
+            }
+        }
+        messages
+    }
+}
+
+/// Shows the text messages completed by `data`, if its frames are parsed.
+fn read_frames(
+    opt: &Opt,
+    data_log: &Mutex<&mut DataLog>,
+    direction: Direction,
+    frames: &mut Option<Frames>,
+    data: &[u8],
+) {
+    let Some(frames) = frames else {
+        return;
+    };
+    for message in frames.read(direction, data) {
+        let mut data_log = data_log.lock().unwrap();
+        data_log.decoded(opt, direction, "websocket", 0, &message);
+    }
+}
+
+/// Like `crate::forward` after a WebSocket upgrade, logging the frames instead of
+/// the data. `pending` was read and logged before. Until `accepted` is set the
+/// upgrade may still fail, so if data comes before that it isn't parsed.
+#[allow(clippy::too_many_arguments)]
+pub async fn forward(
+    opt: &Opt,
+    connection: &Connection,
+    data_log: &Mutex<&mut DataLog>,
+    direction: Direction,
+    accepted: &AtomicBool,
+    pending: Vec<u8>,
+    mut from: ReadHalf<AsyncStream>,
+    mut to: WriteHalf<AsyncStream>,
+) -> Result<()> {
+    let mut frames = accepted.load(Relaxed).then(Frames::default);
+    let mut undecided = frames.is_none();
+    let mut buf = pending;
+    let mut n = buf.len();
+    loop {
+        if n > 0 && undecided {
+            undecided = false;
+            if accepted.load(Relaxed) {
+                frames = Some(Frames::default());
+            } else {
+                info!("Data before the WebSocket upgrade was accepted, not parsing frames");
+            }
+        }
+        read_frames(opt, data_log, direction, &mut frames, &buf[..n]);
+        to.write_all(&buf[..n]).await?;
+
+        buf.resize(opt.buffer_size, 0);
+        n = from.read(&mut buf).await?;
+        if n > 0 && undecided && accepted.load(Relaxed) {
+            undecided = false;
+            frames = Some(Frames::default());
+        }
+        let data = &buf[..n];
+        let show = frames.is_none();
+        data_log.lock().unwrap().read(opt, direction, data, show);
+        connection.forwarded(direction, n);
+        if n == 0 {
+            let _ = to.shutdown().await;
+            return Ok(());
+        }
+        latency::chunk(opt, connection).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragmented_text_messages_are_unmasked_and_reassembled() {
+        let mask = [1, 2, 3, 4];
+        let masked = |payload: &[u8]| -> Vec<u8> {
+            let payload = payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]);
+            mask.into_iter().chain(payload).collect()
+        };
+        // "Hel" in a text frame, a ping, then "lo" in the final continuation frame.
+        let mut data = vec![0x01, 0x83];
+        data.extend(masked(b"Hel"));
+        data.extend([0x89, 0x00]);
+        data.extend([0x80, 0x82]);
+        data.extend(masked(b"lo"));
+        // A binary frame with a 16 bit length isn't shown.
+        data.extend([0x82, 0x7e, 0x01, 0x00]);
+        data.extend([0; 256]);
+
+        let mut frames = Frames::default();
+        let mut messages = vec![];
+        for chunk in data.chunks(3) {
+            messages.extend(frames.read(Direction::Incoming, chunk));
+        }
+        assert_eq!(messages, [b"Hello"]);
+        assert!(frames.frame.is_none() && frames.header.is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::Proxy;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// An upstream that writes back each chunk before reading the next one, so it
+/// stops reading while the proxy doesn't read what it sends.
+fn echo_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                stream.write_all(&buf[..n]).unwrap();
+            }
+            stream.shutdown(Shutdown::Write).unwrap();
+        }
+    });
+    addr
+}
+
+#[test]
+fn large_transfers_in_both_directions_dont_stall() {
+    const SIZE: usize = 64 << 20;
+    let proxy = Proxy::spawn(echo_upstream(), &[]);
+
+    let mut client = TcpStream::connect(proxy.addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut writer = client.try_clone().unwrap();
+    std::thread::spawn(move || {
+        let chunk = vec![b'x'; 1 << 16];
+        for _ in 0..SIZE / chunk.len() {
+            writer.write_all(&chunk).unwrap();
+        }
+        writer.shutdown(Shutdown::Write).unwrap();
+    });
+    let mut echoed = 0;
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        match client.read(&mut buf).expect("forwarding stalled") {
+            0 => break,
+            n => echoed += n,
+        }
+    }
+    assert_eq!(echoed, SIZE);
+
+    let (_, stderr) = proxy.stop();
+    assert!(!stderr.contains("Got error"), "{stderr}");
+}
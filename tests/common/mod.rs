@@ -0,0 +1,59 @@
+use std::io::{BufRead, BufReader, Read};
+use std::net::SocketAddr;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+/// A running proxy, killed when dropped.
+pub struct Proxy {
+    pub child: Child,
+    stdout: BufReader<ChildStdout>,
+    pub addr: SocketAddr,
+}
+
+impl Proxy {
+    pub fn spawn(upstream: SocketAddr, args: &[&str]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_tcp-proxy"))
+            .arg(upstream.ip().to_string())
+            .args(["--host-port", &upstream.port().to_string()])
+            .args(["--listen-addr", "127.0.0.1", "--listen-port", "0"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut proxy = Proxy {
+            child,
+            stdout,
+            addr: ([0, 0, 0, 0], 0).into(),
+        };
+
+        let mut line = String::new();
+        while proxy.stdout.read_line(&mut line).unwrap() != 0 {
+            if let Some(addr) = line.trim().strip_prefix("Listening on ") {
+                proxy.addr = addr.parse().unwrap();
+                return proxy;
+            }
+            line.clear();
+        }
+        panic!("proxy exited before listening");
+    }
+
+    /// Stops the proxy and returns everything it printed to stdout and stderr.
+    pub fn stop(mut self) -> (String, String) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+        let mut stdout = String::new();
+        self.stdout.read_to_string(&mut stdout).unwrap();
+        let mut stderr = String::new();
+        let mut child_stderr = self.child.stderr.take().unwrap();
+        child_stderr.read_to_string(&mut stderr).unwrap();
+        (stdout, stderr)
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::Proxy;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// An upstream that reads until the client stops sending, then answers with how
+/// much it read.
+fn counting_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut data = vec![];
+            stream.read_to_end(&mut data).unwrap();
+            write!(stream, "read {} bytes", data.len()).unwrap();
+        }
+    });
+    addr
+}
+
+#[test]
+fn response_after_client_half_close_is_forwarded() {
+    let proxy = Proxy::spawn(counting_upstream(), &[]);
+
+    let mut client = TcpStream::connect(proxy.addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    client.write_all(b"hello").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "read 5 bytes");
+    std::thread::sleep(Duration::from_millis(200));
+
+    let (stdout, stderr) = proxy.stop();
+    assert!(!stderr.contains("Got error"), "{stderr}");
+    assert!(stdout.contains("==> 5 bytes"), "{stdout}");
+}
@@ -0,0 +1,1292 @@
+use socket2::{Domain, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tcp_proxy::{Proxy, ProxyHandle};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+/// A proxy to `upstream` on a free local port, as built by `configure`.
+async fn spawn_proxy(upstream: SocketAddr, configure: impl FnOnce(Proxy) -> Proxy) -> ProxyHandle {
+    let proxy = Proxy::new(&upstream.ip().to_string())
+        .host_port(upstream.port())
+        .listen_addr(Ipv4Addr::LOCALHOST.into())
+        .listen_port(0);
+    configure(proxy).spawn().await.unwrap()
+}
+
+async fn connect(proxy: &ProxyHandle) -> TcpStream {
+    TcpStream::connect(proxy.local_addr().unwrap())
+        .await
+        .unwrap()
+}
+
+/// Reads until the peer closes, failing the test if that takes too long.
+async fn read_to_end(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![];
+    timeout(Duration::from_secs(10), stream.read_to_end(&mut data))
+        .await
+        .expect("forwarding stalled")?;
+    Ok(data)
+}
+
+/// An upstream that writes back everything it reads, closing its side when the
+/// client does.
+async fn echo_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                tokio::io::copy(&mut read, &mut write).await.unwrap();
+                write.shutdown().await.unwrap();
+            });
+        }
+    });
+    addr
+}
+
+/// An HTTP upstream that answers every request with the Host header it got.
+async fn host_upstream(listener: TcpListener) -> SocketAddr {
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await.unwrap() {
+                        0 => return,
+                        n => request.extend(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8(request).unwrap();
+                let host = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Host: "))
+                    .unwrap_or("");
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{host}",
+                    host.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+        }
+    });
+    addr
+}
+
+/// The Host header the upstream got for a request to the proxy for `proxy:8080`.
+async fn forwarded_host(proxy: &ProxyHandle) -> String {
+    let mut client = connect(proxy).await;
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: proxy:8080\r\n\r\n")
+        .await
+        .unwrap();
+    let response = String::from_utf8(read_to_end(&mut client).await.unwrap()).unwrap();
+    let (_, host) = response.split_once("\r\n\r\n").unwrap();
+    host.to_string()
+}
+
+/// An address nothing listens on. Connecting to it is refused, and as the socket
+/// stays bound while it lives no other test can take its port.
+fn refusing_upstream() -> (Socket, SocketAddr) {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket
+        .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+        .unwrap();
+    let addr = socket.local_addr().unwrap().as_socket().unwrap();
+    (socket, addr)
+}
+
+#[tokio::test]
+async fn data_is_forwarded_in_both_directions() {
+    let proxy = spawn_proxy(echo_upstream().await, |proxy| proxy).await;
+
+    let mut client = connect(&proxy).await;
+    for message in [&b"hello"[..], b"again"] {
+        client.write_all(message).await.unwrap();
+        let mut echoed = vec![0; message.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message);
+    }
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await.unwrap(), b"");
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn large_transfers_arrive_intact() {
+    const SIZE: usize = 16 << 20;
+    let proxy = spawn_proxy(echo_upstream().await, |proxy| proxy).await;
+
+    let data: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+    let (mut read, mut write) = connect(&proxy).await.into_split();
+    let writer = tokio::spawn({
+        let data = data.clone();
+        async move {
+            write.write_all(&data).await.unwrap();
+            write.shutdown().await.unwrap();
+        }
+    });
+    let mut echoed = vec![];
+    timeout(Duration::from_secs(30), read.read_to_end(&mut echoed))
+        .await
+        .expect("forwarding stalled")
+        .unwrap();
+    writer.await.unwrap();
+    assert!(echoed == data, "got {} of {SIZE} bytes back", echoed.len());
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn half_closes_are_forwarded_both_ways() {
+    // The upstream closes its side first, then still reads what the client sends.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    let (received, got) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"greeting").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        received.send(data).unwrap();
+    });
+    let proxy = spawn_proxy(upstream, |proxy| proxy).await;
+
+    let mut client = connect(&proxy).await;
+    assert_eq!(read_to_end(&mut client).await.unwrap(), b"greeting");
+    client.write_all(b"late reply").await.unwrap();
+    client.shutdown().await.unwrap();
+    let data = timeout(Duration::from_secs(10), got).await.unwrap();
+    assert_eq!(data.unwrap(), b"late reply");
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn host_header_is_rewritten_with_the_upstream_port() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = host_upstream(listener).await;
+
+    let proxy = spawn_proxy(upstream, |proxy| proxy.rewrite_host_header(true)).await;
+    let expected = format!("127.0.0.1:{}", upstream.port());
+    assert_eq!(forwarded_host(&proxy).await, expected);
+    proxy.shutdown().await.unwrap();
+
+    let proxy = spawn_proxy(upstream, |proxy| proxy).await;
+    assert_eq!(forwarded_host(&proxy).await, "proxy:8080");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn host_header_has_no_port_for_the_default_one() {
+    let Ok(listener) = TcpListener::bind("127.0.0.1:80").await else {
+        eprintln!("Skipping, port 80 can't be listened on");
+        return;
+    };
+    let upstream = host_upstream(listener).await;
+
+    let proxy = spawn_proxy(upstream, |proxy| proxy.rewrite_host_header(true)).await;
+    assert_eq!(forwarded_host(&proxy).await, "127.0.0.1");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn clients_are_disconnected_when_the_upstream_is_unreachable() {
+    let (_socket, upstream) = refusing_upstream();
+    let proxy = spawn_proxy(upstream, |proxy| proxy).await;
+
+    for _ in 0..2 {
+        let mut client = connect(&proxy).await;
+        match read_to_end(&mut client).await {
+            Ok(data) => assert!(data.is_empty()),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+        }
+    }
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn tls_clients_get_a_502_over_tls_when_the_upstream_is_unreachable() {
+    let (_socket, upstream) = refusing_upstream();
+    let server = spawn_proxy(upstream, |proxy| {
+        proxy.ssl_server(true).rewrite_host_header(true)
+    })
+    .await;
+    let server_addr = server.local_addr().unwrap();
+    let proxy = spawn_proxy(server_addr, |proxy| proxy.ssl(true)).await;
+
+    let mut client = connect(&proxy).await;
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: proxy\r\n\r\n")
+        .await
+        .unwrap();
+    let response = String::from_utf8(read_to_end(&mut client).await.unwrap()).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+        "{response}"
+    );
+
+    drop(client);
+    proxy.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+}
+
+/// A proxy terminating TLS with its generated self-signed certificate in front of
+/// `upstream`, and a proxy connecting to it with TLS without verifying it, which
+/// the returned one is.
+async fn spawn_tls_chain(
+    upstream: SocketAddr,
+    configure: impl FnOnce(Proxy) -> Proxy,
+) -> (ProxyHandle, ProxyHandle) {
+    let server = spawn_proxy(upstream, |proxy| proxy.ssl_server(true)).await;
+    let server_addr = server.local_addr().unwrap();
+    let client = spawn_proxy(server_addr, |proxy| configure(proxy.ssl(true))).await;
+    (client, server)
+}
+
+#[tokio::test]
+async fn tls_is_terminated_with_a_generated_certificate() {
+    const SIZE: usize = 1 << 20;
+    let (proxy, server) = spawn_tls_chain(echo_upstream().await, |proxy| proxy).await;
+
+    let data: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+    let (mut read, mut write) = connect(&proxy).await.into_split();
+    let writer = tokio::spawn({
+        let data = data.clone();
+        async move {
+            write.write_all(&data).await.unwrap();
+            write.shutdown().await.unwrap();
+        }
+    });
+    let mut echoed = vec![];
+    timeout(Duration::from_secs(30), read.read_to_end(&mut echoed))
+        .await
+        .expect("forwarding stalled")
+        .unwrap();
+    writer.await.unwrap();
+    assert!(echoed == data, "got {} of {SIZE} bytes back", echoed.len());
+
+    proxy.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn host_header_over_tls_has_the_upstream_port() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = host_upstream(listener).await;
+    let (proxy, server) = spawn_tls_chain(upstream, |proxy| proxy.rewrite_host_header(true)).await;
+
+    let expected = format!("127.0.0.1:{}", server.local_addr().unwrap().port());
+    assert_eq!(forwarded_host(&proxy).await, expected);
+
+    proxy.shutdown().await.unwrap();
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn tls_handshake_with_a_silent_upstream_times_out() {
+    // Accepts connections but never answers the ClientHello.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+    let proxy = spawn_proxy(upstream, |proxy| proxy.ssl(true).connect_timeout(1)).await;
+
+    let mut client = connect(&proxy).await;
+    let start = std::time::Instant::now();
+    match read_to_end(&mut client).await {
+        Ok(data) => assert!(data.is_empty()),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn idle_connections_are_closed() {
+    let proxy = spawn_proxy(echo_upstream().await, |proxy| proxy.idle_timeout(1)).await;
+
+    let mut client = connect(&proxy).await;
+    // Traffic more often than the timeout keeps the connection open.
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+    }
+    let start = std::time::Instant::now();
+    assert_eq!(read_to_end(&mut client).await.unwrap(), b"");
+    assert!(start.elapsed() >= Duration::from_millis(900));
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn connections_are_closed_at_the_max_age_even_when_active() {
+    let proxy = spawn_proxy(echo_upstream().await, |proxy| {
+        proxy.max_connection_age(1).idle_timeout(5)
+    })
+    .await;
+
+    let mut client = connect(&proxy).await;
+    let start = std::time::Instant::now();
+    let mut echoed = [0; 4];
+    loop {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        if client.write_all(b"ping").await.is_err() {
+            break;
+        }
+        match client.read(&mut echoed).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "not closed");
+    }
+    assert!(start.elapsed() >= Duration::from_millis(900));
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn connections_beyond_the_limit_wait_for_one_to_close() {
+    let proxy = spawn_proxy(echo_upstream().await, |proxy| proxy.max_connections(1)).await;
+
+    let mut first = connect(&proxy).await;
+    first.write_all(b"first").await.unwrap();
+    first.read_exact(&mut [0; 5]).await.unwrap();
+    let mut second = connect(&proxy).await;
+    second.write_all(b"second").await.unwrap();
+    let mut echoed = [0; 6];
+    let waited = timeout(Duration::from_millis(300), second.read_exact(&mut echoed)).await;
+    assert!(waited.is_err(), "second connection was handled");
+
+    drop(first);
+    timeout(Duration::from_secs(10), second.read_exact(&mut echoed))
+        .await
+        .expect("second connection wasn't handled")
+        .unwrap();
+    assert_eq!(&echoed, b"second");
+
+    drop(second);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn connections_beyond_the_limit_can_be_rejected() {
+    let upstream = echo_upstream().await;
+    let port = upstream.port().to_string();
+    let args = ["--host-port", &port, "--listen-addr", "127.0.0.1"];
+    let limit = [
+        "--listen-port",
+        "0",
+        "--max-connections",
+        "1",
+        "--overflow",
+        "reject",
+    ];
+    let proxy = Proxy::from_args(["tcp-proxy", "127.0.0.1"].iter().chain(&args).chain(&limit))
+        .unwrap()
+        .spawn()
+        .await
+        .unwrap();
+
+    let mut first = connect(&proxy).await;
+    first.write_all(b"first").await.unwrap();
+    first.read_exact(&mut [0; 5]).await.unwrap();
+    let mut second = connect(&proxy).await;
+    match read_to_end(&mut second).await {
+        Ok(data) => assert!(data.is_empty()),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut third = connect(&proxy).await;
+    third.write_all(b"third").await.unwrap();
+    third.read_exact(&mut [0; 5]).await.unwrap();
+
+    drop(third);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn connecting_is_retried_until_the_upstream_is_back() {
+    let (socket, upstream) = refusing_upstream();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        socket.listen(16).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let listener = TcpListener::from_std(socket.into()).unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut read, mut write) = stream.split();
+        tokio::io::copy(&mut read, &mut write).await.unwrap();
+    });
+    let proxy = spawn_proxy(upstream, |proxy| proxy.connect_retries(5)).await;
+
+    let mut client = connect(&proxy).await;
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await.unwrap(), b"hello");
+
+    proxy.shutdown().await.unwrap();
+}
+
+/// An upstream that greets every connection with `name` and closes it.
+async fn named_upstream(name: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            stream.write_all(name.as_bytes()).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn connections_take_turns_between_the_upstreams() {
+    let first = named_upstream("first").await;
+    let second = named_upstream("second").await;
+    let third = named_upstream("third").await;
+    let port = first.port().to_string();
+    let second = second.to_string();
+    let third = format!("localhost:{}", third.port());
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--upstream",
+        &second,
+        "--upstream",
+        &third,
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+
+    let mut greetings = vec![];
+    for _ in 0..4 {
+        let mut client = connect(&proxy).await;
+        let greeting = read_to_end(&mut client).await.unwrap();
+        greetings.push(String::from_utf8(greeting).unwrap());
+    }
+    assert_eq!(greetings, ["first", "second", "third", "first"]);
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn failover_uses_the_backup_while_the_primary_is_down() {
+    let (socket, primary) = refusing_upstream();
+    let backup = named_upstream("backup").await.to_string();
+    let port = primary.port().to_string();
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--upstream",
+        &backup,
+        "--balance",
+        "failover",
+        "--failover-cooldown",
+        "0",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+
+    for _ in 0..2 {
+        let mut client = connect(&proxy).await;
+        assert_eq!(read_to_end(&mut client).await.unwrap(), b"backup");
+    }
+
+    socket.listen(16).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let listener = TcpListener::from_std(socket.into()).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            stream.write_all(b"primary").await.unwrap();
+        }
+    });
+    let mut client = connect(&proxy).await;
+    assert_eq!(read_to_end(&mut client).await.unwrap(), b"primary");
+
+    drop(client);
+    proxy.shutdown().await.unwrap();
+}
+
+/// An HTTP upstream that answers every request with `status` and `name`.
+async fn status_upstream(status: &'static str, name: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend(&buf[..n]),
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{name}",
+                name.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn upstreams_failing_health_checks_leave_the_rotation() {
+    let failing = status_upstream("503 Service Unavailable", "failing").await;
+    let healthy = status_upstream("200 OK", "healthy").await.to_string();
+    let port = failing.port().to_string();
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--upstream",
+        &healthy,
+        "--health-check",
+        "--health-check-path",
+        "/health",
+        "--health-check-fall",
+        "1",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    for _ in 0..3 {
+        let mut client = connect(&proxy).await;
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: proxy\r\n\r\n")
+            .await
+            .unwrap();
+        let response = String::from_utf8(read_to_end(&mut client).await.unwrap()).unwrap();
+        assert!(response.ends_with("\r\n\r\nhealthy"), "{response}");
+    }
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn client_data_is_mirrored_without_depending_on_the_mirror() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mirror = listener.local_addr().unwrap().to_string();
+    let (received, got) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"ignored answer").await.unwrap();
+        let mut data = vec![];
+        stream.read_to_end(&mut data).await.unwrap();
+        received.send(data).unwrap();
+    });
+    let (_socket, refusing) = refusing_upstream();
+    let refusing = refusing.to_string();
+
+    let upstream = echo_upstream().await;
+    let port = upstream.port().to_string();
+    for mirror in [&mirror, &refusing] {
+        let args = [
+            "tcp-proxy",
+            "127.0.0.1",
+            "--host-port",
+            &port,
+            "--mirror",
+            mirror,
+            "--listen-addr",
+            "127.0.0.1",
+            "--listen-port",
+            "0",
+        ];
+        let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+        let mut client = connect(&proxy).await;
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut client).await.unwrap(), b"hello");
+        drop(client);
+        proxy.shutdown().await.unwrap();
+    }
+
+    let data = timeout(Duration::from_secs(10), got).await.unwrap();
+    assert_eq!(data.unwrap(), b"hello");
+}
+
+#[tokio::test]
+async fn upstream_connections_start_with_a_proxy_protocol_header() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--send-proxy",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+    let mut client = connect(&proxy).await;
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+
+    let (mut stream, from) = listener.accept().await.unwrap();
+    let mut data = vec![];
+    stream.read_to_end(&mut data).await.unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let expected = format!(
+        "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello",
+        client_addr.port(),
+        from.port()
+    );
+    assert_eq!(String::from_utf8(data).unwrap(), expected);
+
+    drop((client, stream));
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn proxy_protocol_headers_from_clients_are_stripped_and_passed_on() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--accept-proxy",
+        "--send-proxy",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let accepting = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+    let port = accepting.local_addr().unwrap().port().to_string();
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--send-proxy-v2",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let sending = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+
+    let mut client = connect(&sending).await;
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+    let (mut stream, from) = listener.accept().await.unwrap();
+    let mut data = vec![];
+    stream.read_to_end(&mut data).await.unwrap();
+    let expected = format!(
+        "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello",
+        client.local_addr().unwrap().port(),
+        from.port()
+    );
+    assert_eq!(String::from_utf8(data).unwrap(), expected);
+
+    let mut garbage = connect(&accepting).await;
+    garbage.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    match read_to_end(&mut garbage).await {
+        Ok(data) => assert!(data.is_empty()),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+
+    drop((client, stream, garbage));
+    sending.shutdown().await.unwrap();
+    accepting.shutdown().await.unwrap();
+}
+
+/// A SOCKS5 proxy without authentication that connects to IPv4 addresses.
+async fn socks5_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut greeting = [0; 3];
+                stream.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 1, 0]);
+                stream.write_all(&[5, 0]).await.unwrap();
+                let mut request = [0; 10];
+                stream.read_exact(&mut request).await.unwrap();
+                let [5, 1, 0, 1, a, b, c, d, port @ ..] = request else {
+                    panic!("unexpected request {request:?}");
+                };
+                let target = SocketAddr::from(([a, b, c, d], u16::from_be_bytes(port)));
+                let mut upstream = TcpStream::connect(target).await.unwrap();
+                stream
+                    .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn upstreams_can_be_reached_through_socks5() {
+    let socks5 = socks5_proxy().await.to_string();
+    let upstream = echo_upstream().await;
+    let port = upstream.port().to_string();
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--socks5",
+        &socks5,
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+    let mut client = connect(&proxy).await;
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await.unwrap(), b"hello");
+
+    drop(client);
+    proxy.shutdown().await.unwrap();
+}
+
+/// An HTTP proxy that only handles CONNECT, requiring `authorization`.
+async fn http_connect_proxy(authorization: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = vec![];
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                let request = String::from_utf8(request).unwrap();
+                let target = request
+                    .strip_prefix("CONNECT ")
+                    .and_then(|rest| rest.split(' ').next())
+                    .unwrap();
+                if !request.contains(&format!("\r\nProxy-Authorization: {authorization}\r\n")) {
+                    let refusal = b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+                    stream.write_all(refusal).await.unwrap();
+                    return;
+                }
+                let mut upstream = TcpStream::connect(target).await.unwrap();
+                stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn upstreams_can_be_reached_through_an_http_proxy() {
+    let http_proxy = http_connect_proxy("Basic dXNlcjpwYXNz").await.to_string();
+    let upstream = echo_upstream().await;
+    let port = upstream.port().to_string();
+    for (auth, expected) in [("user:pass", &b"hello"[..]), ("user:wrong", b"")] {
+        let args = [
+            "tcp-proxy",
+            "127.0.0.1",
+            "--host-port",
+            &port,
+            "--http-proxy",
+            &http_proxy,
+            "--http-proxy-auth",
+            auth,
+            "--listen-addr",
+            "127.0.0.1",
+            "--listen-port",
+            "0",
+        ];
+        let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+        let mut client = connect(&proxy).await;
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        match read_to_end(&mut client).await {
+            Ok(data) => assert_eq!(data, expected),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+        }
+
+        drop(client);
+        proxy.shutdown().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn socks5_clients_are_forwarded_where_they_ask() {
+    let upstream = echo_upstream().await;
+    let args = [
+        "tcp-proxy",
+        "--socks5-server",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+    let mut client = connect(&proxy).await;
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut choice = [0; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice, [5, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend(upstream.port().to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..2], [5, 0]);
+
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await.unwrap(), b"hello");
+
+    drop(client);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn delays_slow_both_directions_without_counting_as_idle() {
+    let upstream = echo_upstream().await;
+    let port = upstream.port().to_string();
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--delay",
+        "1200",
+        "--idle-timeout",
+        "1",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+    let mut client = connect(&proxy).await;
+    let started = std::time::Instant::now();
+    client.write_all(b"hello").await.unwrap();
+    let mut echoed = [0; 5];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello");
+    assert!(started.elapsed() >= Duration::from_millis(2400));
+
+    drop(client);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn faults_drop_and_reset_connections() {
+    let upstream = echo_upstream().await;
+    let port = upstream.port().to_string();
+    let args = |faults: &[&'static str]| {
+        let mut args = vec![
+            "tcp-proxy",
+            "127.0.0.1",
+            "--host-port",
+            &port,
+            "--listen-addr",
+            "127.0.0.1",
+            "--listen-port",
+            "0",
+        ];
+        args.extend(faults);
+        args
+    };
+
+    let proxy = Proxy::from_args(args(&["--fault-drop-rate", "1"]))
+        .unwrap()
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = connect(&proxy).await;
+    let data = read_to_end(&mut client).await;
+    assert!(
+        data.as_ref()
+            .map_or_else(|e| e.kind() == ErrorKind::ConnectionReset, Vec::is_empty),
+        "{data:?}"
+    );
+    drop(client);
+    proxy.shutdown().await.unwrap();
+
+    let faults = ["--fault-abort-after", "10", "--fault-reset"];
+    let proxy = Proxy::from_args(args(&faults))
+        .unwrap()
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = connect(&proxy).await;
+    client.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    client.read_exact(&mut echoed).await.unwrap();
+    client.write_all(b"ping").await.unwrap();
+    let err = read_to_end(&mut client).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    drop(client);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn fragments_arrive_as_separate_reads() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    let (reads_tx, reads_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut reads = vec![];
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            reads.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+        }
+        reads_tx.send(reads).unwrap();
+    });
+    let port = upstream.port().to_string();
+    let args = [
+        "tcp-proxy",
+        "127.0.0.1",
+        "--host-port",
+        &port,
+        "--fragment",
+        "2",
+        "--fragment-delay",
+        "100",
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+    let mut client = connect(&proxy).await;
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_eq!(reads_rx.await.unwrap(), ["he", "ll", "o"]);
+
+    drop(client);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn small_buffers_still_forward_everything() {
+    let upstream = echo_upstream().await;
+    let port = upstream.port().to_string();
+    let args = |buffer_size| {
+        [
+            "tcp-proxy",
+            "127.0.0.1",
+            "--host-port",
+            &port,
+            "--buffer-size",
+            buffer_size,
+            "--listen-addr",
+            "127.0.0.1",
+            "--listen-port",
+            "0",
+        ]
+        .map(str::to_string)
+    };
+    assert!(Proxy::from_args(args("100")).is_err());
+
+    let proxy = Proxy::from_args(args("1024"))
+        .unwrap()
+        .spawn()
+        .await
+        .unwrap();
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let (mut read, mut write) = connect(&proxy).await.into_split();
+    let writer = tokio::spawn({
+        let data = data.clone();
+        async move {
+            write.write_all(&data).await.unwrap();
+            write.shutdown().await.unwrap();
+        }
+    });
+    let mut echoed = vec![];
+    timeout(Duration::from_secs(10), read.read_to_end(&mut echoed))
+        .await
+        .expect("forwarding stalled")
+        .unwrap();
+    writer.await.unwrap();
+    assert!(echoed == data, "got {} bytes back", echoed.len());
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn upstream_connections_come_from_the_bind_source() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, peer)) = listener.accept().await {
+            let _ = stream.write_all(peer.ip().to_string().as_bytes()).await;
+        }
+    });
+    let port = upstream.port().to_string();
+    let args = |source| {
+        [
+            "tcp-proxy",
+            "127.0.0.1",
+            "--host-port",
+            &port,
+            "--bind-source",
+            source,
+            "--listen-addr",
+            "127.0.0.1",
+            "--listen-port",
+            "0",
+        ]
+        .map(str::to_string)
+    };
+
+    let proxy = Proxy::from_args(args("127.0.0.2"))
+        .unwrap()
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = connect(&proxy).await;
+    assert_eq!(read_to_end(&mut client).await.unwrap(), b"127.0.0.2");
+    drop(client);
+    proxy.shutdown().await.unwrap();
+
+    // An IPv6 source can't reach an IPv4 upstream.
+    let proxy = Proxy::from_args(args("[::1]:0"))
+        .unwrap()
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = connect(&proxy).await;
+    let data = read_to_end(&mut client).await;
+    assert!(
+        data.as_ref()
+            .map_or_else(|e| e.kind() == ErrorKind::ConnectionReset, Vec::is_empty),
+        "{data:?}"
+    );
+    drop(client);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn resolved_addresses_are_tried_in_turn_instead_of_dns() {
+    let upstream = echo_upstream().await;
+    let port = upstream.port().to_string();
+    let args = |resolve: &[&'static str]| {
+        let mut args = vec![
+            "tcp-proxy",
+            "upstream.invalid",
+            "--host-port",
+            &port,
+            "--listen-addr",
+            "127.0.0.1",
+            "--listen-port",
+            "0",
+        ];
+        args.extend(resolve);
+        args
+    };
+
+    // Nothing listens on 127.0.0.2, so that address is skipped.
+    let resolve = ["--resolve", "127.0.0.2", "--resolve", "127.0.0.1"];
+    let proxy = Proxy::from_args(args(&resolve))
+        .unwrap()
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = connect(&proxy).await;
+    client.write_all(b"hello").await.unwrap();
+    let mut echoed = [0; 5];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello");
+    drop(client);
+    proxy.shutdown().await.unwrap();
+
+    let resolve_once = Proxy::from_args(args(&["--resolve-once"]))
+        .unwrap()
+        .spawn()
+        .await;
+    assert!(resolve_once.is_err());
+}
+
+#[tokio::test]
+async fn the_port_can_be_part_of_the_hostname() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = host_upstream(listener).await;
+    let spawn = |hostname: String| {
+        Proxy::new(&hostname)
+            .rewrite_host_header(true)
+            .listen_addr(Ipv4Addr::LOCALHOST.into())
+            .listen_port(0)
+            .spawn()
+    };
+
+    let proxy = spawn(upstream.to_string()).await.unwrap();
+    assert_eq!(forwarded_host(&proxy).await, upstream.to_string());
+    proxy.shutdown().await.unwrap();
+
+    let conflicting = Proxy::new(&upstream.to_string()).host_port(upstream.port());
+    assert!(conflicting.spawn().await.is_err());
+
+    let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+        eprintln!("Skipping IPv6, [::1] can't be listened on");
+        return;
+    };
+    let upstream = host_upstream(listener).await;
+    let proxy = spawn(upstream.to_string()).await.unwrap();
+    assert_eq!(forwarded_host(&proxy).await, upstream.to_string());
+    proxy.shutdown().await.unwrap();
+}
+
+#[test]
+fn udp_rejects_the_options_of_tcp_connections() {
+    let args = |options: &[&'static str]| {
+        let mut args = vec!["tcp-proxy", "--udp", "127.0.0.1"];
+        args.extend(options);
+        args
+    };
+    assert!(Proxy::from_args(args(&["--udp-timeout", "5", "--show-data", "-v"])).is_ok());
+    let tcp_only: [&[_]; 4] = [
+        &["--ssl"],
+        &["--max-connections", "3"],
+        &["--alpn", "h2"],
+        &["--keylog", "keys.log"],
+    ];
+    for options in tcp_only {
+        let Some(error) = Proxy::from_args(args(options)).err() else {
+            panic!("{options:?} was accepted with --udp");
+        };
+        assert!(error.message.contains(options[0]), "{}", error.message);
+    }
+}
+
+#[tokio::test]
+async fn a_target_that_looks_like_an_option_is_a_hostname() {
+    let proxy = Proxy::new("--help")
+        .listen_addr(Ipv4Addr::LOCALHOST.into())
+        .listen_port(0)
+        .spawn()
+        .await
+        .unwrap();
+    assert!(proxy.local_addr().is_some());
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_fixed_bind_source_port_is_shared_by_raced_and_later_connections() {
+    let upstream = echo_upstream().await;
+    // The same port on 127.0.0.2 with a full accept queue, so connecting there
+    // hangs and the next address is tried.
+    let hanging = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    let hanging_addr = SocketAddr::from(([127, 0, 0, 2], upstream.port()));
+    hanging.bind(&hanging_addr.into()).unwrap();
+    hanging.listen(0).unwrap();
+    let mut queued = vec![];
+    while let Ok(Ok(stream)) =
+        timeout(Duration::from_millis(100), TcpStream::connect(hanging_addr)).await
+    {
+        queued.push(stream);
+    }
+    let source = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let port = upstream.port().to_string();
+    let source = source.to_string();
+    let args = [
+        "tcp-proxy",
+        "upstream.invalid",
+        "--host-port",
+        &port,
+        "--resolve",
+        "127.0.0.2",
+        "--resolve",
+        "127.0.0.1",
+        "--bind-source",
+        &source,
+        "--listen-addr",
+        "127.0.0.1",
+        "--listen-port",
+        "0",
+    ];
+    let proxy = Proxy::from_args(args).unwrap().spawn().await.unwrap();
+    for _ in 0..2 {
+        let mut client = connect(&proxy).await;
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(read_to_end(&mut client).await.unwrap(), b"hello");
+    }
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn proxies_in_a_process_share_one_access_log() {
+    let dir = std::env::temp_dir().join(format!("tcp-proxy-outputs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let spawn = |name: &str| {
+        let path = dir.join(name).to_str().unwrap().to_string();
+        let args = [
+            "tcp-proxy",
+            "127.0.0.1",
+            "--access-log",
+            &path,
+            "--listen-addr",
+            "127.0.0.1",
+            "--listen-port",
+            "0",
+        ];
+        Proxy::from_args(args).unwrap().spawn()
+    };
+
+    let first = spawn("access.log").await.unwrap();
+    let second = spawn("access.log").await.unwrap();
+    let err = spawn("other.log").await.err().unwrap();
+    assert!(err.to_string().starts_with("--access-log"), "{err}");
+    assert!(!dir.join("other.log").exists());
+
+    first.shutdown().await.unwrap();
+    second.shutdown().await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
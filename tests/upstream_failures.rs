@@ -0,0 +1,103 @@
+mod common;
+
+use common::Proxy;
+use socket2::{Domain, Socket, Type};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// An upstream that accepts connections and closes them right away.
+fn closing_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            drop(stream);
+        }
+    });
+    addr
+}
+
+fn assert_survives_closing_upstream(args: &[&str]) {
+    let mut proxy = Proxy::spawn(closing_upstream(), args);
+
+    for _ in 0..3 {
+        let mut client = TcpStream::connect(proxy.addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut data = vec![];
+        match client.read_to_end(&mut data) {
+            Ok(_) => assert!(data.is_empty()),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+        }
+    }
+    assert!(proxy.child.try_wait().unwrap().is_none(), "proxy exited");
+
+    let (stdout, stderr) = proxy.stop();
+    assert_eq!(stdout.matches("=== Handling connection").count(), 3);
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    assert!(
+        stderr.contains("TLS handshake with upstream failed"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn ssl_client_survives_upstream_closing() {
+    assert_survives_closing_upstream(&["--ssl"]);
+}
+
+#[test]
+fn ssl_server_survives_upstream_closing() {
+    assert_survives_closing_upstream(&["--ssl", "--ssl-server"]);
+}
+
+#[test]
+fn summary_only_prints_one_line_per_connection() {
+    let proxy = Proxy::spawn(closing_upstream(), &["--summary-only"]);
+    let mut client = TcpStream::connect(proxy.addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let _ = client.read_to_end(&mut vec![]);
+    drop(client);
+    std::thread::sleep(Duration::from_millis(200));
+
+    let (stdout, _) = proxy.stop();
+    let lines: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.starts_with("[0]"))
+        .collect();
+    assert_eq!(lines.len(), 1, "{stdout}");
+    assert!(lines[0].ends_with("clean EOF ==="), "{stdout}");
+}
+
+#[test]
+fn http_clients_get_a_502_when_the_upstream_is_down() {
+    // A bound socket that doesn't listen refuses connections, and keeps other tests
+    // from listening on its port.
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket
+        .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+        .unwrap();
+    let upstream = socket.local_addr().unwrap().as_socket().unwrap();
+    let proxy = Proxy::spawn(upstream, &["--rewrite-host-header"]);
+    let mut client = TcpStream::connect(proxy.addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: proxy\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains(&format!("Failed to connect to {upstream}")),
+        "{response}"
+    );
+}